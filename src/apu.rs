@@ -0,0 +1,72 @@
+/*  apu.rs
+*   The APU (audio processing unit) sits inside the 2A03 right next to the CPU.
+*
+*   There is no sound yet. For now it only remembers what the CPU writes into its registers
+*   ($4000-$4013, $4015 and $4017), so the BUS has something to talk to and the channels can
+*   be added one by one later.
+*/
+
+use crate::region::Region;
+
+#[derive(Clone)]
+pub struct APU{
+    /* $4000-$4017, including $4014 and $4016 which belong to somebody else */
+    registers: [u8; 0x18],
+
+    /* The APU runs at CPU speed, this counts CPU cycles since power on */
+    pub cycle: u64,
+
+    /* Mixed samples that haven't been handed to the frontend yet (see audio.rs) */
+    pub samples: Vec<f32>,
+
+    /* Picks the frame counter and DMC timing tables (see region.rs) */
+    pub region: Region,
+}
+
+pub trait IAPU {
+
+    fn new() -> Self
+    where
+        Self: Sized;
+
+    /* Functions for accessing the CPU Bus, addr is the full address ($4000-$4017).
+    *  Only $4015 can be read, reading it will clear the frame IRQ flag once that exists */
+    fn cpu_read(&mut self, addr: u16) -> u8;
+    fn cpu_write(&mut self, addr: u16, data: u8);
+    fn cpu_peek(&self, addr: u16) -> u8;
+
+    /* Once per CPU cycle */
+    fn clock(&mut self);
+
+}
+
+impl IAPU for APU{
+
+    fn new() -> Self{
+        APU {
+            registers: [0u8; 0x18],
+            cycle: 0,
+            samples: Vec::new(),
+            region: Region::Ntsc,
+        }
+    }
+
+    fn cpu_read(&mut self, addr: u16) -> u8{
+        self.cpu_peek(addr)
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8){
+        if let Some(register) = self.registers.get_mut((addr & 0x001F) as usize) {
+            *register = data;
+        }
+    }
+    fn cpu_peek(&self, _addr: u16) -> u8{
+        /* $4015 (which channels are still playing and the IRQ flags) is the only register that
+        *  can be read. Without channels nothing is playing and no IRQ ever happens */
+        0x00
+    }
+
+    fn clock(&mut self){
+        self.cycle += 1;
+    }
+
+}
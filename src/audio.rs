@@ -0,0 +1,11 @@
+/*  audio.rs
+*   How the sound gets out of the emulator.
+*
+*   The APU collects mono samples between -1.0 and 1.0 while it runs, the frontend gets them
+*   after every frame. There are no channels yet, so for now that's always an empty slice.
+*/
+
+/* Implemented by frontends (sound cards, wav writers, ...) */
+pub trait AudioOutput {
+    fn queue_samples(&mut self, samples: &[f32]);
+}
@@ -0,0 +1,44 @@
+/*  benchmark.rs
+*   Runs a ROM headless as fast as possible and measures how many CPU cycles per second we manage.
+*
+*   The real NTSC 2A03 runs at 1.789773 MHz, so anything above that is faster than a real NES.
+*   The PPU is clocked 3 times per CPU cycle just like in the normal main loop, so the result
+*   includes its cost as well.
+*/
+
+use crate::nes::{Nes, RunEvents};
+use crate::Cartridge;
+
+use std::time::{Duration, Instant};
+
+pub const NTSC_CPU_MHZ: f64 = 1.789773;
+
+pub struct BenchResult {
+    /* The cycles that actually ran, fewer than asked for if the run stopped early */
+    pub cpu_cycles: u64,
+    pub elapsed: Duration,
+    /* Why the run stopped early (the CPU jammed or trapped), if it did */
+    pub events: RunEvents,
+}
+
+impl BenchResult {
+    /* Emulated CPU cycles per second, in MHz */
+    pub fn mhz(&self) -> f64 {
+        self.cpu_cycles as f64 / self.elapsed.as_secs_f64() / 1_000_000.0
+    }
+}
+
+pub fn run(rom_file: &str, cpu_cycles: u64) -> Result<BenchResult, String> {
+    let mut nes = Nes::new();
+    nes.insert_cartridge(Cartridge::load(rom_file)?);
+    nes.reset();
+
+    let start = Instant::now();
+    let events = nes.run_cycles(cpu_cycles);
+
+    Ok(BenchResult {
+        cpu_cycles: events.cpu_cycles,
+        elapsed: start.elapsed(),
+        events,
+    })
+}
@@ -0,0 +1,127 @@
+/*  nes-test.rs
+*   Runs the CPU test suites, see "Testing the CPU" in the README.
+*/
+
+use nes_emulator::disasm::parse_addr;
+use nes_emulator::{klaus, nestest, single_step};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    /* nestest conformance mode: nes-test --nestest <rom> [reference log] */
+    if args.len() >= 3 && args[1] == "--nestest" {
+        std::process::exit(run_nestest(&args[2], args.get(3)));
+    }
+
+    /* Per-opcode JSON tests: nes-test --single-step <directory with the *.json files> */
+    if args.len() >= 3 && args[1] == "--single-step" {
+        std::process::exit(run_single_step(&args[2]));
+    }
+
+    /* Klaus Dormann's test images: nes-test --klaus <functional|interrupt|decimal> <image> [success address] */
+    if args.len() >= 4 && args[1] == "--klaus" {
+        std::process::exit(run_klaus(&args[2], &args[3], args.get(4)));
+    }
+
+    eprintln!("Usage: nes-test --nestest <rom> [reference log]");
+    eprintln!("       nes-test --single-step <directory>");
+    eprintln!("       nes-test --klaus <functional|interrupt|decimal> <image> [success address]");
+    std::process::exit(1);
+}
+
+/* Without a reference log the trace is just printed, otherwise it's compared line by line */
+fn run_nestest(rom_file: &str, log_file: Option<&String>) -> i32 {
+    let Some(log_file) = log_file else {
+        let result = nestest::run(rom_file, |line| {
+            println!("{}", line);
+            true
+        });
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            return 1;
+        }
+        return 0;
+    };
+
+    let report = match nestest::compare(rom_file, log_file) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+
+    match report.divergence {
+        None => {
+            println!("nestest: all {} lines match {}", report.matched, log_file);
+            0
+        },
+        Some(divergence) => {
+            println!("nestest: first divergence at line {}", divergence.line);
+            println!("expected: {}", divergence.expected);
+            println!("actual:   {}", divergence.actual);
+            1
+        },
+    }
+}
+
+/* Prints one line per opcode and a summary, the exit code is 1 if anything failed */
+fn run_single_step(dir: &str) -> i32 {
+    let reports = match single_step::run_dir(dir) {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+
+    for report in &reports {
+        if report.passed() {
+            println!("{}: {}/{} OK", report.opcode, report.total, report.total);
+            continue;
+        }
+        println!("{}: state {}/{}, bus {}/{}", report.opcode, report.state_passed, report.total, report.bus_passed, report.total);
+        if let Some(failure) = &report.first_state_failure {
+            println!("    first state failure: {}", failure);
+        }
+        if let Some(failure) = &report.first_bus_failure {
+            println!("    first bus failure:   {}", failure);
+        }
+    }
+
+    let failed = reports.iter().filter(|report| !report.passed()).count();
+    println!("single-step: {} of {} opcodes passed", reports.len() - failed, reports.len());
+    if failed == 0 { 0 } else { 1 }
+}
+
+fn run_klaus(test: &str, image_file: &str, success: Option<&String>) -> i32 {
+    let Some(test) = klaus::KlausTest::from_name(test) else {
+        eprintln!("Unknown test {}, it has to be functional, interrupt or decimal", test);
+        return 1;
+    };
+    let success = match success.map(|arg| parse_addr(arg)) {
+        None => None,
+        Some(Some(addr)) => Some(addr),
+        Some(None) => {
+            eprintln!("The success address has to be a hex number between 0000 and FFFF");
+            return 1;
+        },
+    };
+
+    let report = match klaus::run(test, image_file, success) {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+
+    let test_case = report.test_case.map(|n| format!(" in test ${:02X}", n)).unwrap_or_default();
+    match report.outcome {
+        klaus::KlausOutcome::Passed => println!("{}: passed after {} cycles", image_file, report.cycles),
+        klaus::KlausOutcome::Trapped => println!("{}: trapped at ${:04X}{}", image_file, report.pc, test_case),
+        klaus::KlausOutcome::Jammed => println!("{}: CPU jammed at ${:04X}{}", image_file, report.pc, test_case),
+        klaus::KlausOutcome::TimedOut => println!("{}: still running after {} cycles, pc ${:04X}{}", image_file, report.cycles, report.pc, test_case),
+    }
+    if report.outcome == klaus::KlausOutcome::Passed { 0 } else { 1 }
+}
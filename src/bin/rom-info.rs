@@ -0,0 +1,84 @@
+/*  rom-info.rs
+*   Shows what's in a .nes file: rom-info <rom> [--disasm [first address] [last address]]
+*/
+
+use nes_emulator::bus::BUS;
+use nes_emulator::disasm::parse_addr;
+use nes_emulator::{Cartridge, RomInfo};
+
+use std::fs;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.len() < 2 {
+        eprintln!("Usage: rom-info <rom> [--disasm [first address] [last address]]");
+        std::process::exit(1);
+    }
+
+    /* ROM dump, addresses in hex */
+    if args.len() >= 3 && args[2] == "--disasm" {
+        std::process::exit(run_disasm(&args[1], args.get(3), args.get(4)));
+    }
+
+    std::process::exit(run_info(&args[1]));
+}
+
+fn run_info(rom_file: &str) -> i32 {
+    let info = match fs::read(rom_file).map_err(|e| format!("Error opening {}: {}", rom_file, e)).and_then(|image| RomInfo::parse(&image)) {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+
+    println!("{}", rom_file);
+    println!("mapper:    {}", info.mapper_id);
+    println!("PRG ROM:   {} x 16KB", info.prg_banks);
+    if info.chr_banks == 0 {
+        println!("CHR RAM:   8KB");
+    } else {
+        println!("CHR ROM:   {} x 8KB", info.chr_banks);
+    }
+    println!("mirroring: {:?}", info.mirror);
+    println!("region:    {:?}", info.region);
+    println!("battery:   {}", if info.battery { "yes" } else { "no" });
+    println!("trainer:   {}", if info.trainer { "yes" } else { "no" });
+
+    /* Loading it is the only way to find out if we can run it */
+    match Cartridge::load(rom_file) {
+        Ok(_) => println!("supported: yes"),
+        Err(e) => println!("supported: no ({})", e),
+    }
+    0
+}
+
+/* Prints the disassembly of the cartridge as the CPU sees it, $8000-$FFFF if no range is given */
+fn run_disasm(rom_file: &str, first: Option<&String>, last: Option<&String>) -> i32 {
+    let parse = |arg: Option<&String>, default: u16| -> Option<u16> {
+        match arg {
+            None => Some(default),
+            Some(arg) => parse_addr(arg),
+        }
+    };
+    let (Some(first), Some(last)) = (parse(first, 0x8000), parse(last, 0xFFFF)) else {
+        eprintln!("Addresses have to be hex numbers between 0000 and FFFF");
+        return 1;
+    };
+
+    let cartridge = match Cartridge::load(rom_file) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+    let mut bus = BUS::new();
+    bus.insert_cartridge(cartridge);
+
+    for line in bus.disassemble(first..=last) {
+        println!("{}", line);
+    }
+    0
+}
@@ -3,130 +3,312 @@
 *   as it's communicating with absolutely everything (CPU, PPU, APU, RAM, etc.).
 *   
 *   Please only modify this if you know what you are doing.
+*
+*   The CPU address space:
+*   $0000-$1FFF  2KB of RAM, mirrored 4 times (a device, see device.rs)
+*   $2000-$3FFF  the 8 PPU registers, mirrored every 8 bytes
+*   $4000-$4017  APU and I/O (OAM DMA on $4014, controllers on $4016/$4017)
+*   $4018-$401F  the CPU test mode, disabled
+*   $4020-$FFFF  the cartridge
 */
 
-use crate::cartridge;
-use crate::CPU;
-use crate::PPU;
-use crate::IPPU;
-use crate::ICPU;
-use crate::ICartridge;
+use crate::apu::{APU, IAPU};
+use crate::cartridge::{Cartridge, ICartridge};
+use crate::device::{BusDevice, Ram};
+use crate::disasm::{self, DisasmLine};
+use crate::dma::{DMA, DmaCycle};
+use crate::input::Controller;
+use crate::ppu::{PPU, IPPU};
 
-use std::borrow::BorrowMut;
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use std::any::Any;
+use std::ops::RangeInclusive;
 
 
+/* Everything the CPU needs from whatever it is connected to. The BUS is the real thing, but
+*  test harnesses and tools can plug in their own memory (see flat_bus.rs).
+*
+*  The CPU doesn't keep a pointer to its bus, it gets it handed in on every clock(). That way
+*  nobody needs an Rc to the other and whoever owns the two decides what the CPU is connected to.
+*
+*  read is what the CPU does, it's allowed to have side effects (reading $2002 clears the
+*  vblank flag, reading $2007 moves the read buffer along, mappers can count reads...).
+*  peek is for everyone else, debuggers, disassemblers and memory viewers get the same value
+*  but nothing in the machine changes. */
+pub trait CpuBus {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    fn peek(&self, addr: u16) -> u8;
+}
+
+#[derive(Clone)]
 pub struct BUS {
-    pub cpu_ram: [u8; 2048],
-    //pub cpu: Weak<Rc<RefCell<dyn ICPU>>>,
-    //pub ppu: Weak<Rc<RefCell<dyn IPPU>>>,
-    //pub cartridge: Weak<Rc<RefCell<dyn ICartridge>>>,
-    
-    /* New pointer system */
-    pub cpu: Option<Rc<RefCell<dyn ICPU>>>,
-    pub ppu: Option<Rc<RefCell<dyn IPPU>>>,
-    pub cartridge: Option<Rc<RefCell<dyn ICartridge>>>,
-    
-    pub n_sys_clockcounter: u32,
+    /* The BUS owns everything the CPU can reach, the CPU itself is owned by the Nes (see nes.rs)
+    *  and gets the BUS handed in on every clock */
+    pub ppu: PPU,
+    pub apu: APU,
+    pub cartridge: Cartridge,
+    pub controllers: [Controller; 2],
+    pub dma: DMA,
+
+    /* The last value that was on the data bus. Nothing answers to some addresses, reading
+    *  them gets this instead (open bus), some games actually depend on it */
+    pub data_bus: u8,
+
+    /* Everything that was plugged in with attach_device(), together with the addresses each
+    *  of them answers to (index into devices). The devices attached last come first */
+    devices: Vec<Box<dyn BusDevice>>,
+    device_ranges: Vec<(RangeInclusive<u16>, usize)>,
+    ticking_devices: Vec<usize>,
 }
 
 impl BUS {
-    pub fn new() -> Rc<RefCell<Self>> {
-        Rc::new(RefCell::new(BUS { 
-            cpu_ram: [0; 2048], 
-            //cpu: Weak::new(), 
-            //ppu: Weak::new(), 
-            //cartridge: Weak::new(),
-            cpu: None,
-            ppu: None,
-            cartridge: None,
-            n_sys_clockcounter: 0 
-        }))
+    pub fn new() -> Self {
+        let mut bus = BUS {
+            ppu: PPU::new(),
+            apu: APU::new(),
+            /* Nothing inserted yet, an empty cartridge doesn't claim any address */
+            cartridge: Cartridge::empty(),
+            controllers: [Controller::new(); 2],
+            dma: DMA::new(),
+            data_bus: 0x00,
+            devices: Vec::new(),
+            device_ranges: Vec::new(),
+            ticking_devices: Vec::new(),
+        };
+        bus.attach_device(Box::new(Ram::new(0x0000..=0x1FFF, 2048)));
+        bus
+    }
+
+    /* Plugs a device into the bus, it gets the addresses it asks for (see device.rs). Where
+    *  devices overlap the one attached last is on top, the ones underneath don't see anything.
+    *  Reads come from the topmost device instead of what is built in there (PPU, APU,
+    *  cartridge...), writes go to the topmost device and the built in one, so expansion audio
+    *  hears the same writes as its mapper. Returns the id for device() and device_mut() */
+    pub fn attach_device(&mut self, device: Box<dyn BusDevice>) -> usize {
+        let id = self.devices.len();
+        let ranges = device.ranges().into_iter().map(|range| (range, id));
+        self.device_ranges.splice(0..0, ranges);
+        if device.needs_tick() {
+            self.ticking_devices.push(id);
+        }
+        self.devices.push(device);
+        id
+    }
+
+    /* The device attached as id, if it's a T. That's how whoever attached it gets to look
+    *  inside (or reset it) later */
+    pub fn device<T: BusDevice>(&self, id: usize) -> Option<&T> {
+        let device: &dyn Any = self.devices.get(id)?.as_ref();
+        device.downcast_ref()
+    }
+    pub fn device_mut<T: BusDevice>(&mut self, id: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.devices.get_mut(id)?.as_mut();
+        device.downcast_mut()
+    }
+
+    /* Once per CPU cycle */
+    pub fn tick_devices(&mut self) {
+        for &id in self.ticking_devices.iter() {
+            self.devices[id].tick();
+        }
+    }
+
+    fn device_at(&self, addr: u16) -> Option<usize> {
+        self.device_ranges.iter().find(|(range, _)| range.contains(&addr)).map(|&(_, id)| id)
     }
 
     /* This is actually cpu_write, I was jsut too lazy to rename it */
     pub fn write(&mut self, addr: u16, data: u8) {
-        if let Some(cartridge) = self.cartridge.as_ref() {
-            if (**cartridge).borrow_mut().cpu_write(addr, data) == false {
-                return;
-            }
+        /* Whatever the CPU writes is on the data bus afterwards, even if nobody listens */
+        self.data_bus = data;
+
+        if let Some(id) = self.device_at(addr) {
+            self.devices[id].write(addr, data);
         }
 
-        if addr <= 0x1FFF {
-            self.cpu_ram[addr as usize] = data;  
-        } else if addr >= 0x2000 && addr >= 0x3FFF {
-            if let Some(ppu) = self.ppu.as_ref() {
-                (**ppu).borrow_mut().cpu_write(addr & 0x0007, data);
-            }
+        match addr {
+            /* RAM is a device */
+            0x0000..=0x1FFF => {},
+            0x2000..=0x3FFF => self.ppu.cpu_write(&mut self.cartridge, addr & 0x0007, data),
+            0x4014 => self.dma.start_oam(data),
+            0x4016 => {
+                /* The strobe goes to both controllers */
+                for controller in self.controllers.iter_mut() {
+                    controller.write(data);
+                }
+            },
+            0x4000..=0x4017 => self.apu.cpu_write(addr, data),
+            /* The CPU test mode registers, they're disabled on every NES that was sold */
+            0x4018..=0x401F => {},
+            0x4020..=0xFFFF => {
+                self.cartridge.cpu_write(addr, data);
+            },
         }
     }
 
     /* This is actually cpu_read, I was jsut too lazy to rename it */
-    pub fn read(&self, addr: u16, readonly: bool) -> u8 {
-        let mut data: u8 = 0x00;
-        
-        if let Some(cartridge) = self.cartridge.as_ref(){
-            if (**cartridge).borrow_mut().cpu_read(addr, data) == false{
-                // !!! TODO: look if this should actually return 0
-                return 0;
-            }
-        }
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let open_bus = self.data_bus;
+
+        let data = if let Some(id) = self.device_at(addr) {
+            self.devices[id].read(addr)
+        } else {
+            self.read_builtin(addr, open_bus)
+        };
 
-        if addr <= 0x1FFF{
-            data = self.cpu_ram[(addr as usize) & 0x07FF];
-        } else if addr >= 0x2000 && addr <= 0x3FFF{
-            if let Some(ppu) = self.ppu.as_ref(){
-                data = (**ppu).borrow_mut().cpu_read(addr & 0x000f, readonly);
-            }
+        self.data_bus = data;
+        data
+    }
+
+    fn read_builtin(&mut self, addr: u16, open_bus: u8) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => open_bus,
+            0x2000..=0x3FFF => self.ppu.cpu_read(&mut self.cartridge, addr & 0x0007),
+            /* Bit 5 of $4015 isn't driven */
+            0x4015 => self.apu.cpu_read(addr) | (open_bus & 0x20),
+            /* The controllers only drive the lowest bits, the rest is whatever was there */
+            0x4016 | 0x4017 => self.controllers[(addr & 0x0001) as usize].read() | (open_bus & 0xE0),
+            /* The other APU registers and $4014 can only be written, test mode is off */
+            0x4000..=0x401F => open_bus,
+            0x4020..=0xFFFF => {
+                let mut data = open_bus;
+                self.cartridge.cpu_read(addr, &mut data);
+                data
+            },
         }
-        return data;
     }
 
-    pub fn insert_cartridge(&mut self, cartridge: &Rc<Rc<RefCell<dyn ICartridge>>>){
-        if let Some(ppu) = self.ppu.as_ref() {
-            (**ppu).borrow_mut().connect_cartridge(cartridge);
+    /* Same as read, but without any side effects. Keep the two in sync! */
+    pub fn peek(&self, addr: u16) -> u8 {
+        let open_bus = self.data_bus;
+
+        if let Some(id) = self.device_at(addr) {
+            return self.devices[id].peek(addr);
+        }
+
+        match addr {
+            0x0000..=0x1FFF => open_bus,
+            0x2000..=0x3FFF => self.ppu.cpu_peek(&self.cartridge, addr & 0x0007),
+            0x4015 => self.apu.cpu_peek(addr) | (open_bus & 0x20),
+            0x4016 | 0x4017 => self.controllers[(addr & 0x0001) as usize].peek() | (open_bus & 0xE0),
+            0x4000..=0x401F => open_bus,
+            0x4020..=0xFFFF => {
+                let mut data = open_bus;
+                self.cartridge.cpu_peek(addr, &mut data);
+                data
+            },
         }
     }
-    pub fn reset(&mut self){
-        if let Some(cpu) = self.cpu.as_ref(){
-            (**cpu).borrow_mut().reset();
-            self.n_sys_clockcounter = 0;
+
+    /* One CPU cycle that the DMA took from the CPU (see dma.rs), the DMA reads through the
+    *  BUS like the CPU would, so reading from registers has the same side effects */
+    pub fn dma_cycle(&mut self, get_cycle: bool) {
+        match self.dma.cycle(get_cycle) {
+            DmaCycle::Idle => {},
+            DmaCycle::OamRead(addr) => {
+                self.dma.oam_data = self.read(addr);
+            },
+            DmaCycle::OamWrite(data) => {
+                self.ppu.cpu_write(&mut self.cartridge, 0x0004, data);
+            },
+            DmaCycle::DmcRead(addr) => {
+                self.dma.dmc_data = Some(self.read(addr));
+            },
         }
     }
-    pub fn clock(&mut self) {
-        println!("running bus.clock()!");
 
-        // Create clones and update counter in an inner block.
-        let (cpu_clone, ppu_clone, counter) = {
-            // 'self' is mutably borrowed here
-            let cpu_clone = self.cpu.clone();
-            let ppu_clone = self.ppu.clone();
-            self.n_sys_clockcounter += 1;
-            (cpu_clone, ppu_clone, self.n_sys_clockcounter)
-        }; // <- End of inner block; the mutable borrow on self is dropped here.
+    /* Disassembles whatever the CPU would see in range, through peek so nothing notices */
+    pub fn disassemble(&self, range: RangeInclusive<u16>) -> Vec<DisasmLine> {
+        disasm::disassemble(range, |addr| self.peek(addr))
+    }
 
-        // Now we call trigger_clock without holding on to the previous borrow.
-        self.trigger_clock(cpu_clone, ppu_clone, counter.into());
+    /* The old cartridge (if any) is simply dropped */
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge){
+        self.cartridge = cartridge;
     }
+}
 
-    pub fn trigger_clock(&self, cpu_clone: Option<Rc<RefCell<dyn ICPU>>>, ppu_clone: Option<Rc<RefCell<dyn IPPU>>>, clock_counter: u64,){
-        if let Some(ppu) = ppu_clone{
-            (*ppu).borrow_mut().clock();
-            println!("ppu clocked!");
-        }
-        if clock_counter % 3 == 0 {
-            // if let Some(cpu) = cpu_clone {
-            //     (*cpu).borrow_mut().clock();
-            //     println!("cpu clocked!");
-            // }
-            self.trigger_clock_cpu(cpu_clone);
+impl Default for BUS {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBus for BUS {
+    fn read(&mut self, addr: u16) -> u8 {
+        BUS::read(self, addr)
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        BUS::write(self, addr, data);
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        BUS::peek(self, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_is_mirrored_up_to_1fff() {
+        let mut bus = BUS::new();
+        bus.write(0x1801, 0x42);
+        for addr in [0x0001, 0x0801, 0x1001, 0x1801] {
+            assert_eq!(bus.read(addr), 0x42);
         }
+        bus.write(0x07FF, 0x17);
+        assert_eq!(bus.read(0x1FFF), 0x17);
+    }
+
+    #[test]
+    fn ppu_registers_are_mirrored_every_8_bytes() {
+        let mut bus = BUS::new();
+        /* OAM address through $3FFB, OAM data through $200C */
+        bus.write(0x3FFB, 0x10);
+        bus.write(0x200C, 0xAB);
+        assert_eq!(bus.ppu.oam[0x10], 0xAB);
+
+        bus.write(0x2013, 0x10);
+        assert_eq!(bus.read(0x3FF4), 0xAB);
     }
-    pub fn trigger_clock_cpu(&self, cpu_clone: Option<Rc<RefCell<dyn ICPU>>>){
-        if let Some(cpu) = cpu_clone {
-            (*cpu).borrow_mut().clock();
-            println!("cpu clocked!");
+
+    #[test]
+    fn unmapped_reads_return_the_data_bus() {
+        let mut bus = BUS::new();
+        for addr in (0x4000..=0x4014).chain(0x4018..=0x401F) {
+            bus.data_bus = addr as u8 ^ 0xA5;
+            assert_eq!(bus.read(addr), addr as u8 ^ 0xA5, "${:04X}", addr);
         }
+        /* Nothing inserted, so the cartridge space is open bus as well */
+        bus.data_bus = 0x3C;
+        assert_eq!(bus.read(0x8000), 0x3C);
+        assert_eq!(bus.peek(0x6000), 0x3C);
+    }
+
+    #[test]
+    fn the_last_value_stays_on_the_data_bus() {
+        let mut bus = BUS::new();
+        bus.write(0x0000, 0x99);
+        assert_eq!(bus.read(0x4018), 0x99);
+        bus.write(0x4000, 0x12);
+        assert_eq!(bus.read(0x4018), 0x12);
+    }
+
+    #[test]
+    fn undriven_bits_of_4015_and_the_controllers_come_from_the_data_bus() {
+        let mut bus = BUS::new();
+        bus.data_bus = 0xFF;
+        assert_eq!(bus.read(0x4015), 0x20);
+
+        bus.controllers[0].buttons = 0x01;
+        bus.controllers[1].buttons = 0x00;
+        bus.write(0x4016, 0x01);
+        bus.write(0x4016, 0x00);
+        bus.data_bus = 0x5F;
+        assert_eq!(bus.read(0x4016), 0x41);
+        assert_eq!(bus.read(0x4017), 0x40);
+        bus.data_bus = 0xFF;
+        assert_eq!(bus.peek(0x4017), 0xE0);
     }
 }
@@ -1,33 +1,122 @@
 
-use std::fs::File;
-use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
+use std::fs;
+use std::io::ErrorKind;
 
 use crate::mapper::IMapper;
+use crate::mapper000::Mapper000;
+use crate::region::Region;
 
-#[repr(C)]
+/* The parts of the 16 byte iNES header we use, the PRG RAM size and padding bytes aren't needed yet */
 #[derive(Debug, Default)]
 struct Header {
-    name: [u8; 4],
     prg_rom_chunks: u8,
     chr_rom_chunks: u8,
     mapper1: u8,
     mapper2: u8,
-    prg_ram_size: u8,
+    /* Byte 9, bit 0 is PAL on plain iNES */
     tv_system1: u8,
-    tv_system2: u8,
-    unused: [u8; 5],
+    /* Byte 12, the CPU/PPU timing on NES 2.0 (padding on plain iNES) */
+    timing: u8,
 }
 
-#[derive(Debug)]
-enum Mirror {
+impl Header {
+    const SIZE: usize = 16;
+
+    fn parse(image: &[u8]) -> Result<Self, String> {
+        if image.len() < Header::SIZE || image[0..4] != *b"NES\x1A" {
+            return Err("Not an iNES image (the header doesn't start with NES<EOF>)".to_string());
+        }
+        Ok(Header {
+            prg_rom_chunks: image[4],
+            chr_rom_chunks: image[5],
+            mapper1: image[6],
+            mapper2: image[7],
+            tv_system1: image[9],
+            timing: image[12],
+        })
+    }
+
+    /* NES 2.0 headers have 10 in bits 2-3 of byte 7 */
+    fn is_nes2(&self) -> bool {
+        self.mapper2 & 0x0C == 0x08
+    }
+
+    /* NES 2.0 has the timing in byte 12, plain iNES only has a PAL bit in byte 9 (which hardly
+    *  any ROM sets). Multi-region ROMs run as NTSC */
+    fn region(&self) -> Region {
+        if self.is_nes2() {
+            match self.timing & 0x03 {
+                1 => Region::Pal,
+                3 => Region::Dendy,
+                _ => Region::Ntsc,
+            }
+        } else if self.tv_system1 & 0x01 != 0 {
+            Region::Pal
+        } else {
+            Region::Ntsc
+        }
+    }
+}
+
+/* How the 4 nametables at $2000-$2FFF end up in the PPU's 2KB of VRAM. Vertical means $2000 and
+*  $2800 are the same (for horizontal scrolling), Horizontal means $2000 and $2400 are the same.
+*  FourScreen cartridges bring their own VRAM so all 4 are different */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirror {
     Vertical,
     Horizontal,
     OnescreenLo,
     OnescreenHi,
+    FourScreen,
+}
+
+/* Everything the header tells us about a ROM, without loading it */
+#[derive(Debug, Clone, Copy)]
+pub struct RomInfo {
+    pub mapper_id: u8,
+    /* 16KB each */
+    pub prg_banks: u8,
+    /* 8KB each, 0 means the cartridge has 8KB of CHR RAM instead */
+    pub chr_banks: u8,
+    pub mirror: Mirror,
+    /* Battery backed PRG RAM at $6000-$7FFF (save games) */
+    pub battery: bool,
+    /* 512 bytes in front of the PRG ROM, which we skip */
+    pub trainer: bool,
+    /* The console the ROM was made for, as far as the header knows */
+    pub region: Region,
 }
 
+impl RomInfo {
+    pub fn parse(image: &[u8]) -> Result<Self, String> {
+        let header = Header::parse(image)?;
+        Ok(RomInfo {
+            mapper_id: (header.mapper2 & 0xF0) | (header.mapper1 >> 4),
+            prg_banks: header.prg_rom_chunks,
+            chr_banks: header.chr_rom_chunks,
+            mirror: if header.mapper1 & 0x08 != 0 {
+                Mirror::FourScreen
+            } else if header.mapper1 & 0x01 != 0 {
+                Mirror::Vertical
+            } else {
+                Mirror::Horizontal
+            },
+            battery: header.mapper1 & 0x02 != 0,
+            trainer: header.mapper1 & 0x04 != 0,
+            region: header.region(),
+        })
+    }
+
+    pub fn prg_size(&self) -> usize {
+        self.prg_banks as usize * 16384
+    }
+    /* Only the CHR ROM, CHR RAM isn't part of the image */
+    pub fn chr_size(&self) -> usize {
+        self.chr_banks as usize * 8192
+    }
+}
+
+#[derive(Clone)]
 pub struct Cartridge{
     pub prg_memory: Vec<u8>,
     pub chr_memory: Vec<u8>,
@@ -38,33 +127,40 @@ pub struct Cartridge{
 
     pub image_valid: bool,
 
+    /* What the header says, mappers that can switch it override it (see ICartridge::mirror) */
     pub mirror: Mirror,
+    /* Four screen cartridges have 4KB of VRAM for the nametables on board */
+    pub vram: Vec<u8>,
+
+    pub region: Region,
 
-    pub mapper: Weak<Rc<RefCell<dyn IMapper>>>,
+    pub mapper: Option<Box<dyn IMapper>>,
 
 }
 
 pub trait ICartridge{
 
-    fn new(file_name: &str) -> Rc<RefCell<Self>>
-    where 
-        Self: Sized;
-
-    /* Functions for accessing the CPU Bus */
-    fn cpu_read(&mut self, addr: u16, data: u8) -> bool;
+    /* Functions for accessing the CPU Bus, they return true if the cartridge handled the access */
+    fn cpu_read(&mut self, addr: u16, data: &mut u8) -> bool;
     fn cpu_write(&mut self, addr: u16, data: u8) -> bool;
 
     /* Functions for accessing the PPU Bus */
-    fn ppu_read(&mut self, addr: u16, data: u8) -> bool;
+    fn ppu_read(&mut self, addr: u16, data: &mut u8) -> bool;
     fn ppu_write(&mut self, addr: u16, data: u8) -> bool;
 
-}
+    /* Like the reads, but mappers that watch the bus (IRQ counters, latches...) don't see them */
+    fn cpu_peek(&self, addr: u16, data: &mut u8) -> bool;
+    fn ppu_peek(&self, addr: u16, data: &mut u8) -> bool;
 
-impl ICartridge for Cartridge{
+    /* The nametable mirroring right now, mappers can change it while the game runs */
+    fn mirror(&self) -> Mirror;
 
-    fn new(file_name: &str) -> Rc<RefCell<Self>>{
-        
-        let mut cart = Cartridge {
+}
+
+impl Cartridge{
+    /* An empty slot, it doesn't answer to any address */
+    pub fn empty() -> Self{
+        Cartridge {
             prg_memory: Vec::new(),
             chr_memory: Vec::new(),
 
@@ -75,118 +171,304 @@ impl ICartridge for Cartridge{
             image_valid: false,
 
             mirror: Mirror::Horizontal,
-            mapper: Weak::new(),
-        };
+            vram: Vec::new(),
+            region: Region::Ntsc,
+            mapper: None,
+        }
+    }
 
-        let mut b_image_valid = false;
-        let file = File::open(file_name).unwrap_or_else(|e| {
+    /* Loads a .nes file, the error says what's wrong with it */
+    pub fn load(file_name: &str) -> Result<Self, String>{
+        let image = fs::read(file_name).map_err(|e| {
             if e.kind() == ErrorKind::NotFound {
-                eprintln!("Error: {} was not found", file_name);
+                format!("{} was not found", file_name)
             } else {
-                eprintln!("Error opening {}: {}", file_name, e);
+                format!("Error opening {}: {}", file_name, e)
             }
-            panic!("Could not open the file");
-        });
-
-        let mut reader = BufReader::new(file);
-
-        let mut header = Header::default();
-        
-        let header_size = size_of::<Header>();
-        let header_buf: &mut [u8] = unsafe {
-            std::slice::from_raw_parts_mut((&mut header as *mut Header) as *mut u8, header_size)
-        };
+        })?;
+        Cartridge::from_bytes(&image).map_err(|e| format!("{}: {}", file_name, e))
+    }
 
-        reader.read_exact(header_buf).unwrap();
+    /* Builds a cartridge from a whole .nes file */
+    pub fn from_bytes(image: &[u8]) -> Result<Self, String>{
+        let info = RomInfo::parse(image)?;
+        /* The CPU would have nothing to run (and the mappers nothing to mirror) */
+        if info.prg_banks == 0 {
+            return Err("There is no PRG ROM".to_string());
+        }
+        let mut cart = Cartridge::empty();
 
-        /* If a "trainer" exists (bit 2 of mapper1 is set), skip 512 bytes. */
-        if header.mapper1 & 0x04 != 0 {
-            reader.seek(SeekFrom::Current(512)).unwrap();
+        cart.mapper_id = info.mapper_id;
+        cart.mirror = info.mirror;
+        cart.region = info.region;
+        if cart.mirror == Mirror::FourScreen {
+            cart.vram = vec![0u8; 4096];
         }
-    
-        // Determine Mapper ID.
-        cart.mapper_id = ((header.mapper2 >> 4) << 4) | (header.mapper1 >> 4);
+        cart.prg_banks = info.prg_banks;
+        cart.chr_banks = info.chr_banks;
+
+        /* Only iNES images are supported for now, NES 2.0 headers are read as if they were iNES */
+        let prg_start = Header::SIZE + if info.trainer { 512 } else { 0 };
+        let chr_start = prg_start + info.prg_size();
+        let chr_end = chr_start + info.chr_size();
+        if image.len() < chr_end {
+            return Err(format!("The image is {} bytes, but the header asks for {}", image.len(), chr_end));
+        }
+        cart.prg_memory = image[prg_start..chr_start].to_vec();
 
-        cart.mirror = if header.mapper1 & 0x01 != 0 {
-            Mirror::Vertical
+        /* No CHR ROM means the cartridge has 8KB of CHR RAM instead */
+        cart.chr_memory = if info.chr_banks == 0 {
+            vec![0u8; 8192]
         } else {
-            Mirror::Horizontal
+            image[chr_start..chr_end].to_vec()
         };
 
-        let n_file_type = 1;
+        cart.mapper = match info.mapper_id {
+            0 => Some(Box::new(Mapper000::new(cart.prg_banks, cart.chr_banks))),
+            id => return Err(format!("Mapper {} isn't supported yet", id)),
+        };
 
-        cart.prg_banks = header.prg_rom_chunks;
-        cart.chr_banks = header.chr_rom_chunks;
-       
-        if n_file_type == 0{
+        cart.image_valid = true;
 
-        }
+        Ok(cart)
+    }
+}
 
-        if n_file_type == 1 {
-            let prg_size = cart.prg_banks as usize * 16384;
-            cart.prg_memory.resize(prg_size as usize, 0);
-            reader.read_exact(&mut cart.prg_memory).unwrap();
+impl ICartridge for Cartridge{
 
-            let chr_size = cart.chr_banks as usize * 8192;
-            cart.chr_memory.resize(chr_size as usize, 0);
-            reader.read_exact(&mut cart.chr_memory).unwrap();
+    fn cpu_read(&mut self, addr: u16, data: &mut u8) -> bool{
+        let mut mapped_addr: u32 = 0;
+        if let Some(mapper) = self.mapper.as_mut(){
+            if mapper.cpu_map_read(addr, &mut mapped_addr){
+                *data = self.prg_memory[mapped_addr as usize];
+                return true;
+            }
         }
-
-
-        if n_file_type == 2{
-
+        false
+    }
+    fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        /* Mappers only map writes to memory that can be written (PRG RAM), never to ROM */
+        let mut mapped_addr: u32 = 0;
+        if let Some(mapper) = self.mapper.as_mut(){
+            if mapper.cpu_map_write(addr, &mut mapped_addr){
+                self.prg_memory[mapped_addr as usize] = data;
+                return true;
+            }
         }
+        false
+    }
 
-
-        if cart.mapper_id == 0 {
-            println!("Using Mapper_000 with {} PRG banks and {} CHR banks", cart.prg_banks, cart.chr_banks);
+    fn ppu_read(&mut self, addr: u16, data: &mut u8) -> bool{
+        if is_nametable(addr) {
+            if let Some(mapper) = self.mapper.as_mut() {
+                if mapper.nametable_read(addr, data) {
+                    return true;
+                }
+            }
+            if let Some(vram) = self.vram.get((addr & 0x0FFF) as usize) {
+                *data = *vram;
+                return true;
+            }
+            return false;
         }
 
-        cart.image_valid = true;
-    
-        Rc::new(RefCell::new(cart))
-    }
-
-    fn cpu_read(&mut self, addr: u16, mut data: u8) -> bool{
         let mut mapped_addr: u32 = 0;
-        if let Some(mapper) = self.mapper.upgrade(){
-            if (**mapper).borrow_mut().cpu_map_read(addr, mapped_addr){
-                data = self.prg_memory[mapped_addr as usize];
+        if let Some(mapper) = self.mapper.as_mut(){
+            if mapper.ppu_map_read(addr, &mut mapped_addr){
+                *data = self.chr_memory[mapped_addr as usize];
                 return true;
             }
         }
-        return false;
+        false
     }
-    fn cpu_write(&mut self, addr: u16, mut data: u8) -> bool {
+    fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
+        if is_nametable(addr) {
+            if let Some(mapper) = self.mapper.as_mut() {
+                if mapper.nametable_write(addr, data) {
+                    return true;
+                }
+            }
+            if let Some(vram) = self.vram.get_mut((addr & 0x0FFF) as usize) {
+                *vram = data;
+                return true;
+            }
+            return false;
+        }
+
         let mut mapped_addr: u32 = 0;
-        if let Some(mapper) = self.mapper.upgrade(){
-            if (**mapper).borrow_mut().cpu_map_write(addr, mapped_addr){
-                data = self.prg_memory[mapped_addr as usize];
+        if let Some(mapper) = self.mapper.as_mut(){
+            if mapper.ppu_map_write(addr, &mut mapped_addr){
+                self.chr_memory[mapped_addr as usize] = data;
                 return true;
             }
         }
-        return false;
+        false
     }
 
-    fn ppu_read(&mut self, addr: u16, mut data: u8) -> bool{
+    fn cpu_peek(&self, addr: u16, data: &mut u8) -> bool{
         let mut mapped_addr: u32 = 0;
-        if let Some(mapper) = self.mapper.upgrade(){
-            if (**mapper).borrow_mut().ppu_map_read(addr, mapped_addr){
-                data = self.chr_memory[mapped_addr as usize];
+        if let Some(mapper) = self.mapper.as_ref(){
+            if mapper.cpu_map_peek(addr, &mut mapped_addr){
+                *data = self.prg_memory[mapped_addr as usize];
                 return true;
             }
         }
-        return false;
+        false
     }
-    fn ppu_write(&mut self, addr: u16, mut data: u8) -> bool {
+    fn ppu_peek(&self, addr: u16, data: &mut u8) -> bool{
+        if is_nametable(addr) {
+            if let Some(mapper) = self.mapper.as_ref() {
+                if mapper.nametable_peek(addr, data) {
+                    return true;
+                }
+            }
+            if let Some(vram) = self.vram.get((addr & 0x0FFF) as usize) {
+                *data = *vram;
+                return true;
+            }
+            return false;
+        }
+
         let mut mapped_addr: u32 = 0;
-        if let Some(mapper) = self.mapper.upgrade(){
-            if (**mapper).borrow_mut().cpu_map_read(addr, mapped_addr){
-                data = self.chr_memory[mapped_addr as usize];
+        if let Some(mapper) = self.mapper.as_ref(){
+            if mapper.ppu_map_peek(addr, &mut mapped_addr){
+                *data = self.chr_memory[mapped_addr as usize];
                 return true;
             }
         }
-        return false;
+        false
+    }
+
+    fn mirror(&self) -> Mirror{
+        match self.mapper.as_ref().and_then(|mapper| mapper.mirror()) {
+            Some(mirror) => mirror,
+            None => self.mirror,
+        }
     }
 
 }
+
+/* $2000-$3EFF, the palette at $3F00 is always inside the PPU */
+fn is_nametable(addr: u16) -> bool {
+    (0x2000..0x3F00).contains(&addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* An iNES image with the given header bytes 4-7 and zeroed ROM behind it */
+    fn image(prg_banks: u8, chr_banks: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut image = vec![b'N', b'E', b'S', 0x1A, prg_banks, chr_banks, flags6, flags7];
+        image.resize(Header::SIZE + prg_banks as usize * 16384 + chr_banks as usize * 8192, 0);
+        image
+    }
+
+    #[test]
+    fn rom_info_comes_from_the_header() {
+        let info = RomInfo::parse(&image(2, 1, 0x13, 0x40)).unwrap();
+        assert_eq!(info.mapper_id, 0x41);
+        assert_eq!(info.prg_size(), 32768);
+        assert_eq!(info.chr_size(), 8192);
+        assert!(matches!(info.mirror, Mirror::Vertical));
+        assert!(info.battery);
+        assert!(!info.trainer);
+    }
+
+    #[test]
+    fn a_cartridge_without_chr_rom_gets_chr_ram() {
+        let cart = Cartridge::from_bytes(&image(1, 0, 0, 0)).unwrap();
+        assert_eq!(cart.prg_memory.len(), 16384);
+        assert_eq!(cart.chr_memory.len(), 8192);
+        assert!(cart.image_valid);
+    }
+
+    #[test]
+    fn broken_images_are_refused() {
+        assert!(Cartridge::from_bytes(b"NES").is_err());
+        assert!(Cartridge::from_bytes(&image(0, 1, 0, 0)).is_err());
+        assert!(Cartridge::from_bytes(&image(1, 1, 0x10, 0)).is_err());
+
+        let mut cut_off = image(2, 1, 0, 0);
+        cut_off.truncate(cut_off.len() - 1);
+        assert!(Cartridge::from_bytes(&cut_off).is_err());
+    }
+
+    #[test]
+    fn four_screen_cartridges_bring_their_own_vram() {
+        let cart = Cartridge::from_bytes(&image(1, 1, 0x09, 0)).unwrap();
+        assert_eq!(cart.mirror(), Mirror::FourScreen);
+        assert_eq!(cart.vram.len(), 4096);
+
+        let cart = Cartridge::from_bytes(&image(1, 1, 0x01, 0)).unwrap();
+        assert_eq!(cart.mirror(), Mirror::Vertical);
+        assert!(cart.vram.is_empty());
+    }
+
+    #[test]
+    fn the_region_comes_from_byte_9_or_nes_2_0_byte_12() {
+        let region = |flags7: u8, tv_system1: u8, timing: u8| {
+            let mut image = image(1, 1, 0, flags7);
+            image[9] = tv_system1;
+            image[12] = timing;
+            RomInfo::parse(&image).unwrap().region
+        };
+        assert_eq!(region(0, 0, 0), Region::Ntsc);
+        assert_eq!(region(0, 1, 0), Region::Pal);
+        /* Byte 12 is padding on plain iNES */
+        assert_eq!(region(0, 0, 3), Region::Ntsc);
+
+        /* NES 2.0 only looks at byte 12 */
+        assert_eq!(region(0x08, 0, 0), Region::Ntsc);
+        assert_eq!(region(0x08, 0, 1), Region::Pal);
+        assert_eq!(region(0x08, 1, 0), Region::Ntsc);
+        assert_eq!(region(0x08, 0, 3), Region::Dendy);
+        /* Multi-region */
+        assert_eq!(region(0x08, 0, 2), Region::Ntsc);
+    }
+
+    /* NROM, except that it switches to one-screen mirroring and keeps $2400 to itself */
+    #[derive(Clone)]
+    struct TestMapper(Mapper000, u8);
+
+    impl IMapper for TestMapper {
+        fn new(prg_banks: u8, chr_banks: u8) -> Self { TestMapper(Mapper000::new(prg_banks, chr_banks), 0) }
+        fn box_clone(&self) -> Box<dyn IMapper> { Box::new(self.clone()) }
+        fn cpu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.cpu_map_read(addr, mapped_addr) }
+        fn cpu_map_write(&mut self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.cpu_map_write(addr, mapped_addr) }
+        fn ppu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.ppu_map_read(addr, mapped_addr) }
+        fn ppu_map_write(&mut self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.ppu_map_write(addr, mapped_addr) }
+        fn cpu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.cpu_map_peek(addr, mapped_addr) }
+        fn ppu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool { self.0.ppu_map_peek(addr, mapped_addr) }
+
+        fn mirror(&self) -> Option<Mirror> { Some(Mirror::OnescreenHi) }
+        fn nametable_read(&mut self, addr: u16, data: &mut u8) -> bool {
+            *data = self.1;
+            addr == 0x2400
+        }
+        fn nametable_write(&mut self, addr: u16, data: u8) -> bool {
+            self.1 = data;
+            addr == 0x2400
+        }
+        fn nametable_peek(&self, addr: u16, data: &mut u8) -> bool {
+            *data = self.1;
+            addr == 0x2400
+        }
+    }
+
+    #[test]
+    fn mappers_can_take_over_the_nametables() {
+        let mut cart = Cartridge::from_bytes(&image(1, 1, 0x01, 0)).unwrap();
+        cart.mapper = Some(Box::new(TestMapper::new(1, 1)));
+        assert_eq!(cart.mirror(), Mirror::OnescreenHi);
+
+        let mut data = 0;
+        assert!(cart.ppu_write(0x2400, 0x42));
+        assert!(cart.ppu_read(0x2400, &mut data));
+        assert_eq!(data, 0x42);
+        assert!(cart.ppu_peek(0x2400, &mut data));
+        /* Everything else is left to the PPU */
+        assert!(!cart.ppu_write(0x2000, 0x42));
+        assert!(!cart.ppu_read(0x2800, &mut data));
+    }
+}
@@ -9,61 +9,435 @@
 *
 *   Back to the Programming! In this file you will not only find the CPU struct, together with the
 *   ICPU trait, which holds some function declarations, you will also find the Instruction struct,
-*   which points to the corresponding method inside CPU.
-*   Currently there are no implementations for the different CPU instructions, but if I or whoever
-*   is reading this code feels like they have too much time, they are welcome to create methods for
-*   all instructions and a table filled with Instruction, which are all pointing to their methods.
+*   which describes an opcode. The LOOKUP table has one for each of them, the CPU matches on its
+*   addressing mode and mnemonic to call the corresponding methods.
+*   All 151 official instructions are implemented and the lookup table has an entry for every one
+*   of the 256 opcodes. The unofficial (a.k.a. illegal) opcodes are implemented as well, what the CPU
+*   does when it runs into one of them is decided by illegal_opcode_mode.
+*
+*   Every call to clock() is one CPU cycle and does exactly the read or write the real CPU does on
+*   that cycle, including all of the dummy reads and writes. The addressing modes drive this, see
+*   the comment above addrmode_imp().
 *
 */
 
 
-use crate::bus::BUS;
+use crate::bus::CpuBus;
+
+use std::collections::BTreeMap;
+
+
 
-use std::borrow::Borrow;
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+/* One variant for every ins_* function */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC, CLD, CLI, CLV, CMP, CPX,
+    CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP, JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA,
+    PLP, ROL, ROR, RTI, RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
+    /* Unofficial */
+    ALR, ANC, ANE, ARR, DCP, ISB, JAM, LAS, LAX, LXA, RLA, RRA, SAX, SBX, SHA, SHX, SHY, SLO, SRE,
+    TAS,
+}
 
+impl std::fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
 
+/* One variant for every addrmode_* function, so we can tell which one an instruction uses */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrMode {
+    IMP,
+    IMM,
+    ZP0,
+    ZPX,
+    ZPY,
+    REL,
+    ABS,
+    ABX,
+    ABY,
+    IND,
+    IZX,
+    IZY,
+}
 
-type OpcodeFunction = Rc<Box<dyn Fn(&mut dyn ICPU)->u8>>;
-type AddrmodeFunction = Rc<Box<dyn Fn(&mut dyn ICPU)->u8>>;
+/* What an instruction does with its operand. Together with the addressing mode this decides
+*  what happens on every cycle of the instruction. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /* Only reads it (LDA, CMP, ...). Indexed modes only take an extra cycle when crossing a page */
+    Read,
+    /* Only writes it (STA, STX, ...) */
+    Write,
+    /* Reads it, writes it back unchanged and then writes the result (INC, ASL, ...) */
+    ReadModifyWrite,
+}
+
+impl Access {
+    const fn of(mnemonic: Mnemonic) -> Self {
+        use Mnemonic::*;
+        match mnemonic {
+            STA | STX | STY | SAX | SHA | SHX | SHY | TAS => Access::Write,
+            ASL | LSR | ROL | ROR | INC | DEC
+            | SLO | SRE | RLA | RRA | DCP | ISB => Access::ReadModifyWrite,
+            _ => Access::Read,
+        }
+    }
+}
+
+/* What the CPU should do when it comes across an unofficial opcode */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IllegalOpcodeMode {
+    /* Just run it like the real 2A03 would */
+    Execute,
+    /* Stop in front of the opcode and set `trapped`, so a debugger can take over. resume_trap()
+    *  runs the opcode and carries on */
+    Trap,
+    /* Run it, but remember where it happened (see illegal_opcode_log) */
+    Log,
+}
 
+/* Which chip the core behaves like. The 2A03 in the NES is a 6502 with the decimal mode cut
+*  out (Nintendo didn't want to pay for the patent), so the D flag can be set and cleared but
+*  ADC and SBC ignore it. A plain NMOS 6502 does BCD math when D is set, which is what you want
+*  for anything that isn't a NES (and for the Klaus Dormann decimal test). */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVariant {
+    Ricoh2A03,
+    Nmos6502,
+}
+
+/* Everything there is to know about an opcode, the CPU looks it up in LOOKUP */
+#[derive(Debug, Clone, Copy)]
 pub struct Instruction{
-    pub name: String,
+    pub mnemonic: Mnemonic,
+    /* Without page crossings or taken branches */
     pub cycles: u8,
-    pub opcode_function: OpcodeFunction,
-    pub addrmode_function: AddrmodeFunction,
+    pub addrmode: AddrMode,
+    pub access: Access,
+    pub illegal: bool,
 }
 
 impl Instruction{
-    pub fn new(
-        name: impl Into<String>,
-        cycles: u8,
-        opcode_fn: fn(&mut dyn ICPU) -> u8,
-        addrmode_fn: fn(&mut dyn ICPU) -> u8,
-    ) -> Self {
+    pub const fn new(mnemonic: Mnemonic, cycles: u8, addrmode: AddrMode) -> Self {
         Self {
-            name: name.into(),
+            mnemonic,
             cycles,
-            addrmode_function: Rc::new(Box::new(move |cpu: &mut dyn ICPU| {addrmode_fn(cpu)})),
-            opcode_function: Rc::new(Box::new(move |cpu: &mut dyn ICPU| {opcode_fn(cpu)})),
+            addrmode,
+            access: Access::of(mnemonic),
+            illegal: false,
         }
     }
-    pub fn new_empty() -> Self {
-        Self {
-            name: "XXX".to_string(),
-            cycles: 2,
-            opcode_function: Rc::new(Box::new(|cpu: &mut dyn ICPU| {
-                cpu.ins_xxx()
-            })),
-            addrmode_function: Rc::new(Box::new(|cpu: &mut dyn ICPU| {
-                cpu.addrmode_imp()
-            })),
-        }
+    /* Same as new(), but marks the instruction as one of the unofficial opcodes */
+    pub const fn new_illegal(mnemonic: Mnemonic, cycles: u8, addrmode: AddrMode) -> Self {
+        let mut instruction = Self::new(mnemonic, cycles, addrmode);
+        instruction.illegal = true;
+        instruction
     }
 }
 
-
+/* Setting up The lookup table... I hate my life */
+/* Using This as my template: https://github.com/OneLoneCoder/olcNES/blob/master/Part%232%20-%20CPU/olc6502.cpp */
+/* Cycle counts are the base counts, page crossings and taken branches add to them */
+pub const LOOKUP: [Instruction; 256] = {
+    use Mnemonic::*;
+    use AddrMode::*;
+    let mut lookup = [Instruction::new_illegal(JAM, 2, IMP); 256];
+    /* 0x0n Opcodes */
+    lookup[0x00] = Instruction::new(BRK, 7, IMP);
+    lookup[0x01] = Instruction::new(ORA, 6, IZX);
+    lookup[0x02] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x03] = Instruction::new_illegal(SLO, 8, IZX);
+    lookup[0x04] = Instruction::new_illegal(NOP, 3, ZP0);
+    lookup[0x05] = Instruction::new(ORA, 3, ZP0);
+    lookup[0x06] = Instruction::new(ASL, 5, ZP0);
+    lookup[0x07] = Instruction::new_illegal(SLO, 5, ZP0);
+    lookup[0x08] = Instruction::new(PHP, 3, IMP);
+    lookup[0x09] = Instruction::new(ORA, 2, IMM);
+    lookup[0x0A] = Instruction::new(ASL, 2, IMP);
+    lookup[0x0B] = Instruction::new_illegal(ANC, 2, IMM);
+    lookup[0x0C] = Instruction::new_illegal(NOP, 4, ABS);
+    lookup[0x0D] = Instruction::new(ORA, 4, ABS);
+    lookup[0x0E] = Instruction::new(ASL, 6, ABS);
+    lookup[0x0F] = Instruction::new_illegal(SLO, 6, ABS);
+
+    /* 0x1n Opcodes */
+    lookup[0x10] = Instruction::new(BPL, 2, REL);
+    lookup[0x11] = Instruction::new(ORA, 5, IZY);
+    lookup[0x12] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x13] = Instruction::new_illegal(SLO, 8, IZY);
+    lookup[0x14] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0x15] = Instruction::new(ORA, 4, ZPX);
+    lookup[0x16] = Instruction::new(ASL, 6, ZPX);
+    lookup[0x17] = Instruction::new_illegal(SLO, 6, ZPX);
+    lookup[0x18] = Instruction::new(CLC, 2, IMP);
+    lookup[0x19] = Instruction::new(ORA, 4, ABY);
+    lookup[0x1A] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0x1B] = Instruction::new_illegal(SLO, 7, ABY);
+    lookup[0x1C] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0x1D] = Instruction::new(ORA, 4, ABX);
+    lookup[0x1E] = Instruction::new(ASL, 7, ABX);
+    lookup[0x1F] = Instruction::new_illegal(SLO, 7, ABX);
+
+    /* 0x2n Opcodes */
+    lookup[0x20] = Instruction::new(JSR, 6, ABS);
+    lookup[0x21] = Instruction::new(AND, 6, IZX);
+    lookup[0x22] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x23] = Instruction::new_illegal(RLA, 8, IZX);
+    lookup[0x24] = Instruction::new(BIT, 3, ZP0);
+    lookup[0x25] = Instruction::new(AND, 3, ZP0);
+    lookup[0x26] = Instruction::new(ROL, 5, ZP0);
+    lookup[0x27] = Instruction::new_illegal(RLA, 5, ZP0);
+    lookup[0x28] = Instruction::new(PLP, 4, IMP);
+    lookup[0x29] = Instruction::new(AND, 2, IMM);
+    lookup[0x2A] = Instruction::new(ROL, 2, IMP);
+    lookup[0x2B] = Instruction::new_illegal(ANC, 2, IMM);
+    lookup[0x2C] = Instruction::new(BIT, 4, ABS);
+    lookup[0x2D] = Instruction::new(AND, 4, ABS);
+    lookup[0x2E] = Instruction::new(ROL, 6, ABS);
+    lookup[0x2F] = Instruction::new_illegal(RLA, 6, ABS);
+
+    /* 0x3n Opcodes */
+    lookup[0x30] = Instruction::new(BMI, 2, REL);
+    lookup[0x31] = Instruction::new(AND, 5, IZY);
+    lookup[0x32] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x33] = Instruction::new_illegal(RLA, 8, IZY);
+    lookup[0x34] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0x35] = Instruction::new(AND, 4, ZPX);
+    lookup[0x36] = Instruction::new(ROL, 6, ZPX);
+    lookup[0x37] = Instruction::new_illegal(RLA, 6, ZPX);
+    lookup[0x38] = Instruction::new(SEC, 2, IMP);
+    lookup[0x39] = Instruction::new(AND, 4, ABY);
+    lookup[0x3A] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0x3B] = Instruction::new_illegal(RLA, 7, ABY);
+    lookup[0x3C] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0x3D] = Instruction::new(AND, 4, ABX);
+    lookup[0x3E] = Instruction::new(ROL, 7, ABX);
+    lookup[0x3F] = Instruction::new_illegal(RLA, 7, ABX);
+
+    /* 0x4n Opcodes */
+    lookup[0x40] = Instruction::new(RTI, 6, IMP);
+    lookup[0x41] = Instruction::new(EOR, 6, IZX);
+    lookup[0x42] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x43] = Instruction::new_illegal(SRE, 8, IZX);
+    lookup[0x44] = Instruction::new_illegal(NOP, 3, ZP0);
+    lookup[0x45] = Instruction::new(EOR, 3, ZP0);
+    lookup[0x46] = Instruction::new(LSR, 5, ZP0);
+    lookup[0x47] = Instruction::new_illegal(SRE, 5, ZP0);
+    lookup[0x48] = Instruction::new(PHA, 3, IMP);
+    lookup[0x49] = Instruction::new(EOR, 2, IMM);
+    lookup[0x4A] = Instruction::new(LSR, 2, IMP);
+    lookup[0x4B] = Instruction::new_illegal(ALR, 2, IMM);
+    lookup[0x4C] = Instruction::new(JMP, 3, ABS);
+    lookup[0x4D] = Instruction::new(EOR, 4, ABS);
+    lookup[0x4E] = Instruction::new(LSR, 6, ABS);
+    lookup[0x4F] = Instruction::new_illegal(SRE, 6, ABS);
+
+    /* 0x5n Opcodes */
+    lookup[0x50] = Instruction::new(BVC, 2, REL);
+    lookup[0x51] = Instruction::new(EOR, 5, IZY);
+    lookup[0x52] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x53] = Instruction::new_illegal(SRE, 8, IZY);
+    lookup[0x54] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0x55] = Instruction::new(EOR, 4, ZPX);
+    lookup[0x56] = Instruction::new(LSR, 6, ZPX);
+    lookup[0x57] = Instruction::new_illegal(SRE, 6, ZPX);
+    lookup[0x58] = Instruction::new(CLI, 2, IMP);
+    lookup[0x59] = Instruction::new(EOR, 4, ABY);
+    lookup[0x5A] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0x5B] = Instruction::new_illegal(SRE, 7, ABY);
+    lookup[0x5C] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0x5D] = Instruction::new(EOR, 4, ABX);
+    lookup[0x5E] = Instruction::new(LSR, 7, ABX);
+    lookup[0x5F] = Instruction::new_illegal(SRE, 7, ABX);
+
+    /* 0x6n Opcodes */
+    lookup[0x60] = Instruction::new(RTS, 6, IMP);
+    lookup[0x61] = Instruction::new(ADC, 6, IZX);
+    lookup[0x62] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x63] = Instruction::new_illegal(RRA, 8, IZX);
+    lookup[0x64] = Instruction::new_illegal(NOP, 3, ZP0);
+    lookup[0x65] = Instruction::new(ADC, 3, ZP0);
+    lookup[0x66] = Instruction::new(ROR, 5, ZP0);
+    lookup[0x67] = Instruction::new_illegal(RRA, 5, ZP0);
+    lookup[0x68] = Instruction::new(PLA, 4, IMP);
+    lookup[0x69] = Instruction::new(ADC, 2, IMM);
+    lookup[0x6A] = Instruction::new(ROR, 2, IMP);
+    lookup[0x6B] = Instruction::new_illegal(ARR, 2, IMM);
+    lookup[0x6C] = Instruction::new(JMP, 5, IND);
+    lookup[0x6D] = Instruction::new(ADC, 4, ABS);
+    lookup[0x6E] = Instruction::new(ROR, 6, ABS);
+    lookup[0x6F] = Instruction::new_illegal(RRA, 6, ABS);
+
+    /* 0x7n Opcodes */
+    lookup[0x70] = Instruction::new(BVS, 2, REL);
+    lookup[0x71] = Instruction::new(ADC, 5, IZY);
+    lookup[0x72] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x73] = Instruction::new_illegal(RRA, 8, IZY);
+    lookup[0x74] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0x75] = Instruction::new(ADC, 4, ZPX);
+    lookup[0x76] = Instruction::new(ROR, 6, ZPX);
+    lookup[0x77] = Instruction::new_illegal(RRA, 6, ZPX);
+    lookup[0x78] = Instruction::new(SEI, 2, IMP);
+    lookup[0x79] = Instruction::new(ADC, 4, ABY);
+    lookup[0x7A] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0x7B] = Instruction::new_illegal(RRA, 7, ABY);
+    lookup[0x7C] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0x7D] = Instruction::new(ADC, 4, ABX);
+    lookup[0x7E] = Instruction::new(ROR, 7, ABX);
+    lookup[0x7F] = Instruction::new_illegal(RRA, 7, ABX);
+
+    /* 0x8n Opcodes */
+    lookup[0x80] = Instruction::new_illegal(NOP, 2, IMM);
+    lookup[0x81] = Instruction::new(STA, 6, IZX);
+    lookup[0x82] = Instruction::new_illegal(NOP, 2, IMM);
+    lookup[0x83] = Instruction::new_illegal(SAX, 6, IZX);
+    lookup[0x84] = Instruction::new(STY, 3, ZP0);
+    lookup[0x85] = Instruction::new(STA, 3, ZP0);
+    lookup[0x86] = Instruction::new(STX, 3, ZP0);
+    lookup[0x87] = Instruction::new_illegal(SAX, 3, ZP0);
+    lookup[0x88] = Instruction::new(DEY, 2, IMP);
+    lookup[0x89] = Instruction::new_illegal(NOP, 2, IMM);
+    lookup[0x8A] = Instruction::new(TXA, 2, IMP);
+    lookup[0x8B] = Instruction::new_illegal(ANE, 2, IMM);
+    lookup[0x8C] = Instruction::new(STY, 4, ABS);
+    lookup[0x8D] = Instruction::new(STA, 4, ABS);
+    lookup[0x8E] = Instruction::new(STX, 4, ABS);
+    lookup[0x8F] = Instruction::new_illegal(SAX, 4, ABS);
+
+    /* 0x9n Opcodes */
+    lookup[0x90] = Instruction::new(BCC, 2, REL);
+    lookup[0x91] = Instruction::new(STA, 6, IZY);
+    lookup[0x92] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0x93] = Instruction::new_illegal(SHA, 6, IZY);
+    lookup[0x94] = Instruction::new(STY, 4, ZPX);
+    lookup[0x95] = Instruction::new(STA, 4, ZPX);
+    lookup[0x96] = Instruction::new(STX, 4, ZPY);
+    lookup[0x97] = Instruction::new_illegal(SAX, 4, ZPY);
+    lookup[0x98] = Instruction::new(TYA, 2, IMP);
+    lookup[0x99] = Instruction::new(STA, 5, ABY);
+    lookup[0x9A] = Instruction::new(TXS, 2, IMP);
+    lookup[0x9B] = Instruction::new_illegal(TAS, 5, ABY);
+    lookup[0x9C] = Instruction::new_illegal(SHY, 5, ABX);
+    lookup[0x9D] = Instruction::new(STA, 5, ABX);
+    lookup[0x9E] = Instruction::new_illegal(SHX, 5, ABY);
+    lookup[0x9F] = Instruction::new_illegal(SHA, 5, ABY);
+
+    /* 0xAn Opcodes */
+    lookup[0xA0] = Instruction::new(LDY, 2, IMM);
+    lookup[0xA1] = Instruction::new(LDA, 6, IZX);
+    lookup[0xA2] = Instruction::new(LDX, 2, IMM);
+    lookup[0xA3] = Instruction::new_illegal(LAX, 6, IZX);
+    lookup[0xA4] = Instruction::new(LDY, 3, ZP0);
+    lookup[0xA5] = Instruction::new(LDA, 3, ZP0);
+    lookup[0xA6] = Instruction::new(LDX, 3, ZP0);
+    lookup[0xA7] = Instruction::new_illegal(LAX, 3, ZP0);
+    lookup[0xA8] = Instruction::new(TAY, 2, IMP);
+    lookup[0xA9] = Instruction::new(LDA, 2, IMM);
+    lookup[0xAA] = Instruction::new(TAX, 2, IMP);
+    lookup[0xAB] = Instruction::new_illegal(LXA, 2, IMM);
+    lookup[0xAC] = Instruction::new(LDY, 4, ABS);
+    lookup[0xAD] = Instruction::new(LDA, 4, ABS);
+    lookup[0xAE] = Instruction::new(LDX, 4, ABS);
+    lookup[0xAF] = Instruction::new_illegal(LAX, 4, ABS);
+
+    /* 0xBn Opcodes */
+    lookup[0xB0] = Instruction::new(BCS, 2, REL);
+    lookup[0xB1] = Instruction::new(LDA, 5, IZY);
+    lookup[0xB2] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0xB3] = Instruction::new_illegal(LAX, 5, IZY);
+    lookup[0xB4] = Instruction::new(LDY, 4, ZPX);
+    lookup[0xB5] = Instruction::new(LDA, 4, ZPX);
+    lookup[0xB6] = Instruction::new(LDX, 4, ZPY);
+    lookup[0xB7] = Instruction::new_illegal(LAX, 4, ZPY);
+    lookup[0xB8] = Instruction::new(CLV, 2, IMP);
+    lookup[0xB9] = Instruction::new(LDA, 4, ABY);
+    lookup[0xBA] = Instruction::new(TSX, 2, IMP);
+    lookup[0xBB] = Instruction::new_illegal(LAS, 4, ABY);
+    lookup[0xBC] = Instruction::new(LDY, 4, ABX);
+    lookup[0xBD] = Instruction::new(LDA, 4, ABX);
+    lookup[0xBE] = Instruction::new(LDX, 4, ABY);
+    lookup[0xBF] = Instruction::new_illegal(LAX, 4, ABY);
+
+    /* 0xCn Opcodes */
+    lookup[0xC0] = Instruction::new(CPY, 2, IMM);
+    lookup[0xC1] = Instruction::new(CMP, 6, IZX);
+    lookup[0xC2] = Instruction::new_illegal(NOP, 2, IMM);
+    lookup[0xC3] = Instruction::new_illegal(DCP, 8, IZX);
+    lookup[0xC4] = Instruction::new(CPY, 3, ZP0);
+    lookup[0xC5] = Instruction::new(CMP, 3, ZP0);
+    lookup[0xC6] = Instruction::new(DEC, 5, ZP0);
+    lookup[0xC7] = Instruction::new_illegal(DCP, 5, ZP0);
+    lookup[0xC8] = Instruction::new(INY, 2, IMP);
+    lookup[0xC9] = Instruction::new(CMP, 2, IMM);
+    lookup[0xCA] = Instruction::new(DEX, 2, IMP);
+    lookup[0xCB] = Instruction::new_illegal(SBX, 2, IMM);
+    lookup[0xCC] = Instruction::new(CPY, 4, ABS);
+    lookup[0xCD] = Instruction::new(CMP, 4, ABS);
+    lookup[0xCE] = Instruction::new(DEC, 6, ABS);
+    lookup[0xCF] = Instruction::new_illegal(DCP, 6, ABS);
+
+    /* 0xDn Opcodes */
+    lookup[0xD0] = Instruction::new(BNE, 2, REL);
+    lookup[0xD1] = Instruction::new(CMP, 5, IZY);
+    lookup[0xD2] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0xD3] = Instruction::new_illegal(DCP, 8, IZY);
+    lookup[0xD4] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0xD5] = Instruction::new(CMP, 4, ZPX);
+    lookup[0xD6] = Instruction::new(DEC, 6, ZPX);
+    lookup[0xD7] = Instruction::new_illegal(DCP, 6, ZPX);
+    lookup[0xD8] = Instruction::new(CLD, 2, IMP);
+    lookup[0xD9] = Instruction::new(CMP, 4, ABY);
+    lookup[0xDA] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0xDB] = Instruction::new_illegal(DCP, 7, ABY);
+    lookup[0xDC] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0xDD] = Instruction::new(CMP, 4, ABX);
+    lookup[0xDE] = Instruction::new(DEC, 7, ABX);
+    lookup[0xDF] = Instruction::new_illegal(DCP, 7, ABX);
+
+    /* 0xEn Opcodes */
+    lookup[0xE0] = Instruction::new(CPX, 2, IMM);
+    lookup[0xE1] = Instruction::new(SBC, 6, IZX);
+    lookup[0xE2] = Instruction::new_illegal(NOP, 2, IMM);
+    lookup[0xE3] = Instruction::new_illegal(ISB, 8, IZX);
+    lookup[0xE4] = Instruction::new(CPX, 3, ZP0);
+    lookup[0xE5] = Instruction::new(SBC, 3, ZP0);
+    lookup[0xE6] = Instruction::new(INC, 5, ZP0);
+    lookup[0xE7] = Instruction::new_illegal(ISB, 5, ZP0);
+    lookup[0xE8] = Instruction::new(INX, 2, IMP);
+    lookup[0xE9] = Instruction::new(SBC, 2, IMM);
+    lookup[0xEA] = Instruction::new(NOP, 2, IMP);
+    lookup[0xEB] = Instruction::new_illegal(SBC, 2, IMM);
+    lookup[0xEC] = Instruction::new(CPX, 4, ABS);
+    lookup[0xED] = Instruction::new(SBC, 4, ABS);
+    lookup[0xEE] = Instruction::new(INC, 6, ABS);
+    lookup[0xEF] = Instruction::new_illegal(ISB, 6, ABS);
+
+    /* 0xFn Opcodes */
+    lookup[0xF0] = Instruction::new(BEQ, 2, REL);
+    lookup[0xF1] = Instruction::new(SBC, 5, IZY);
+    lookup[0xF2] = Instruction::new_illegal(JAM, 2, IMP);
+    lookup[0xF3] = Instruction::new_illegal(ISB, 8, IZY);
+    lookup[0xF4] = Instruction::new_illegal(NOP, 4, ZPX);
+    lookup[0xF5] = Instruction::new(SBC, 4, ZPX);
+    lookup[0xF6] = Instruction::new(INC, 6, ZPX);
+    lookup[0xF7] = Instruction::new_illegal(ISB, 6, ZPX);
+    lookup[0xF8] = Instruction::new(SED, 2, IMP);
+    lookup[0xF9] = Instruction::new(SBC, 4, ABY);
+    lookup[0xFA] = Instruction::new_illegal(NOP, 2, IMP);
+    lookup[0xFB] = Instruction::new_illegal(ISB, 7, ABY);
+    lookup[0xFC] = Instruction::new_illegal(NOP, 4, ABX);
+    lookup[0xFD] = Instruction::new(SBC, 4, ABX);
+    lookup[0xFE] = Instruction::new(INC, 7, ABX);
+    lookup[0xFF] = Instruction::new_illegal(ISB, 7, ABX);
+
+    lookup
+};
+
+
+
+#[derive(Clone)]
 pub struct CPU {
     /* All members of the CPU struct will be public for now, just to make debugging/testing a bit easier*/
     pub a: u8,
@@ -79,26 +453,60 @@ pub struct CPU {
     |||| |||+- Carry
     |||| ||+-- Zero
     |||| |+--- Interrupt Disable
-    |||| +---- Decimal (Only on the 6502, not the 2A03)
+    |||| +---- Decimal (Only on the 6502, not the 2A03, see CpuVariant)
     |||+------ Break Flag 
     ||+------- (No CPU effect; always pushed as 1)
     |+-------- Overflow
     +--------- Negative
     */
 
-    //pub bus: Weak<Rc<RefCell<BUS>>>,
-
-    /* New pointer system */
-    pub bus: Weak<RefCell<BUS>>,
-    
 
     /* Some helper members */
     pub last_fetched: u8,
     pub absolute_addr: u16,
     pub relative_addr: u16,
     pub opcode: u8,
+    /* Cycles left before the CPU carries on, after a reset it waits for 7 cycles */
     pub cycles: u8,
-    pub lookup: Vec<Instruction>,
+    /* The cycle of the current instruction the next clock() runs, 0 fetches a new opcode */
+    pub step: u8,
+    /* The zero page or absolute pointer of the indirect addressing modes */
+    pub pointer: u16,
+    /* The index register carried into the high byte of absolute_addr */
+    pub page_crossed: bool,
+    /* Set by the branch instructions if their condition is met */
+    pub branch_taken: bool,
+    /* Every cycle since the last reset, the reset itself included */
+    pub total_cycles: u64,
+
+    pub variant: CpuVariant,
+    pub illegal_opcode_mode: IllegalOpcodeMode,
+    /* Set when illegal_opcode_mode is Trap and an unofficial opcode is about to run, pc still
+    *  points at that opcode. The CPU won't do anything until resume_trap() is called */
+    pub trapped: bool,
+    /* Set by resume_trap(), so the opcode we stopped at runs instead of trapping again */
+    skip_trap: bool,
+    /* In Log mode, every address an unofficial opcode ran at and the opcode. The frontend can
+    *  look at it (or clear it) whenever it wants */
+    pub illegal_opcode_log: BTreeMap<u16, u8>,
+    /* Set by the JAM opcodes, only a reset gets the CPU going again */
+    pub jammed: bool,
+
+    /* NMI is edge triggered: nmi_pending is set when nmi_line goes from inactive to active. The
+    *  edge counts for the cycle that just ran, so set_nmi() goes in between two clock() calls */
+    pub nmi_line: bool,
+    pub nmi_pending: bool,
+    /* IRQ is level triggered and shared by everyone, one bit per IRQ_SOURCE_* */
+    pub irq_lines: u8,
+    /* An IRQ is requested and the I flag allows it */
+    pub irq_pending: bool,
+    /* What the last poll saw, see poll_interrupts() */
+    prev_nmi_pending: bool,
+    prev_irq_pending: bool,
+    /* The cycle that just ran doesn't poll, prev_* keep what the poll before saw */
+    skip_poll: bool,
+    /* The BRK sequence is running because of an NMI or IRQ, not because of the instruction */
+    pub hardware_interrupt: bool,
 }
 
 
@@ -111,219 +519,160 @@ const STATUS_FLAG_I: u8 = 0b00000100;
 const STATUS_FLAG_Z: u8 = 0b00000010;
 const STATUS_FLAG_C: u8 = 0b00000001;
 
+/* Everything that can pull the IRQ line, see set_irq() */
+pub const IRQ_SOURCE_APU_FRAME: u8 = 0b00000001;
+pub const IRQ_SOURCE_APU_DMC: u8 = 0b00000010;
+pub const IRQ_SOURCE_MAPPER: u8 = 0b00000100;
+
 
 
 #[allow(dead_code)]
 pub trait ICPU {
-    fn new() -> Rc<RefCell<Self>>
+    fn new() -> Self
     where 
         Self: Sized;
-    fn read(&self, addr: u16) -> u8;
-    fn write(&mut self, addr: u16, data: u8);
-    fn connect_bus(&mut self, bus: &Rc<Rc<RefCell<BUS>>>);
     fn set_flag(&mut self, status_flag: u8);
     fn clear_flag(&mut self, status_flag: u8);
-    fn get_flag(&mut self, status_flag: u8) -> u8;
+    fn get_flag(&self, status_flag: u8) -> u8;
 
     fn fetch(&mut self) -> u8;
-    fn clock(&mut self);
-    fn reset(&mut self);
-    fn irq(&mut self);
-    fn nmi(&mut self);
+    /* The CPU doesn't own or point to a bus, whatever it should read from and write to is handed in */
+    fn clock(&mut self, bus: &mut dyn CpuBus);
+    fn reset(&mut self, bus: &mut dyn CpuBus);
+    /* The interrupt inputs of the CPU, the devices driving them call these whenever their output changes */
+    fn set_nmi(&mut self, active: bool);
+    fn set_irq(&mut self, source: u8, active: bool);
 
     /* Opcode functions */
-    fn ins_adc(&mut self) -> u8;
-    fn ins_and(&mut self) -> u8;
-    fn ins_asl(&mut self) -> u8;
-    fn ins_bcc(&mut self) -> u8;
-    fn ins_bcs(&mut self) -> u8;
-    fn ins_beq(&mut self) -> u8;
-    fn ins_bit(&mut self) -> u8;
-    fn ins_bmi(&mut self) -> u8;
-    fn ins_bne(&mut self) -> u8;
-    fn ins_bpl(&mut self) -> u8;
-    fn ins_brk(&mut self) -> u8;
-    fn ins_bvc(&mut self) -> u8;
-    fn ins_bvs(&mut self) -> u8;
-    fn ins_clc(&mut self) -> u8;
-    fn ins_cld(&mut self) -> u8;
-    fn ins_cli(&mut self) -> u8;
-    fn ins_clv(&mut self) -> u8;
-    fn ins_cmp(&mut self) -> u8;
-    fn ins_cpx(&mut self) -> u8;
-    fn ins_cpy(&mut self) -> u8;
-    fn ins_dec(&mut self) -> u8;
-    fn ins_dex(&mut self) -> u8;
-    fn ins_dey(&mut self) -> u8;
-    fn ins_eor(&mut self) -> u8;
-    fn ins_inc(&mut self) -> u8;
-    fn ins_inx(&mut self) -> u8;
-    fn ins_iny(&mut self) -> u8;
-    fn ins_jmp(&mut self) -> u8;
-    fn ins_jsr(&mut self) -> u8;
-    fn ins_lda(&mut self) -> u8;
-    fn ins_ldx(&mut self) -> u8;
-    fn ins_ldy(&mut self) -> u8;
-    fn ins_lsr(&mut self) -> u8;
-    fn ins_nop(&mut self) -> u8;
-    fn ins_ora(&mut self) -> u8;
-    fn ins_pha(&mut self) -> u8;
-    fn ins_php(&mut self) -> u8;
-    fn ins_pla(&mut self) -> u8;
-    fn ins_plp(&mut self) -> u8;
-    fn ins_rol(&mut self) -> u8;
-    fn ins_ror(&mut self) -> u8;
-    fn ins_rti(&mut self) -> u8;
-    fn ins_rts(&mut self) -> u8;
-    fn ins_sbc(&mut self) -> u8;
-    fn ins_sec(&mut self) -> u8;
-    fn ins_sed(&mut self) -> u8;
-    fn ins_sei(&mut self) -> u8;
-    fn ins_sta(&mut self) -> u8;
-    fn ins_stx(&mut self) -> u8;
-    fn ins_sty(&mut self) -> u8;
-    fn ins_tax(&mut self) -> u8;
-    fn ins_tay(&mut self) -> u8;
-    fn ins_tsx(&mut self) -> u8;
-    fn ins_txa(&mut self) -> u8;
-    fn ins_txs(&mut self) -> u8;
-    fn ins_tya(&mut self) -> u8;
-
-
-
-    // Illegal opcode
-    fn ins_xxx(&mut self) -> u8;
+    fn ins_adc(&mut self);
+    fn ins_and(&mut self);
+    fn ins_asl(&mut self, bus: &mut dyn CpuBus);
+    fn ins_bcc(&mut self);
+    fn ins_bcs(&mut self);
+    fn ins_beq(&mut self);
+    fn ins_bit(&mut self);
+    fn ins_bmi(&mut self);
+    fn ins_bne(&mut self);
+    fn ins_bpl(&mut self);
+    fn ins_brk(&mut self, bus: &mut dyn CpuBus);
+    fn ins_bvc(&mut self);
+    fn ins_bvs(&mut self);
+    fn ins_clc(&mut self);
+    fn ins_cld(&mut self);
+    fn ins_cli(&mut self);
+    fn ins_clv(&mut self);
+    fn ins_cmp(&mut self);
+    fn ins_cpx(&mut self);
+    fn ins_cpy(&mut self);
+    fn ins_dec(&mut self, bus: &mut dyn CpuBus);
+    fn ins_dex(&mut self);
+    fn ins_dey(&mut self);
+    fn ins_eor(&mut self);
+    fn ins_inc(&mut self, bus: &mut dyn CpuBus);
+    fn ins_inx(&mut self);
+    fn ins_iny(&mut self);
+    fn ins_jmp(&mut self);
+    fn ins_jsr(&mut self);
+    fn ins_lda(&mut self);
+    fn ins_ldx(&mut self);
+    fn ins_ldy(&mut self);
+    fn ins_lsr(&mut self, bus: &mut dyn CpuBus);
+    fn ins_nop(&mut self);
+    fn ins_ora(&mut self);
+    fn ins_pha(&mut self, bus: &mut dyn CpuBus);
+    fn ins_php(&mut self, bus: &mut dyn CpuBus);
+    fn ins_pla(&mut self, bus: &mut dyn CpuBus);
+    fn ins_plp(&mut self, bus: &mut dyn CpuBus);
+    fn ins_rol(&mut self, bus: &mut dyn CpuBus);
+    fn ins_ror(&mut self, bus: &mut dyn CpuBus);
+    fn ins_rti(&mut self, bus: &mut dyn CpuBus);
+    fn ins_rts(&mut self);
+    fn ins_sbc(&mut self);
+    fn ins_sec(&mut self);
+    fn ins_sed(&mut self);
+    fn ins_sei(&mut self);
+    fn ins_sta(&mut self, bus: &mut dyn CpuBus);
+    fn ins_stx(&mut self, bus: &mut dyn CpuBus);
+    fn ins_sty(&mut self, bus: &mut dyn CpuBus);
+    fn ins_tax(&mut self);
+    fn ins_tay(&mut self);
+    fn ins_tsx(&mut self);
+    fn ins_txa(&mut self);
+    fn ins_txs(&mut self);
+    fn ins_tya(&mut self);
+
+    /* Unofficial opcodes */
+    fn ins_alr(&mut self);
+    fn ins_anc(&mut self);
+    fn ins_ane(&mut self);
+    fn ins_arr(&mut self);
+    fn ins_dcp(&mut self, bus: &mut dyn CpuBus);
+    fn ins_isb(&mut self, bus: &mut dyn CpuBus);
+    fn ins_jam(&mut self);
+    fn ins_las(&mut self);
+    fn ins_lax(&mut self);
+    fn ins_lxa(&mut self);
+    fn ins_rla(&mut self, bus: &mut dyn CpuBus);
+    fn ins_rra(&mut self, bus: &mut dyn CpuBus);
+    fn ins_sax(&mut self, bus: &mut dyn CpuBus);
+    fn ins_sbx(&mut self);
+    fn ins_sha(&mut self, bus: &mut dyn CpuBus);
+    fn ins_shx(&mut self, bus: &mut dyn CpuBus);
+    fn ins_shy(&mut self, bus: &mut dyn CpuBus);
+    fn ins_slo(&mut self, bus: &mut dyn CpuBus);
+    fn ins_sre(&mut self, bus: &mut dyn CpuBus);
+    fn ins_tas(&mut self, bus: &mut dyn CpuBus);
 
     /* Addressing modes */
-    fn addrmode_imp(&mut self) -> u8;
-    fn addrmode_zp0(&mut self) -> u8;
-    fn addrmode_zpy(&mut self) -> u8;
-    fn addrmode_abs(&mut self) -> u8;
-    fn addrmode_aby(&mut self) -> u8;
-    fn addrmode_izx(&mut self) -> u8;
-    fn addrmode_imm(&mut self) -> u8;
-    fn addrmode_zpx(&mut self) -> u8;
-    fn addrmode_rel(&mut self) -> u8;
-    fn addrmode_abx(&mut self) -> u8;
-    fn addrmode_ind(&mut self) -> u8;
-    fn addrmode_izy(&mut self) -> u8;
-
-    // Illegal addressing mode
-    fn addrmode_xxx(&mut self) -> u8;
+    fn addrmode_imp(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_zp0(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_zpy(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_abs(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_aby(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_izx(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_imm(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_zpx(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_rel(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_abx(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_ind(&mut self, bus: &mut dyn CpuBus) -> bool;
+    fn addrmode_izy(&mut self, bus: &mut dyn CpuBus) -> bool;
 }
 
+
 impl ICPU for CPU {
-    fn new() -> Rc<RefCell<Self>> {
-        let mut lookup: Vec<Instruction> = (0..256).map(|_| Instruction::new_empty()).collect();
-        /* Setting up The lookup table... I hate my life */
-        /* Using This as my template: https://github.com/OneLoneCoder/olcNES/blob/master/Part%232%20-%20CPU/olc6502.cpp */
-        /* 0x0n Opcodes */
-        lookup[0x00] = Instruction::new("BRK", 2, |cpu: &mut dyn ICPU| {cpu.ins_brk()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x01] = Instruction::new("ORA", 6, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_izx());
-        lookup[0x02] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x03] = Instruction::new("???", 8, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x04] = Instruction::new("???", 3, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x05] = Instruction::new("ORA", 3, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_zp0());
-        lookup[0x06] = Instruction::new("ASL", 5, |cpu: &mut dyn ICPU| {cpu.ins_asl()}, |cpu: &mut dyn ICPU| cpu.addrmode_zp0());
-        lookup[0x07] = Instruction::new("???", 5, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x08] = Instruction::new("PHP", 3, |cpu: &mut dyn ICPU| {cpu.ins_php()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x09] = Instruction::new("ORA", 2, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_imm());
-        lookup[0x0A] = Instruction::new("ASL", 2, |cpu: &mut dyn ICPU| {cpu.ins_asl()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x0B] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x0C] = Instruction::new("???", 4, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x0D] = Instruction::new("ORA", 4, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x0E] = Instruction::new("ASL", 6, |cpu: &mut dyn ICPU| {cpu.ins_asl()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x0F] = Instruction::new("???", 6, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-
-        /* 0x1n Opcodes */
-        lookup[0x10] = Instruction::new("BPL", 2, |cpu: &mut dyn ICPU| {cpu.ins_bpl()}, |cpu: &mut dyn ICPU| cpu.addrmode_rel());
-        lookup[0x11] = Instruction::new("ORA", 5, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_izy());
-        lookup[0x12] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x13] = Instruction::new("???", 8, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x14] = Instruction::new("???", 4, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x15] = Instruction::new("ORA", 4, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_zpx());
-        lookup[0x16] = Instruction::new("ASL", 6, |cpu: &mut dyn ICPU| {cpu.ins_asl()}, |cpu: &mut dyn ICPU| cpu.addrmode_zpx());
-        lookup[0x17] = Instruction::new("???", 6, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x18] = Instruction::new("CLC", 2, |cpu: &mut dyn ICPU| {cpu.ins_clc()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x19] = Instruction::new("ORA", 4, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_aby());
-        lookup[0x1A] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x1B] = Instruction::new("???", 7, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x1C] = Instruction::new("???", 4, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x1D] = Instruction::new("ORA", 4, |cpu: &mut dyn ICPU| {cpu.ins_ora()}, |cpu: &mut dyn ICPU| cpu.addrmode_abx());
-        lookup[0x1E] = Instruction::new("ASL", 7, |cpu: &mut dyn ICPU| {cpu.ins_asl()}, |cpu: &mut dyn ICPU| cpu.addrmode_abx());
-        lookup[0x1F] = Instruction::new("???", 7, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-
-        
-        /* 0x2n Opcodes */
-        lookup[0x20] = Instruction::new("JSR", 6, |cpu: &mut dyn ICPU| {cpu.ins_jsr()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x21] = Instruction::new("AND", 6, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_izx());
-        lookup[0x22] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x23] = Instruction::new("???", 8, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x24] = Instruction::new("BIT", 3, |cpu: &mut dyn ICPU| {cpu.ins_bit()}, |cpu: &mut dyn ICPU| cpu.addrmode_zp0());
-        lookup[0x25] = Instruction::new("AND", 3, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_zp0());
-        lookup[0x26] = Instruction::new("ROL", 5, |cpu: &mut dyn ICPU| {cpu.ins_rol()}, |cpu: &mut dyn ICPU| cpu.addrmode_zp0());
-        lookup[0x27] = Instruction::new("???", 5, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x28] = Instruction::new("PLP", 4, |cpu: &mut dyn ICPU| {cpu.ins_plp()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x29] = Instruction::new("AND", 2, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_imm());
-        lookup[0x2A] = Instruction::new("ROL", 2, |cpu: &mut dyn ICPU| {cpu.ins_rol()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x2B] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x2C] = Instruction::new("BIT", 4, |cpu: &mut dyn ICPU| {cpu.ins_bit()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x2D] = Instruction::new("AND", 4, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x2E] = Instruction::new("ROL", 6, |cpu: &mut dyn ICPU| {cpu.ins_rol()}, |cpu: &mut dyn ICPU| cpu.addrmode_abs());
-        lookup[0x2F] = Instruction::new("???", 6, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-    
-        /* 0x3n Opcodes */
-        lookup[0x30] = Instruction::new("BMI", 2, |cpu: &mut dyn ICPU| {cpu.ins_bmi()}, |cpu: &mut dyn ICPU| cpu.addrmode_rel());
-        lookup[0x31] = Instruction::new("AND", 5, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_izy());
-        lookup[0x32] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x33] = Instruction::new("???", 8, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x34] = Instruction::new("???", 4, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x35] = Instruction::new("AND", 4, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_zpx());
-        lookup[0x36] = Instruction::new("ROL", 6, |cpu: &mut dyn ICPU| {cpu.ins_rol()}, |cpu: &mut dyn ICPU| cpu.addrmode_zpx());
-        lookup[0x37] = Instruction::new("???", 6, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x38] = Instruction::new("SEC", 2, |cpu: &mut dyn ICPU| {cpu.ins_sec()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x39] = Instruction::new("AND", 4, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_aby());
-        lookup[0x3A] = Instruction::new("???", 2, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x3B] = Instruction::new("???", 7, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x3C] = Instruction::new("NOP", 4, |cpu: &mut dyn ICPU| {cpu.ins_nop()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-        lookup[0x3D] = Instruction::new("AND", 4, |cpu: &mut dyn ICPU| {cpu.ins_and()}, |cpu: &mut dyn ICPU| cpu.addrmode_abx());
-        lookup[0x3E] = Instruction::new("ROL", 7, |cpu: &mut dyn ICPU| {cpu.ins_rol()}, |cpu: &mut dyn ICPU| cpu.addrmode_abx());
-        lookup[0x3F] = Instruction::new("???", 7, |cpu: &mut dyn ICPU| {cpu.ins_xxx()}, |cpu: &mut dyn ICPU| cpu.addrmode_imp());
-
-
-        Rc::new(RefCell::new(CPU {
+    fn new() -> Self {
+        CPU {
             a: 0,
             x: 0,
             y: 0,
             pc: 0,
             sp: 0,
             status_flags: 0,
-            bus: Weak::new(),
             last_fetched: 0,
             absolute_addr: 0,
             relative_addr: 0,
             opcode: 0,
             cycles: 0,
-            lookup,
-        }))
-    }
-
-    fn read(&self, addr: u16) -> u8 {
-        if let Some(bus) = self.bus.upgrade() {
-            let val = (*bus).borrow().read(addr, true);
-            return val;
+            step: 0,
+            pointer: 0,
+            page_crossed: false,
+            branch_taken: false,
+            total_cycles: 0,
+            variant: CpuVariant::Ricoh2A03,
+            illegal_opcode_mode: IllegalOpcodeMode::Execute,
+            trapped: false,
+            skip_trap: false,
+            illegal_opcode_log: BTreeMap::new(),
+            jammed: false,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_lines: 0,
+            irq_pending: false,
+            prev_nmi_pending: false,
+            prev_irq_pending: false,
+            skip_poll: false,
+            hardware_interrupt: false,
         }
-        return 0; // Default return if bus is not available
-    }
-    fn write(&mut self, addr: u16, data: u8) {
-        if let Some(bus) = self.bus.upgrade() {
-            bus.borrow_mut().write(addr, data);
-        }
-    }
-
-    /* This function is called to enter the BUS into the CPU struct */
-    fn connect_bus(&mut self, bus: &Rc<Rc<RefCell<BUS>>>) { 
-        self.bus = Rc::downgrade(bus); 
     }
 
     fn set_flag(&mut self, status_flag: u8){
@@ -332,7 +681,7 @@ impl ICPU for CPU {
     fn clear_flag(&mut self, status_flag: u8){
         self.status_flags &= !status_flag;
     }
-    fn get_flag(&mut self, status_flag: u8) -> u8{
+    fn get_flag(&self, status_flag: u8) -> u8{
         if self.status_flags & status_flag > 0{
              1
         }else {
@@ -340,60 +689,87 @@ impl ICPU for CPU {
         }
     }
     fn fetch(&mut self) -> u8{
-        let addrmode_fn = self.lookup[self.opcode as usize].addrmode_function.clone();
-    
-        if addrmode_fn(self) != self.addrmode_imp() {
-            self.last_fetched = self.read(self.absolute_addr);
-        }
+        /* The operand was already read on its own cycle (the accumulator for implied instructions) */
         self.last_fetched
     }
-    fn clock(&mut self) {
-        /* I just want to mention how incredibly grateful I am that AI exists, I have spent over an
-        * hour on this and ChatGPT just cooked it up in 5 mins */
-        if self.cycles == 0 {
-            println!("Current pc: {}", self.pc);
-            // Fetch the opcode from memory
-            let instruction = &self.lookup[self.opcode as usize];
-            self.opcode = self.read(self.pc);
-            // self.opcode = r_val;
-            self.pc += 1;
-
-            // Use a new block to limit the scope of the immutable borrow.
-            let (addrmode_fn, opcode_fn, cycles) = {
-                // Borrow the instruction immutably.
-                // let instruction = &self.lookup[self.opcode as usize];
-                // Extract both functions via cloning the smart pointer, and save cycles.
-                (
-                    Rc::clone(&instruction.addrmode_function),
-                    Rc::clone(&instruction.opcode_function),
-                    instruction.cycles,
-                )
-            }; // The borrow of `instruction` ends here.
-
-            // Set the cycles for this instruction.
-            self.cycles = cycles;
-
-            // Call the addressing mode function first (if that's your desired order)
-            let additional_cycle1 = addrmode_fn(self);
+    fn clock(&mut self, bus: &mut dyn CpuBus) {
+        self.total_cycles += 1;
 
-            // Now call the opcode function.
-            let additional_cycle2 = opcode_fn(self);
+        /* A jammed or trapped CPU doesn't do anything anymore */
+        if self.jammed || self.trapped {
+            return;
+        }
 
-            self.cycles += (additional_cycle1 as u8) & (additional_cycle2 as u8);
+        if self.cycles > 0 {
+            self.cycles -= 1;
+            return;
         }
 
-        self.cycles = self.cycles.wrapping_sub(1);
+        let done = if self.step == 0 {
+            /* The first instruction of an interrupt handler always runs before the next interrupt,
+            *  BRK and the interrupts are the only ones that leave $00 in opcode */
+            if (self.prev_nmi_pending || self.prev_irq_pending) && self.opcode != 0x00 {
+                /* The opcode is fetched, but thrown away. BRK is run instead, without moving pc */
+                bus.read(self.pc);
+                self.opcode = 0x00;
+                self.hardware_interrupt = true;
+            } else {
+                // Fetch the opcode from memory
+                self.opcode = bus.read(self.pc);
+
+                if LOOKUP[self.opcode as usize].illegal {
+                    match self.illegal_opcode_mode {
+                        IllegalOpcodeMode::Execute => {},
+                        IllegalOpcodeMode::Trap if self.skip_trap => {
+                            self.skip_trap = false;
+                        },
+                        IllegalOpcodeMode::Trap => {
+                            self.trapped = true;
+                            return;
+                        },
+                        IllegalOpcodeMode::Log => {
+                            self.illegal_opcode_log.insert(self.pc, self.opcode);
+                        },
+                    }
+                }
+
+                self.pc = self.pc.wrapping_add(1);
+            }
+            false
+        } else {
+            /* The addressing mode knows what to do on this cycle */
+            match LOOKUP[self.opcode as usize].addrmode {
+                AddrMode::IMP => self.addrmode_imp(bus),
+                AddrMode::IMM => self.addrmode_imm(bus),
+                AddrMode::ZP0 => self.addrmode_zp0(bus),
+                AddrMode::ZPX => self.addrmode_zpx(bus),
+                AddrMode::ZPY => self.addrmode_zpy(bus),
+                AddrMode::REL => self.addrmode_rel(bus),
+                AddrMode::ABS => self.addrmode_abs(bus),
+                AddrMode::ABX => self.addrmode_abx(bus),
+                AddrMode::ABY => self.addrmode_aby(bus),
+                AddrMode::IND => self.addrmode_ind(bus),
+                AddrMode::IZX => self.addrmode_izx(bus),
+                AddrMode::IZY => self.addrmode_izy(bus),
+            }
+        };
+
+        /* The unused flag is always set */
+        self.set_flag(STATUS_FLAG_1);
+
+        self.poll_interrupts();
 
+        self.step = if done { 0 } else { self.step + 1 };
     }
-    fn reset(&mut self){
+    fn reset(&mut self, bus: &mut dyn CpuBus){
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.sp = 0xFD;
-        self.status_flags = 0x00 | STATUS_FLAG_1;
+        self.status_flags = STATUS_FLAG_1 | STATUS_FLAG_I;
         self.absolute_addr = 0xFFFC;
-        let lo: u16 = self.read(self.absolute_addr + 0) as u16;
-        let hi: u16 = self.read(self.absolute_addr + 1) as u16;
+        let lo: u16 = bus.read(self.absolute_addr) as u16;
+        let hi: u16 = bus.read(self.absolute_addr + 1) as u16;
 
         self.pc = (hi << 8) | lo;
 
@@ -401,425 +777,1250 @@ impl ICPU for CPU {
         self.absolute_addr = 0x0000;
         self.last_fetched = 0x00;
 
-        self.cycles = 8;
+        /* Resetting takes as long as an interrupt: 7 cycles */
+        self.cycles = 7;
+        self.step = 0;
+        self.total_cycles = 0;
+        self.nmi_pending = false;
+        self.prev_nmi_pending = false;
+        self.irq_pending = false;
+        self.prev_irq_pending = false;
+        self.skip_poll = false;
+        self.hardware_interrupt = false;
+        self.trapped = false;
+        self.skip_trap = false;
+        self.jammed = false;
     }
-    fn irq(&mut self){
-        if self.get_flag(STATUS_FLAG_I) == 0 {
-            self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-            self.sp += 1;
-            self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-            self.sp += 1;
-
-            self.clear_flag(STATUS_FLAG_B);
-            self.set_flag(STATUS_FLAG_1);
-            self.set_flag(STATUS_FLAG_I);
-            self.write(0x0100 + self.sp as u16, self.status_flags);
-            self.sp -= 1;
-
-            self.absolute_addr = 0xFFFE;
-
-            let lo: u16 = self.read(self.absolute_addr + 0) as u16;
-            let hi: u16 = self.read(self.absolute_addr + 1) as u16;
-
-            self.pc = (hi << 8) | lo;
-
-            self.cycles = 7;
-
+    fn set_nmi(&mut self, active: bool){
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = active;
+    }
+    fn set_irq(&mut self, source: u8, active: bool){
+        if active {
+            self.irq_lines |= source;
+        } else {
+            self.irq_lines &= !source;
         }
     }
-    fn nmi(&mut self){
-        self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-        self.sp += 1;
-        self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-        self.sp += 1;
 
+
+    /* Opcodes */
+    fn ins_adc(&mut self){
+        let value = self.fetch();
+        self.adc(value);
+    }
+    fn ins_and(&mut self){
+        self.a &= self.fetch();
+        self.set_zn(self.a);
+    }
+    fn ins_asl(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.shift_left(value);
+        self.set_zn(temp);
+        self.store_result(bus, temp);
+    }
+    fn ins_bcc(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_C) == 0);
+    }
+    fn ins_bcs(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_C) == 1);
+    }
+    fn ins_beq(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_Z) == 1);
+    }
+    fn ins_bit(&mut self){
+        self.fetch();
+        self.set_flag_to(STATUS_FLAG_Z, (self.a & self.last_fetched) == 0x00);
+        self.set_flag_to(STATUS_FLAG_N, (self.last_fetched & STATUS_FLAG_N) != 0);
+        self.set_flag_to(STATUS_FLAG_V, (self.last_fetched & STATUS_FLAG_V) != 0);
+    }
+    fn ins_bmi(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_N) == 1);
+    }
+    fn ins_bne(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_Z) == 0);
+    }
+    fn ins_bpl(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_N) == 0);
+    }
+    fn ins_brk(&mut self, bus: &mut dyn CpuBus){
+        /* pc is already pushed by brk_cycle(), the vector is read after this.
+        *  The B flag only exists on the stack, it is never set in the status register itself */
+        self.push(bus, self.status_flags | STATUS_FLAG_B | STATUS_FLAG_1);
+        self.set_flag(STATUS_FLAG_I);
+    }
+    fn ins_bvc(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_V) == 0);
+    }
+    fn ins_bvs(&mut self){
+        self.branch(self.get_flag(STATUS_FLAG_V) == 1);
+    }
+    fn ins_clc(&mut self){
+        self.clear_flag(STATUS_FLAG_C);
+    }
+    fn ins_cld(&mut self){
+        self.clear_flag(STATUS_FLAG_D);
+    }
+    fn ins_cli(&mut self){
+        self.clear_flag(STATUS_FLAG_I);
+    }
+    fn ins_clv(&mut self){
+        self.clear_flag(STATUS_FLAG_V);
+    }
+    fn ins_cmp(&mut self){
+        self.compare(self.a);
+    }
+    fn ins_cpx(&mut self){
+        self.compare(self.x);
+    }
+    fn ins_cpy(&mut self){
+        self.compare(self.y);
+    }
+    fn ins_dec(&mut self, bus: &mut dyn CpuBus){
+        let temp = self.fetch().wrapping_sub(1);
+        bus.write(self.absolute_addr, temp);
+        self.set_zn(temp);
+    }
+    fn ins_dex(&mut self){
+        self.x = self.x.wrapping_sub(1);
+        self.set_zn(self.x);
+    }
+    fn ins_dey(&mut self){
+        self.y = self.y.wrapping_sub(1);
+        self.set_zn(self.y);
+    }
+    fn ins_eor(&mut self){
+        self.a ^= self.fetch();
+        self.set_zn(self.a);
+    }
+    fn ins_inc(&mut self, bus: &mut dyn CpuBus){
+        let temp = self.fetch().wrapping_add(1);
+        bus.write(self.absolute_addr, temp);
+        self.set_zn(temp);
+    }
+    fn ins_inx(&mut self){
+        self.x = self.x.wrapping_add(1);
+        self.set_zn(self.x);
+    }
+    fn ins_iny(&mut self){
+        self.y = self.y.wrapping_add(1);
+        self.set_zn(self.y);
+    }
+    fn ins_jmp(&mut self){
+        self.pc = self.absolute_addr;
+    }
+    fn ins_jsr(&mut self){
+        /* The return address is already on the stack, see jsr_cycle() */
+        self.pc = self.absolute_addr;
+    }
+    fn ins_lda(&mut self){
+        self.a = self.fetch();
+        self.set_zn(self.a);
+    }
+    fn ins_ldx(&mut self){
+        self.x = self.fetch();
+        self.set_zn(self.x);
+    }
+    fn ins_ldy(&mut self){
+        self.y = self.fetch();
+        self.set_zn(self.y);
+    }
+    fn ins_lsr(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.shift_right(value);
+        self.set_zn(temp);
+        self.store_result(bus, temp);
+    }
+    fn ins_nop(&mut self){
+        /* The unofficial NOPs still read their operand like any other read instruction does */
+    }
+    fn ins_ora(&mut self){
+        self.a |= self.fetch();
+        self.set_zn(self.a);
+    }
+    fn ins_pha(&mut self, bus: &mut dyn CpuBus){
+        self.push(bus, self.a);
+    }
+    fn ins_php(&mut self, bus: &mut dyn CpuBus){
+        /* PHP always pushes the B flag as 1 */
+        self.push(bus, self.status_flags | STATUS_FLAG_B | STATUS_FLAG_1);
+    }
+    fn ins_pla(&mut self, bus: &mut dyn CpuBus){
+        self.a = self.pull(bus);
+        self.set_zn(self.a);
+    }
+    fn ins_plp(&mut self, bus: &mut dyn CpuBus){
+        self.status_flags = self.pull(bus);
+        self.clear_flag(STATUS_FLAG_B);
+        self.set_flag(STATUS_FLAG_1);
+    }
+    fn ins_rol(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.rotate_left(value);
+        self.set_zn(temp);
+        self.store_result(bus, temp);
+    }
+    fn ins_ror(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.rotate_right(value);
+        self.set_zn(temp);
+        self.store_result(bus, temp);
+    }
+    fn ins_rti(&mut self, bus: &mut dyn CpuBus){
+        /* Only the status, pc is pulled by rti_cycle() afterwards */
+        self.status_flags = self.pull(bus);
         self.clear_flag(STATUS_FLAG_B);
         self.set_flag(STATUS_FLAG_1);
+    }
+    fn ins_rts(&mut self){
+        /* rts_cycle() pulled the address of the last byte of the JSR */
+        self.pc = self.pc.wrapping_add(1);
+    }
+    fn ins_sbc(&mut self){
+        let value = self.fetch();
+        self.sbc(value);
+    }
+    fn ins_sec(&mut self){
+        self.set_flag(STATUS_FLAG_C);
+    }
+    fn ins_sed(&mut self){
+        self.set_flag(STATUS_FLAG_D);
+    }
+    fn ins_sei(&mut self){
         self.set_flag(STATUS_FLAG_I);
-        self.write(0x0100 + self.sp as u16, self.status_flags);
-        self.sp -= 1;
-
-        self.absolute_addr = 0xFFFA;
-
-        let lo: u16 = self.read(self.absolute_addr + 0) as u16;
-        let hi: u16 = self.read(self.absolute_addr + 1) as u16;
-
-        self.pc = (hi << 8) | lo;
+    }
+    fn ins_sta(&mut self, bus: &mut dyn CpuBus){
+        bus.write(self.absolute_addr, self.a);
+    }
+    fn ins_stx(&mut self, bus: &mut dyn CpuBus){
+        bus.write(self.absolute_addr, self.x);
+    }
+    fn ins_sty(&mut self, bus: &mut dyn CpuBus){
+        bus.write(self.absolute_addr, self.y);
+    }
+    fn ins_tax(&mut self){
+        self.x = self.a;
+        self.set_zn(self.x);
+    }
+    fn ins_tay(&mut self){
+        self.y = self.a;
+        self.set_zn(self.y);
+    }
+    fn ins_tsx(&mut self){
+        self.x = self.sp;
+        self.set_zn(self.x);
+    }
+    fn ins_txa(&mut self){
+        self.a = self.x;
+        self.set_zn(self.a);
+    }
+    fn ins_txs(&mut self){
+        /* The only transfer that doesn't touch any flags */
+        self.sp = self.x;
+    }
+    fn ins_tya(&mut self){
+        self.a = self.y;
+        self.set_zn(self.a);
+    }
 
-        self.cycles = 8;
+    /* Unofficial opcodes, see https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+    *  and http://www.oxyron.de/html/opcodes02.html for what they do */
+    fn ins_alr(&mut self){
+        self.a &= self.fetch();
+        self.a = self.shift_right(self.a);
+        self.set_zn(self.a);
+    }
+    fn ins_anc(&mut self){
+        self.a &= self.fetch();
+        self.set_zn(self.a);
+        self.set_flag_to(STATUS_FLAG_C, (self.a & 0x80) != 0);
+    }
+    fn ins_ane(&mut self){
+        /* Unstable on real hardware, 0xEE is the "magic" constant most 2A03s show */
+        self.a = (self.a | 0xEE) & self.x & self.fetch();
+        self.set_zn(self.a);
+    }
+    fn ins_arr(&mut self){
+        self.a &= self.fetch();
+        self.a = (self.get_flag(STATUS_FLAG_C) << 7) | (self.a >> 1);
+        self.set_zn(self.a);
+        self.set_flag_to(STATUS_FLAG_C, (self.a & 0x40) != 0);
+        self.set_flag_to(STATUS_FLAG_V, ((self.a >> 6) ^ (self.a >> 5)) & 0x01 != 0);
+    }
+    fn ins_dcp(&mut self, bus: &mut dyn CpuBus){
+        let temp = self.fetch().wrapping_sub(1);
+        bus.write(self.absolute_addr, temp);
+        self.set_flag_to(STATUS_FLAG_C, self.a >= temp);
+        self.set_zn(self.a.wrapping_sub(temp));
+    }
+    fn ins_isb(&mut self, bus: &mut dyn CpuBus){
+        let temp = self.fetch().wrapping_add(1);
+        bus.write(self.absolute_addr, temp);
+        self.sbc(temp);
+    }
+    fn ins_jam(&mut self){
+        /* The CPU locks up for good, leave pc on the opcode so it's obvious where it happened */
+        self.pc = self.pc.wrapping_sub(1);
+        self.jammed = true;
+    }
+    fn ins_las(&mut self){
+        let temp = self.fetch() & self.sp;
+        self.a = temp;
+        self.x = temp;
+        self.sp = temp;
+        self.set_zn(temp);
+    }
+    fn ins_lax(&mut self){
+        self.a = self.fetch();
+        self.x = self.a;
+        self.set_zn(self.a);
+    }
+    fn ins_lxa(&mut self){
+        /* Just as unstable as ANE */
+        self.a = (self.a | 0xEE) & self.fetch();
+        self.x = self.a;
+        self.set_zn(self.a);
+    }
+    fn ins_rla(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.rotate_left(value);
+        bus.write(self.absolute_addr, temp);
+        self.a &= temp;
+        self.set_zn(self.a);
+    }
+    fn ins_rra(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.rotate_right(value);
+        bus.write(self.absolute_addr, temp);
+        self.adc(temp);
+    }
+    fn ins_sax(&mut self, bus: &mut dyn CpuBus){
+        bus.write(self.absolute_addr, self.a & self.x);
+    }
+    fn ins_sbx(&mut self){
+        let value = self.fetch();
+        let temp = self.a & self.x;
+        self.set_flag_to(STATUS_FLAG_C, temp >= value);
+        self.x = temp.wrapping_sub(value);
+        self.set_zn(self.x);
+    }
+    fn ins_sha(&mut self, bus: &mut dyn CpuBus){
+        self.store_high_and(bus, self.a & self.x, self.y);
+    }
+    fn ins_shx(&mut self, bus: &mut dyn CpuBus){
+        self.store_high_and(bus, self.x, self.y);
+    }
+    fn ins_shy(&mut self, bus: &mut dyn CpuBus){
+        self.store_high_and(bus, self.y, self.x);
+    }
+    fn ins_slo(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.shift_left(value);
+        bus.write(self.absolute_addr, temp);
+        self.a |= temp;
+        self.set_zn(self.a);
+    }
+    fn ins_sre(&mut self, bus: &mut dyn CpuBus){
+        let value = self.fetch();
+        let temp = self.shift_right(value);
+        bus.write(self.absolute_addr, temp);
+        self.a ^= temp;
+        self.set_zn(self.a);
+    }
+    fn ins_tas(&mut self, bus: &mut dyn CpuBus){
+        self.sp = self.a & self.x;
+        self.store_high_and(bus, self.sp, self.y);
     }
 
-    /* Opcodes */
-    fn ins_adc(&mut self) -> u8{
-        self.fetch();
-        let temp: u16 = self.a as u16 + self.last_fetched as u16 + self.get_flag(STATUS_FLAG_C) as u16;
-        if temp > 255{self.set_flag(STATUS_FLAG_C);}
-        if (temp & 0x00FF) == 0{self.set_flag(STATUS_FLAG_Z);}
-        if (temp & 0x80) != 0 {self.set_flag(STATUS_FLAG_N);}
-        /* If you want to know where the fuck this comes from, maybe watch this: https://youtu.be/8XmxKPJDGU0?t=2819 */
-	      // SetFlag(V, (~((uint16_t)a ^ (uint16_t)fetched) & ((uint16_t)a ^ (uint16_t)temp)) & 0x0080);
-        if (!((self.a as u16) ^ (self.last_fetched as u16)) & ((self.a as u16) ^ (temp as u16) & 0x0080)) != 0{
-            self.set_flag(STATUS_FLAG_V);
+    /* Addressing modes
+    *  These run the instruction one cycle at a time: every call is cycle number self.step of the
+    *  instruction (the opcode fetch was cycle 0) and does exactly one read or write on the bus,
+    *  just like the real CPU. They return true on the last cycle of the instruction.
+    *  The cycle by cycle breakdown of every mode is in https://www.nesdev.org/6502_cpu.txt */
+
+    /* Implied instructions read the byte after the opcode and throw it away. The stack
+    *  instructions are implied too, but they take a few more cycles */
+    fn addrmode_imp(&mut self, bus: &mut dyn CpuBus) -> bool {
+        match self.opcode {
+            /* BRK */ 0x00 => self.brk_cycle(bus),
+            /* RTI */ 0x40 => self.rti_cycle(bus),
+            /* RTS */ 0x60 => self.rts_cycle(bus),
+            /* PHP, PHA */ 0x08 | 0x48 => self.push_cycle(bus),
+            /* PLP, PLA */ 0x28 | 0x68 => self.pull_cycle(bus),
+            _ => {
+                bus.read(self.pc);
+                self.last_fetched = self.a;
+                self.execute(bus);
+                true
+            },
         }
-
-        self.a = temp as u8 & 0x00FF;
-
-        1
     }
-    fn ins_and(&mut self) -> u8{
-        self.a = self.a & self.fetch();
-
-        if self.a == 0x00{
-            self.set_flag(STATUS_FLAG_Z);
+    fn addrmode_zp0(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => {
+                self.absolute_addr = self.read_next(bus) as u16;
+                false
+            },
+            step => self.operate(bus, step - 2),
         }
-        if self.a & 0x80 != 0{
-            self.set_flag(STATUS_FLAG_N);
+    }
+    fn addrmode_zpy(&mut self, bus: &mut dyn CpuBus) -> bool{
+        self.zero_page_indexed(bus, self.y)
+    }
+    fn addrmode_abs(&mut self, bus: &mut dyn CpuBus) -> bool {
+        match (self.opcode, self.step) {
+            /* JSR */ (0x20, _) => self.jsr_cycle(bus),
+            (_, 1) => {
+                self.absolute_addr = self.read_next(bus) as u16;
+                false
+            },
+            /* JMP doesn't do anything with its address, it just goes there */
+            (0x4C, 2) => {
+                self.absolute_addr |= (self.read_next(bus) as u16) << 8;
+                self.execute(bus);
+                true
+            },
+            (_, 2) => {
+                self.absolute_addr |= (self.read_next(bus) as u16) << 8;
+                false
+            },
+            (_, step) => self.operate(bus, step - 3),
         }
-        1
     }
-    fn ins_asl(&mut self) -> u8{todo!()}
-    fn ins_bcc(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_C) == 0{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
-
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    fn addrmode_aby(&mut self, bus: &mut dyn CpuBus) -> bool {
+        self.absolute_indexed(bus, self.y)
+    }
+    fn addrmode_izx(&mut self, bus: &mut dyn CpuBus) -> bool {
+        match self.step {
+            1 => self.pointer = self.read_next(bus) as u16,
+            2 => {
+                /* The pointer is read before X is added */
+                bus.read(self.pointer);
+                self.pointer = (self.pointer + self.x as u16) & 0x00FF;
+            },
+            3 => self.absolute_addr = bus.read(self.pointer) as u16,
+            4 => self.absolute_addr |= (bus.read((self.pointer + 1) & 0x00FF) as u16) << 8,
+            step => return self.operate(bus, step - 5),
+        }
+        false
+    }
+    fn addrmode_imm(&mut self, bus: &mut dyn CpuBus) -> bool {
+        /* The operand is the byte right after the opcode */
+        self.absolute_addr = self.pc;
+        self.pc = self.pc.wrapping_add(1);
+        self.operate(bus, 0)
+    }
+    fn addrmode_zpx(&mut self, bus: &mut dyn CpuBus) -> bool {
+        self.zero_page_indexed(bus, self.x)
+    }
+    fn addrmode_rel(&mut self, bus: &mut dyn CpuBus) -> bool {
+        match self.step {
+            1 => {
+                self.relative_addr = self.read_next(bus) as u16;
+                if (self.relative_addr & 0x80) != 0{
+                    self.relative_addr |= 0xFF00;
+                }
+                /* The branch instruction decides if we go anywhere, if not we're done */
+                self.execute(bus);
+                !self.branch_taken
+            },
+            2 => {
+                /* Taking the branch costs a cycle, the low byte of pc is fixed on it */
+                bus.read(self.pc);
+                self.absolute_addr = self.pc.wrapping_add(self.relative_addr);
+                if (self.absolute_addr & 0xFF00) == (self.pc & 0xFF00){
+                    self.pc = self.absolute_addr;
+                    /* A taken branch that stays on its page doesn't poll interrupts on this cycle,
+                    *  an NMI or IRQ that showed up on the cycle before waits for the next instruction.
+                    *  The NMI stays pending, so it isn't lost, it's just late */
+                    self.skip_poll = true;
+                    return true;
+                }
+                self.pc = (self.pc & 0xFF00) | (self.absolute_addr & 0x00FF);
+                false
+            },
+            _ => {
+                /* Jumping to another page costs one more cycle to fix the high byte */
+                bus.read(self.pc);
+                self.pc = self.absolute_addr;
+                true
+            },
+        }
+    }
+    fn addrmode_abx(&mut self, bus: &mut dyn CpuBus) -> bool {
+        self.absolute_indexed(bus, self.x)
+    }
+    fn addrmode_ind(&mut self, bus: &mut dyn CpuBus) -> bool {
+        match self.step {
+            1 => self.pointer = self.read_next(bus) as u16,
+            2 => self.pointer |= (self.read_next(bus) as u16) << 8,
+            3 => self.absolute_addr = bus.read(self.pointer) as u16,
+            _ => {
+                /* You might want to take a look at this: https://www.nesdev.org/wiki/CPU_addressing_modes
+                *  The high byte of the pointer is never incremented, so a pointer at $xxFF wraps
+                *  around to $xx00 (page boundary hardware bug) */
+                let hi_addr = (self.pointer & 0xFF00) | (self.pointer.wrapping_add(1) & 0x00FF);
+                self.absolute_addr |= (bus.read(hi_addr) as u16) << 8;
+                self.execute(bus);
+                return true;
+            },
+        }
+        false
+    }
+    fn addrmode_izy(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => self.pointer = self.read_next(bus) as u16,
+            2 => self.absolute_addr = bus.read(self.pointer) as u16,
+            3 => {
+                self.absolute_addr |= (bus.read((self.pointer + 1) & 0x00FF) as u16) << 8;
+                self.add_index(self.y);
+            },
+            4 => return self.fix_page_cycle(bus),
+            step => return self.operate(bus, step - 5),
+        }
+        false
+    }
+}
 
-            self.pc = self.absolute_addr;
+/* Small helpers that are shared between the instructions, they don't need to be part of ICPU */
+impl CPU {
+    /* Gets a trapped CPU going again, the opcode it stopped at runs this time */
+    pub fn resume_trap(&mut self){
+        if self.trapped {
+            self.trapped = false;
+            self.skip_trap = true;
         }
-        0
     }
-    fn ins_bcs(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_C) == 1{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* True when the next clock() fetches a new opcode */
+    pub fn at_instruction_boundary(&self) -> bool{
+        self.cycles == 0 && self.step == 0
+    }
 
-            self.pc = self.absolute_addr;
+    fn set_flag_to(&mut self, status_flag: u8, value: bool){
+        if value {
+            self.set_flag(status_flag);
+        } else {
+            self.clear_flag(status_flag);
         }
-        0
     }
-    fn ins_beq(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_Z) == 1{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* Almost every instruction updates the zero and negative flags the same way */
+    fn set_zn(&mut self, value: u8){
+        self.set_flag_to(STATUS_FLAG_Z, value == 0x00);
+        self.set_flag_to(STATUS_FLAG_N, (value & 0x80) != 0);
+    }
 
-            self.pc = self.absolute_addr;
+    /* The stack lives in page 1 and grows downwards */
+    fn push(&mut self, bus: &mut dyn CpuBus, data: u8){
+        bus.write(0x0100 + self.sp as u16, data);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+    fn pull(&mut self, bus: &mut dyn CpuBus) -> u8{
+        self.sp = self.sp.wrapping_add(1);
+        bus.read(0x0100 + self.sp as u16)
+    }
+
+    /* Shifts and rotates either work on the accumulator (implied) or on memory */
+    fn store_result(&mut self, bus: &mut dyn CpuBus, data: u8){
+        if LOOKUP[self.opcode as usize].addrmode == AddrMode::IMP {
+            self.a = data;
+        } else {
+            bus.write(self.absolute_addr, data);
         }
-        0
     }
-    fn ins_bit(&mut self) -> u8{todo!()}
-    fn ins_bmi(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_N) == 1{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* ADC and SBC (with an inverted operand) share all of their logic */
+    fn add_with_carry(&mut self, value: u8){
+        let temp: u16 = self.a as u16 + value as u16 + self.get_flag(STATUS_FLAG_C) as u16;
+        self.set_flag_to(STATUS_FLAG_C, temp > 255);
+        /* If you want to know where the fuck this comes from, maybe watch this: https://youtu.be/8XmxKPJDGU0?t=2819 */
+        // SetFlag(V, (~((uint16_t)a ^ (uint16_t)fetched) & ((uint16_t)a ^ (uint16_t)temp)) & 0x0080);
+        self.set_flag_to(STATUS_FLAG_V, (!((self.a as u16) ^ (value as u16)) & ((self.a as u16) ^ temp) & 0x0080) != 0);
+        self.a = temp as u8;
+        self.set_zn(self.a);
+    }
 
-            self.pc = self.absolute_addr;
+    /* Decimal mode only exists on the NMOS 6502, see CpuVariant */
+    fn decimal_mode(&self) -> bool{
+        self.variant == CpuVariant::Nmos6502 && self.get_flag(STATUS_FLAG_D) == 1
+    }
+
+    /* ADC, RRA */
+    fn adc(&mut self, value: u8){
+        if self.decimal_mode() {
+            self.add_decimal(value);
+        } else {
+            self.add_with_carry(value);
         }
-        0
     }
-    fn ins_bne(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_Z) == 0{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* SBC, ISB */
+    fn sbc(&mut self, value: u8){
+        /* The flags of a decimal SBC are the same as the binary ones, only A is different */
+        let a = self.a;
+        let borrow = 1 - self.get_flag(STATUS_FLAG_C) as u16;
+        /* Subtraction is just an addition with the inverted operand */
+        self.add_with_carry(value ^ 0xFF);
+        if self.decimal_mode() {
+            self.a = subtract_decimal(a, value, borrow);
+        }
+    }
 
-            self.pc = self.absolute_addr;
+    /* BCD addition like the NMOS 6502 does it, each nibble is fixed up once it goes over 9.
+    *  Z still comes from the binary sum and N and V are taken before the high nibble is fixed,
+    *  so they don't always make sense, but that's what the real chip does.
+    *  http://www.6502.org/tutorials/decimal_mode.html has all the details */
+    fn add_decimal(&mut self, value: u8){
+        let a = self.a as u16;
+        let value = value as u16;
+        let carry = self.get_flag(STATUS_FLAG_C) as u16;
+
+        let mut temp = (a & 0x0F) + (value & 0x0F) + carry;
+        if temp > 0x09 {
+            temp += 0x06;
+        }
+        temp = if temp <= 0x0F {
+            (temp & 0x0F) + (a & 0xF0) + (value & 0xF0)
+        } else {
+            (temp & 0x0F) + (a & 0xF0) + (value & 0xF0) + 0x10
+        };
+
+        self.set_flag_to(STATUS_FLAG_Z, (a + value + carry) & 0x00FF == 0);
+        self.set_flag_to(STATUS_FLAG_N, (temp & 0x0080) != 0);
+        self.set_flag_to(STATUS_FLAG_V, ((a ^ temp) & 0x0080) != 0 && ((a ^ value) & 0x0080) == 0);
+
+        if (temp & 0x01F0) > 0x0090 {
+            temp += 0x0060;
         }
-        0
+        self.set_flag_to(STATUS_FLAG_C, (temp & 0x0FF0) > 0x00F0);
+        self.a = temp as u8;
     }
-    fn ins_bpl(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_N) == 0{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* The shifts and rotates only update the carry here, the callers decide about Z and N */
+    fn shift_left(&mut self, value: u8) -> u8{
+        self.set_flag_to(STATUS_FLAG_C, (value & 0x80) != 0);
+        value << 1
+    }
+    fn shift_right(&mut self, value: u8) -> u8{
+        self.set_flag_to(STATUS_FLAG_C, (value & 0x01) != 0);
+        value >> 1
+    }
+    fn rotate_left(&mut self, value: u8) -> u8{
+        let temp = (value << 1) | self.get_flag(STATUS_FLAG_C);
+        self.set_flag_to(STATUS_FLAG_C, (value & 0x80) != 0);
+        temp
+    }
+    fn rotate_right(&mut self, value: u8) -> u8{
+        let temp = (self.get_flag(STATUS_FLAG_C) << 7) | (value >> 1);
+        self.set_flag_to(STATUS_FLAG_C, (value & 0x01) != 0);
+        temp
+    }
 
-            self.pc = self.absolute_addr;
+    /* SHA, SHX, SHY and TAS store the value ANDed with the high byte of the base address + 1.
+    *  If the index crosses a page, that value also replaces the high byte of the target address. */
+    fn store_high_and(&mut self, bus: &mut dyn CpuBus, value: u8, index: u8){
+        let base = self.absolute_addr.wrapping_sub(index as u16);
+        let temp = value & ((base >> 8) as u8).wrapping_add(1);
+        if (base & 0xFF00) != (self.absolute_addr & 0xFF00) {
+            self.absolute_addr = ((temp as u16) << 8) | (self.absolute_addr & 0x00FF);
         }
-        0
+        bus.write(self.absolute_addr, temp);
     }
-    fn ins_brk(&mut self) -> u8{
-        // todo!()
 
-        println!("Current instruction: BRK (Not implemented yet!)");
-        0
+    fn compare(&mut self, register: u8){
+        self.fetch();
+        self.set_flag_to(STATUS_FLAG_C, register >= self.last_fetched);
+        self.set_zn(register.wrapping_sub(self.last_fetched));
     }
-    fn ins_bvc(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_V) == 0{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* addrmode_rel() does the rest */
+    fn branch(&mut self, condition: bool){
+        self.branch_taken = condition;
+    }
 
-            self.pc = self.absolute_addr;
+    /* Runs the opcode function of the current instruction */
+    fn execute(&mut self, bus: &mut dyn CpuBus){
+        use Mnemonic::*;
+        match LOOKUP[self.opcode as usize].mnemonic {
+            ADC => self.ins_adc(),
+            AND => self.ins_and(),
+            ASL => self.ins_asl(bus),
+            BCC => self.ins_bcc(),
+            BCS => self.ins_bcs(),
+            BEQ => self.ins_beq(),
+            BIT => self.ins_bit(),
+            BMI => self.ins_bmi(),
+            BNE => self.ins_bne(),
+            BPL => self.ins_bpl(),
+            BRK => self.ins_brk(bus),
+            BVC => self.ins_bvc(),
+            BVS => self.ins_bvs(),
+            CLC => self.ins_clc(),
+            CLD => self.ins_cld(),
+            CLI => self.ins_cli(),
+            CLV => self.ins_clv(),
+            CMP => self.ins_cmp(),
+            CPX => self.ins_cpx(),
+            CPY => self.ins_cpy(),
+            DEC => self.ins_dec(bus),
+            DEX => self.ins_dex(),
+            DEY => self.ins_dey(),
+            EOR => self.ins_eor(),
+            INC => self.ins_inc(bus),
+            INX => self.ins_inx(),
+            INY => self.ins_iny(),
+            JMP => self.ins_jmp(),
+            JSR => self.ins_jsr(),
+            LDA => self.ins_lda(),
+            LDX => self.ins_ldx(),
+            LDY => self.ins_ldy(),
+            LSR => self.ins_lsr(bus),
+            NOP => self.ins_nop(),
+            ORA => self.ins_ora(),
+            PHA => self.ins_pha(bus),
+            PHP => self.ins_php(bus),
+            PLA => self.ins_pla(bus),
+            PLP => self.ins_plp(bus),
+            ROL => self.ins_rol(bus),
+            ROR => self.ins_ror(bus),
+            RTI => self.ins_rti(bus),
+            RTS => self.ins_rts(),
+            SBC => self.ins_sbc(),
+            SEC => self.ins_sec(),
+            SED => self.ins_sed(),
+            SEI => self.ins_sei(),
+            STA => self.ins_sta(bus),
+            STX => self.ins_stx(bus),
+            STY => self.ins_sty(bus),
+            TAX => self.ins_tax(),
+            TAY => self.ins_tay(),
+            TSX => self.ins_tsx(),
+            TXA => self.ins_txa(),
+            TXS => self.ins_txs(),
+            TYA => self.ins_tya(),
+            ALR => self.ins_alr(),
+            ANC => self.ins_anc(),
+            ANE => self.ins_ane(),
+            ARR => self.ins_arr(),
+            DCP => self.ins_dcp(bus),
+            ISB => self.ins_isb(bus),
+            JAM => self.ins_jam(),
+            LAS => self.ins_las(),
+            LAX => self.ins_lax(),
+            LXA => self.ins_lxa(),
+            RLA => self.ins_rla(bus),
+            RRA => self.ins_rra(bus),
+            SAX => self.ins_sax(bus),
+            SBX => self.ins_sbx(),
+            SHA => self.ins_sha(bus),
+            SHX => self.ins_shx(bus),
+            SHY => self.ins_shy(bus),
+            SLO => self.ins_slo(bus),
+            SRE => self.ins_sre(bus),
+            TAS => self.ins_tas(bus),
         }
-        0
     }
-    fn ins_bvs(&mut self) -> u8{
-        if self.get_flag(STATUS_FLAG_V) == 1{
-            self.cycles += 1;
-            self.absolute_addr = self.pc + self.relative_addr;
 
-            if (self.absolute_addr & 0xFF0) != (self.pc & 0xFF00){
-                self.cycles += 1;
-            } 
+    /* Reads the next byte of the instruction */
+    fn read_next(&mut self, bus: &mut dyn CpuBus) -> u8{
+        let data = bus.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        data
+    }
 
-            self.pc = self.absolute_addr;
+    /* The cycles after the addressing mode has worked out absolute_addr, op_step counts from 0 */
+    fn operate(&mut self, bus: &mut dyn CpuBus, op_step: u8) -> bool{
+        match (LOOKUP[self.opcode as usize].access, op_step) {
+            (Access::Read, _) => {
+                self.last_fetched = bus.read(self.absolute_addr);
+                self.execute(bus);
+                true
+            },
+            (Access::Write, _) => {
+                self.execute(bus);
+                true
+            },
+            (Access::ReadModifyWrite, 0) => {
+                self.last_fetched = bus.read(self.absolute_addr);
+                false
+            },
+            (Access::ReadModifyWrite, 1) => {
+                /* While the value is being modified the unchanged value is written back,
+                *  mappers like the MMC1 see both writes */
+                bus.write(self.absolute_addr, self.last_fetched);
+                false
+            },
+            (Access::ReadModifyWrite, _) => {
+                self.execute(bus);
+                true
+            },
         }
-        0
     }
-    fn ins_clc(&mut self) -> u8{
-        self.clear_flag(STATUS_FLAG_C);
-        0
+
+    /* Zero page,X and zero page,Y read the unindexed address first and never leave the zero page */
+    fn zero_page_indexed(&mut self, bus: &mut dyn CpuBus, index: u8) -> bool{
+        match self.step {
+            1 => self.absolute_addr = self.read_next(bus) as u16,
+            2 => {
+                bus.read(self.absolute_addr);
+                self.absolute_addr = (self.absolute_addr + index as u16) & 0x00FF;
+            },
+            step => return self.operate(bus, step - 3),
+        }
+        false
     }
-    fn ins_cld(&mut self) -> u8{
-        self.clear_flag(STATUS_FLAG_D);
-        0
+
+    fn absolute_indexed(&mut self, bus: &mut dyn CpuBus, index: u8) -> bool{
+        match self.step {
+            1 => self.absolute_addr = self.read_next(bus) as u16,
+            2 => {
+                self.absolute_addr |= (self.read_next(bus) as u16) << 8;
+                self.add_index(index);
+            },
+            3 => return self.fix_page_cycle(bus),
+            step => return self.operate(bus, step - 4),
+        }
+        false
     }
-    fn ins_cli(&mut self) -> u8{
-        self.clear_flag(STATUS_FLAG_I);
-        0
+
+    fn add_index(&mut self, index: u8){
+        let base = self.absolute_addr;
+        self.absolute_addr = base.wrapping_add(index as u16);
+        self.page_crossed = (base & 0xFF00) != (self.absolute_addr & 0xFF00);
     }
-    fn ins_clv(&mut self) -> u8{
-        self.clear_flag(STATUS_FLAG_V);
-        0
-    }
-    fn ins_cmp(&mut self) -> u8{todo!()}
-    fn ins_cpx(&mut self) -> u8{todo!()}
-    fn ins_cpy(&mut self) -> u8{todo!()}
-    fn ins_dec(&mut self) -> u8{todo!()}
-    fn ins_dex(&mut self) -> u8{todo!()}
-    fn ins_dey(&mut self) -> u8{todo!()}
-    fn ins_eor(&mut self) -> u8{todo!()}
-    fn ins_inc(&mut self) -> u8{todo!()}
-    fn ins_inx(&mut self) -> u8{todo!()}
-    fn ins_iny(&mut self) -> u8{todo!()}
-    fn ins_jmp(&mut self) -> u8{todo!()}
-    fn ins_jsr(&mut self) -> u8{todo!()}
-    fn ins_lda(&mut self) -> u8{todo!()}
-    fn ins_ldx(&mut self) -> u8{todo!()}
-    fn ins_ldy(&mut self) -> u8{todo!()}
-    fn ins_lsr(&mut self) -> u8{todo!()}
-    fn ins_nop(&mut self) -> u8{todo!()}
-    fn ins_ora(&mut self) -> u8{todo!()}
-    fn ins_pha(&mut self) -> u8{
-        self.write(0x0100 + self.sp as u16, self.a);
-        self.sp -= 1;
-        0
-    }
-    fn ins_php(&mut self) -> u8{todo!()}
-    fn ins_pla(&mut self) -> u8{
-        self.sp += 1;
-        self.a = self.read(0x0100 + self.sp as u16);
-        if self.a == 0x00 {self.set_flag(STATUS_FLAG_Z);}
-        if self.a & 0x80 != 0 {self.set_flag(STATUS_FLAG_N);}
-        0
-    }
-    fn ins_plp(&mut self) -> u8{todo!()}
-    fn ins_rol(&mut self) -> u8{todo!()}
-    fn ins_ror(&mut self) -> u8{todo!()}
-    fn ins_rti(&mut self) -> u8{
-        self.sp += 1;
-        self.status_flags = self.read(0x0100 + self.sp as u16) as u8;
-        self.clear_flag(STATUS_FLAG_B);
-        self.clear_flag(STATUS_FLAG_1);
 
-        self.sp += 1;
-        self.pc = self.read(0x0100 + self.sp as u16) as u16;
-        self.sp += 1;
-        self.pc |= (self.read(0x0100 + self.sp as u16) as u16 )<< 8;
+    /* The index is added to the low byte first, so the CPU reads from the wrong page when the
+    *  addition carries. Read instructions are done if it didn't, everything else always reads the
+    *  address once before the real access. */
+    fn fix_page_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        if LOOKUP[self.opcode as usize].access == Access::Read && !self.page_crossed {
+            return self.operate(bus, 0);
+        }
+        let unfixed = if self.page_crossed { self.absolute_addr.wrapping_sub(0x0100) } else { self.absolute_addr };
+        bus.read(unfixed);
+        false
+    }
 
-        0
+    /* BRK reads its padding byte, pushes pc and the status and then jumps through $FFFE.
+    *  NMIs and IRQs run the same sequence, they just don't skip the padding byte and push the
+    *  status without the B flag. */
+    fn brk_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => {
+                bus.read(self.pc);
+                if !self.hardware_interrupt {
+                    self.pc = self.pc.wrapping_add(1);
+                }
+            },
+            2 => self.push(bus, (self.pc >> 8) as u8),
+            3 => self.push(bus, self.pc as u8),
+            4 => {
+                /* The vector is only picked now, so an NMI that shows up until here hijacks
+                *  an IRQ or BRK, which then jumps to the NMI handler instead */
+                self.pointer = if self.nmi_pending { 0xFFFA } else { 0xFFFE };
+                self.nmi_pending = false;
+                if self.hardware_interrupt {
+                    self.push(bus, (self.status_flags & !STATUS_FLAG_B) | STATUS_FLAG_1);
+                    self.set_flag(STATUS_FLAG_I);
+                } else {
+                    self.execute(bus);
+                }
+            },
+            5 => self.absolute_addr = bus.read(self.pointer) as u16,
+            _ => {
+                self.absolute_addr |= (bus.read(self.pointer + 1) as u16) << 8;
+                self.pc = self.absolute_addr;
+                self.hardware_interrupt = false;
+                return true;
+            },
+        }
+        false
     }
-    fn ins_rts(&mut self) -> u8{todo!()}
-    fn ins_sbc(&mut self) -> u8{
-        self.fetch();
-        let val: u16 = self.last_fetched as u16 ^ 0x00FF;
-
-        let temp: u16 = self.a as u16 + val + self.get_flag(STATUS_FLAG_C) as u16;
-    
-        /* Same as with addition */
-        if temp > 255{self.set_flag(STATUS_FLAG_C);}
-        if (temp & 0x00FF) == 0{self.set_flag(STATUS_FLAG_Z);}
-        if (temp & 0x80) != 0 {self.set_flag(STATUS_FLAG_N);}
-        /* If you want to know where the fuck this comes from, maybe watch this: https://youtu.be/8XmxKPJDGU0?t=2819 */
-	      // SetFlag(V, (~((uint16_t)a ^ (uint16_t)fetched) & ((uint16_t)a ^ (uint16_t)temp)) & 0x0080);
-        if (!((self.a as u16) ^ (self.last_fetched as u16)) & ((self.a as u16) ^ (temp as u16) & 0x0080)) != 0{
-            self.set_flag(STATUS_FLAG_V);
+
+    /* Runs at the end of every cycle. Whether an interrupt follows an instruction is decided by
+    *  what this saw at the end of its second to last cycle (prev_*). That's why CLI, SEI and PLP,
+    *  which change the I flag on their last cycle, only have an effect after the next instruction,
+    *  while RTI restores it early enough to count right away.
+    *  https://www.nesdev.org/wiki/CPU_interrupts */
+    fn poll_interrupts(&mut self){
+        if !self.skip_poll {
+            self.prev_nmi_pending = self.nmi_pending;
+            self.prev_irq_pending = self.irq_pending;
         }
+        self.skip_poll = false;
 
-        self.a = temp as u8 & 0x00FF;
+        self.irq_pending = self.irq_lines != 0 && self.get_flag(STATUS_FLAG_I) == 0;
+    }
 
-        1
+    /* JSR reads the low byte of the address, pushes pc (which now points at the high byte,
+    *  RTS adds the missing 1 again) and only then reads the high byte */
+    fn jsr_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => self.absolute_addr = self.read_next(bus) as u16,
+            2 => {
+                bus.read(0x0100 + self.sp as u16);
+            },
+            3 => self.push(bus, (self.pc >> 8) as u8),
+            4 => self.push(bus, self.pc as u8),
+            _ => {
+                self.absolute_addr |= (bus.read(self.pc) as u16) << 8;
+                self.execute(bus);
+                return true;
+            },
+        }
+        false
+    }
 
+    fn rti_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => {
+                bus.read(self.pc);
+            },
+            2 => {
+                bus.read(0x0100 + self.sp as u16);
+            },
+            3 => self.execute(bus),
+            4 => self.pc = self.pull(bus) as u16,
+            _ => {
+                self.pc |= (self.pull(bus) as u16) << 8;
+                return true;
+            },
+        }
+        false
     }
-    fn ins_sec(&mut self) -> u8{todo!()}
-    fn ins_sed(&mut self) -> u8{todo!()}
-    fn ins_sei(&mut self) -> u8{todo!()}
-    fn ins_sta(&mut self) -> u8{todo!()}
-    fn ins_stx(&mut self) -> u8{todo!()}
-    fn ins_sty(&mut self) -> u8{todo!()}
-    fn ins_tax(&mut self) -> u8{todo!()}
-    fn ins_tay(&mut self) -> u8{todo!()}
-    fn ins_tsx(&mut self) -> u8{todo!()}
-    fn ins_txa(&mut self) -> u8{todo!()}
-    fn ins_txs(&mut self) -> u8{todo!()}
-    fn ins_tya(&mut self) -> u8{todo!()}
 
-    // Illegal opcode
-    fn ins_xxx(&mut self) -> u8{
-        println!("Illegal opcode detected!");
-        0
+    fn rts_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => {
+                bus.read(self.pc);
+            },
+            2 => {
+                bus.read(0x0100 + self.sp as u16);
+            },
+            3 => self.pc = self.pull(bus) as u16,
+            4 => self.pc |= (self.pull(bus) as u16) << 8,
+            _ => {
+                bus.read(self.pc);
+                self.execute(bus);
+                return true;
+            },
+        }
+        false
     }
 
-    /* Addressing modes */
+    /* PHA and PHP */
+    fn push_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        if self.step == 1 {
+            bus.read(self.pc);
+            return false;
+        }
+        self.execute(bus);
+        true
+    }
 
-    /* Implied means there is no additional data, so we just return 0 */
-    fn addrmode_imp(&mut self) -> u8 {
-        self.last_fetched = self.a;
-        0
-    }
-    fn addrmode_zp0(&mut self) -> u8{
-        self.absolute_addr = self.read(self.pc) as u16;
-        self.pc += 1;
-        self.absolute_addr &= 0x00ff;
-        0
-    }
-    fn addrmode_zpy(&mut self) -> u8{
-        self.absolute_addr = self.read(self.pc) as u16 + self.y as u16;
-        self.pc += 1;
-        self.absolute_addr &= 0x00FF;
-        0
-    }
-    fn addrmode_abs(&mut self) -> u8 {
-        let lo: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-
-        self.absolute_addr = (hi << 8) | lo;
-        0
-    }
-    fn addrmode_aby(&mut self) -> u8 {
-        let lo: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-
-        self.absolute_addr = (hi << 8) | lo;
-        self.absolute_addr += self.y as u16;
-
-        if (self.absolute_addr & 0xFF00) != (hi << 8){
-            1
-        }else {
-            0
+    /* PLA and PLP */
+    fn pull_cycle(&mut self, bus: &mut dyn CpuBus) -> bool{
+        match self.step {
+            1 => {
+                bus.read(self.pc);
+            },
+            2 => {
+                bus.read(0x0100 + self.sp as u16);
+            },
+            _ => {
+                self.execute(bus);
+                return true;
+            },
         }
+        false
     }
-    fn addrmode_izx(&mut self) -> u8 {
-        let t: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
+}
 
-        let lo: u16 = self.read((t + self.x as u16) & 0x00FF) as u16;
-        let hi: u16 = self.read((t + self.x  as u16 + 1) & 0x00FF) as u16;
+/* The result of a BCD subtraction on the NMOS 6502, the flags are set like in binary mode */
+fn subtract_decimal(a: u8, value: u8, borrow: u16) -> u8{
+    let a = a as u16;
+    let value = value as u16;
+
+    let mut temp = (a & 0x0F).wrapping_sub(value & 0x0F).wrapping_sub(borrow);
+    temp = if (temp & 0x0010) != 0 {
+        (temp.wrapping_sub(0x06) & 0x0F) | (a & 0xF0).wrapping_sub(value & 0xF0).wrapping_sub(0x10)
+    } else {
+        (temp & 0x0F) | (a & 0xF0).wrapping_sub(value & 0xF0)
+    };
+    if (temp & 0x0100) != 0 {
+        temp = temp.wrapping_sub(0x60);
+    }
+    temp as u8
+}
 
-        self.absolute_addr = (hi << 8) | lo;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flat_bus::{AccessKind, BusAccess, FlatBus};
+
+    /* A CPU on a FlatBus with program at addr, ready to run its first instruction */
+    fn machine(addr: u16, program: &[u8]) -> (CPU, FlatBus) {
+        let mut bus = FlatBus::new();
+        let mut cpu = CPU::new();
+
+        let start = addr as usize;
+        bus.ram[start..start + program.len()].copy_from_slice(program);
+        cpu.pc = addr;
+        cpu.sp = 0xFD;
+        cpu.status_flags = STATUS_FLAG_1 | STATUS_FLAG_I;
+        (cpu, bus)
+    }
 
-        0
+    /* Runs one instruction and returns how many cycles it took */
+    fn run_instruction(cpu: &mut CPU, bus: &mut FlatBus) -> usize {
+        bus.accesses.clear();
+        let mut cycles = 0;
+        loop {
+            cpu.clock(bus);
+            cycles += 1;
+            if cpu.at_instruction_boundary() {
+                return cycles;
+            }
+        }
     }
-    fn addrmode_imm(&mut self) -> u8 {
-        self.pc += 1;
-        self.absolute_addr = self.pc;
-        0
+
+    fn read(addr: u16, data: u8) -> BusAccess {
+        BusAccess { addr, data, kind: AccessKind::Read }
     }
-    fn addrmode_zpx(&mut self) -> u8 {
-        self.absolute_addr = self.read(self.pc) as u16 + self.x as u16;
-        self.pc += 1;
-        self.absolute_addr &= 0xFF;
-        0
+    fn write(addr: u16, data: u8) -> BusAccess {
+        BusAccess { addr, data, kind: AccessKind::Write }
     }
-    fn addrmode_rel(&mut self) -> u8 {
-        self.relative_addr = self.read(self.pc) as u16;
-        self.pc += 1;
 
-        if (self.relative_addr & 0x80) != 0{
-            self.relative_addr |= 0xFF00;
+    #[test]
+    fn cycle_counts_include_page_crossing_penalties() {
+        /* The program, what X and Y are set to and the cycles it takes */
+        let cases: [(&[u8], u8, usize); 11] = [
+            (&[0xA9, 0x42], 0, 2),       /* LDA #$42 */
+            (&[0xAD, 0x00, 0x03], 0, 4), /* LDA $0300 */
+            (&[0xBD, 0x10, 0x03], 1, 4), /* LDA $0310,X */
+            (&[0xBD, 0xFF, 0x03], 1, 5), /* LDA $03FF,X crosses into $0400 */
+            (&[0xB9, 0xFF, 0x03], 1, 5), /* LDA $03FF,Y */
+            (&[0x9D, 0x10, 0x03], 0, 5), /* STA $0310,X always takes the extra cycle */
+            (&[0xB1, 0x10], 0, 5),       /* LDA ($10),Y with ($10) = $03FF */
+            (&[0xB1, 0x10], 1, 6),       /* Same, but crossing into $0400 */
+            (&[0xFE, 0x00, 0x03], 0, 7), /* INC $0300,X */
+            (&[0x20, 0x00, 0x03], 0, 6), /* JSR $0300 */
+            (&[0x00, 0x00], 0, 7),       /* BRK */
+        ];
+        for (program, index, expected) in cases {
+            let (mut cpu, mut bus) = machine(0x0200, program);
+            bus.ram[0x10] = 0xFF;
+            bus.ram[0x11] = 0x03;
+            cpu.x = index;
+            cpu.y = index;
+            assert_eq!(run_instruction(&mut cpu, &mut bus), expected, "{:02X?}", program);
         }
-        0
     }
-    fn addrmode_abx(&mut self) -> u8 {
-        let lo: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-
-        self.absolute_addr = (hi << 8) | lo;
-        self.absolute_addr += self.x as u16;
 
-        if (self.absolute_addr & 0xFF00) != (hi << 8){
-            1
-        }else {
-            0
+    #[test]
+    fn every_opcode_takes_its_base_cycles_without_page_crossings() {
+        for (opcode, instruction) in LOOKUP.iter().enumerate() {
+            /* JAM never finishes and branches are checked below */
+            if instruction.mnemonic == Mnemonic::JAM || instruction.addrmode == AddrMode::REL {
+                continue;
+            }
+            let (mut cpu, mut bus) = machine(0x0200, &[opcode as u8, 0x10, 0x03]);
+            bus.ram[0x10] = 0x00;
+            bus.ram[0x11] = 0x03;
+            assert_eq!(run_instruction(&mut cpu, &mut bus), instruction.cycles as usize, "opcode ${:02X}", opcode);
         }
     }
-    fn addrmode_ind(&mut self) -> u8 {
-        let ptr_lo: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-        let ptr_hi: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
-        
-        let ptr: u16 = (ptr_hi << 8) | ptr_lo;
 
-        /* You might want to take a look at this: https://www.nesdev.org/wiki/CPU_addressing_modes */
-        if ptr_lo == 0x00FF{ /* Simulate a page boundary hardware bug */
-            self.absolute_addr = ((self.read(ptr & 0xFF00) as u16) << 8) | self.read(ptr + 0) as u16;
-        }else { /* Normal behaviour */
-            self.absolute_addr = ((self.read(ptr + 1) as u16) << 8) | self.read(ptr + 0) as u16;
+    #[test]
+    fn branches_take_longer_when_taken_and_crossing_a_page() {
+        /* Where the branch is, its offset, whether Z is set and the cycles it takes */
+        let cases = [
+            (0x0200, 0x02, true, 2),  /* Not taken */
+            (0x0200, 0x02, false, 3), /* Taken, lands on $0204 */
+            (0x02F0, 0x20, false, 4), /* Taken, lands on $0312 */
+            (0x0300, 0xF0, false, 4), /* Taken backwards, lands on $02F2 */
+        ];
+        for (addr, offset, zero, expected) in cases {
+            /* BNE */
+            let (mut cpu, mut bus) = machine(addr, &[0xD0, offset]);
+            if zero {
+                cpu.set_flag(STATUS_FLAG_Z);
+            }
+            assert_eq!(run_instruction(&mut cpu, &mut bus), expected, "BNE at ${:04X}", addr);
+            let target = if zero { addr + 2 } else { addr.wrapping_add(2).wrapping_add(offset as i8 as u16) };
+            assert_eq!(cpu.pc, target);
         }
+    }
 
-        0
+    #[test]
+    fn read_modify_write_writes_the_old_value_back_first() {
+        /* INC $10 */
+        let (mut cpu, mut bus) = machine(0x0200, &[0xE6, 0x10]);
+        bus.ram[0x10] = 0x41;
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(bus.accesses, [
+            read(0x0200, 0xE6),
+            read(0x0201, 0x10),
+            read(0x0010, 0x41),
+            write(0x0010, 0x41),
+            write(0x0010, 0x42),
+        ]);
+
+        /* INC $02FE,X with X = 3 reads from $0201 before the carry gets into the high byte */
+        let (mut cpu, mut bus) = machine(0x0200, &[0xFE, 0xFE, 0x02]);
+        bus.ram[0x0301] = 0x7F;
+        cpu.x = 0x03;
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(bus.accesses, [
+            read(0x0200, 0xFE),
+            read(0x0201, 0xFE),
+            read(0x0202, 0x02),
+            read(0x0201, 0xFE),
+            read(0x0301, 0x7F),
+            write(0x0301, 0x7F),
+            write(0x0301, 0x80),
+        ]);
     }
-    fn addrmode_izy(&mut self) -> u8{
-        let t: u16 = self.read(self.pc) as u16;
-        self.pc += 1;
 
-        let lo: u16 = self.read(t & 0x00FF) as u16;
-        let hi: u16 = self.read((t+1) & 0x00FF) as u16;
+    #[test]
+    fn indexed_stores_do_a_dummy_read_before_the_page_is_fixed() {
+        /* STA $02FF,X with X = 1 */
+        let (mut cpu, mut bus) = machine(0x0200, &[0x9D, 0xFF, 0x02]);
+        cpu.a = 0x99;
+        cpu.x = 0x01;
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(bus.accesses, [
+            read(0x0200, 0x9D),
+            read(0x0201, 0xFF),
+            read(0x0202, 0x02),
+            read(0x0200, 0x9D),
+            write(0x0300, 0x99),
+        ]);
+    }
 
-        self.absolute_addr = (hi << 8) | lo;
-        self.absolute_addr += self.y as u16;
+    /* Puts the NMI handler at $0400 and the IRQ handler at $0500, both just NOPs */
+    fn set_vectors(bus: &mut FlatBus) {
+        bus.ram[0xFFFA..].copy_from_slice(&[0x00, 0x04, 0x00, 0x00, 0x00, 0x05]);
+        bus.ram[0x0400..0x0410].fill(0xEA);
+        bus.ram[0x0500..0x0510].fill(0xEA);
+    }
 
-        if (self.absolute_addr & 0xFF00) != (hi << 8){
-            1
-        }else {
-            0
+    /* What the last interrupt or BRK pushed: pc and the status flags */
+    fn pushed(bus: &FlatBus) -> (u16, u8) {
+        (((bus.ram[0x01FD] as u16) << 8) | bus.ram[0x01FC] as u16, bus.ram[0x01FB])
+    }
+
+    #[test]
+    fn nmi_runs_after_the_current_instruction() {
+        let (mut cpu, mut bus) = machine(0x0200, &[0xEA, 0xEA, 0xEA]);
+        set_vectors(&mut bus);
+        cpu.set_nmi(true);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 2);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 7);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(pushed(&bus), (0x0201, STATUS_FLAG_1 | STATUS_FLAG_I));
+        assert_eq!(cpu.get_flag(STATUS_FLAG_I), 1);
+
+        /* NMI is edge triggered, keeping the line active doesn't start another one */
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0401);
+    }
+
+    #[test]
+    fn nmi_on_the_last_cycle_waits_for_the_next_instruction() {
+        let (mut cpu, mut bus) = machine(0x0200, &[0xEA, 0xEA, 0xEA]);
+        set_vectors(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.set_nmi(true);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 2);
+        assert_eq!(cpu.pc, 0x0202);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 7);
+        assert_eq!(pushed(&bus).0, 0x0202);
+    }
+
+    #[test]
+    fn irq_waits_for_the_i_flag_and_cli_takes_an_instruction_to_count() {
+        /* CLI, NOP, NOP */
+        let (mut cpu, mut bus) = machine(0x0200, &[0x58, 0xEA, 0xEA]);
+        set_vectors(&mut bus);
+        cpu.set_irq(IRQ_SOURCE_MAPPER, true);
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0201);
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0202);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 7);
+        assert_eq!(cpu.pc, 0x0500);
+        assert_eq!(pushed(&bus), (0x0202, STATUS_FLAG_1));
+
+        /* The handler runs with I set, so the line that is still active doesn't get in again */
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0501);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        let (mut cpu, mut bus) = machine(0x0200, &[0x00, 0x00]);
+        set_vectors(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.set_nmi(true);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 5);
+        /* BRK went to the NMI handler, but still pushed its B flag */
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(pushed(&bus), (0x0202, STATUS_FLAG_1 | STATUS_FLAG_B | STATUS_FLAG_I));
+
+        /* The NMI was handled by BRK, it doesn't run a second time */
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0401);
+    }
+
+    #[test]
+    fn taken_branch_on_the_same_page_delays_interrupts() {
+        /* BNE to the next instruction, NOP, NOP */
+        let (mut cpu, mut bus) = machine(0x0200, &[0xD0, 0x00, 0xEA, 0xEA]);
+        set_vectors(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.clock(&mut bus);
+        cpu.set_nmi(true);
+        assert_eq!(run_instruction(&mut cpu, &mut bus), 1);
+        assert_eq!(cpu.pc, 0x0202);
+        /* Without the branch the NMI would come now, instead the NOP gets to run first */
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0203);
+        run_instruction(&mut cpu, &mut bus);
+        assert_eq!(cpu.pc, 0x0400);
+    }
+
+    /* SED, CLC, LDA #a, ADC #value (or SEC, SBC #value), returns A and the carry */
+    fn decimal(variant: CpuVariant, a: u8, value: u8, subtract: bool) -> (u8, u8) {
+        let program = if subtract {
+            [0xF8, 0x38, 0xA9, a, 0xE9, value]
+        } else {
+            [0xF8, 0x18, 0xA9, a, 0x69, value]
+        };
+        let (mut cpu, mut bus) = machine(0x0200, &program);
+        cpu.variant = variant;
+        for _ in 0..4 {
+            run_instruction(&mut cpu, &mut bus);
         }
+        (cpu.a, cpu.get_flag(STATUS_FLAG_C))
     }
-    
-    // Illegal addrmode
-    fn addrmode_xxx(&mut self) -> u8 {
-        println!("Illegal Addressing mode detected!");
-        0
+
+    #[test]
+    fn decimal_mode_only_exists_on_the_nmos_6502() {
+        assert_eq!(decimal(CpuVariant::Nmos6502, 0x09, 0x01, false), (0x10, 0));
+        assert_eq!(decimal(CpuVariant::Nmos6502, 0x99, 0x01, false), (0x00, 1));
+        assert_eq!(decimal(CpuVariant::Nmos6502, 0x10, 0x01, true), (0x09, 1));
+        assert_eq!(decimal(CpuVariant::Nmos6502, 0x00, 0x01, true), (0x99, 0));
+
+        /* The 2A03 has the D flag, but ignores it */
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x09, 0x01, false), (0x0A, 0));
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x99, 0x01, false), (0x9A, 0));
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x10, 0x01, true), (0x0F, 1));
+        assert_eq!(decimal(CpuVariant::Ricoh2A03, 0x00, 0x01, true), (0xFF, 0));
     }
 }
-
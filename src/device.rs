@@ -0,0 +1,194 @@
+/*  device.rs
+*   Things that can be plugged into the CPU bus.
+*
+*   A device says which addresses it answers to and the BUS sends reads and writes there,
+*   without BUS::read and BUS::write having to know about it (see BUS::attach_device()).
+*   RAM is one of them, others could be expansion audio, or a debugging port at $6000 that
+*   test ROMs write their results to (a Ram over $6000-$7FFF does the job, BUS::device() and
+*   BUS::device_mut() get it back to read or clear the result).
+*
+*   The PPU, the APU, the DMA and the controllers are wired into more than the bus (the
+*   cartridge, the NMI and IRQ lines, the frontend), so they stay built into the BUS.
+*/
+
+use std::any::Any;
+use std::ops::RangeInclusive;
+
+/* box_clone and Send for the same reason as the mappers (see mapper.rs). Any is there so the
+*  BUS can hand a device back as what it really is */
+pub trait BusDevice: Any + Send {
+    fn box_clone(&self) -> Box<dyn BusDevice>;
+
+    /* The CPU addresses the device answers to, the BUS asks once when the device is attached */
+    fn ranges(&self) -> Vec<RangeInclusive<u16>>;
+
+    /* Same rules as for the BUS, read can have side effects and peek can't */
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    fn peek(&self, addr: u16) -> u8;
+
+    /* Once per CPU cycle, for devices that do something on their own (timers, sound...). Only
+    *  devices that return true from needs_tick() (asked once when attached) get ticked, so the
+    *  others don't cost anything */
+    fn needs_tick(&self) -> bool {
+        false
+    }
+    fn tick(&mut self) {}
+}
+
+impl Clone for Box<dyn BusDevice> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/* Plain memory. If the range is bigger than the memory it's mirrored over the whole range,
+*  so the size has to be a power of 2 (the NES' 2KB show up 4 times in $0000-$1FFF) */
+#[derive(Clone)]
+pub struct Ram {
+    range: RangeInclusive<u16>,
+    memory: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(range: RangeInclusive<u16>, size: usize) -> Self {
+        assert!(size.is_power_of_two(), "RAM size has to be a power of 2");
+        Ram {
+            range,
+            memory: vec![0; size],
+        }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn index(&self, addr: u16) -> usize {
+        /* Wrapping, so an address below the range doesn't panic, it just lands in the mirror */
+        addr.wrapping_sub(*self.range.start()) as usize & (self.memory.len() - 1)
+    }
+}
+
+impl BusDevice for Ram {
+    fn box_clone(&self) -> Box<dyn BusDevice> {
+        Box::new(self.clone())
+    }
+
+    fn ranges(&self) -> Vec<RangeInclusive<u16>> {
+        vec![self.range.clone()]
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.peek(addr)
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        let index = self.index(addr);
+        self.memory[index] = data;
+    }
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[self.index(addr)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BUS;
+
+    /* Counts its ticks */
+    #[derive(Clone, Default)]
+    struct Ticker {
+        ticks: u32,
+    }
+
+    impl BusDevice for Ticker {
+        fn box_clone(&self) -> Box<dyn BusDevice> {
+            Box::new(self.clone())
+        }
+        fn ranges(&self) -> Vec<RangeInclusive<u16>> {
+            Vec::new()
+        }
+        fn read(&mut self, _addr: u16) -> u8 {
+            0
+        }
+        fn write(&mut self, _addr: u16, _data: u8) {}
+        fn peek(&self, _addr: u16) -> u8 {
+            0
+        }
+        fn needs_tick(&self) -> bool {
+            true
+        }
+        fn tick(&mut self) {
+            self.ticks += 1;
+        }
+    }
+
+    #[test]
+    fn ram_is_mirrored_over_its_range() {
+        let mut ram = Ram::new(0x6000..=0x7FFF, 0x0800);
+        ram.write(0x6001, 0x42);
+        for addr in [0x6001, 0x6801, 0x7001, 0x7801] {
+            assert_eq!(ram.read(addr), 0x42);
+        }
+        assert_eq!(ram.memory()[1], 0x42);
+    }
+
+    #[test]
+    fn ram_addresses_outside_the_range_wrap_around() {
+        let mut ram = Ram::new(0x6000..=0x67FF, 0x0800);
+        ram.write(0x6000, 0x42);
+        assert_eq!(ram.peek(0x5800), 0x42);
+        assert_eq!(ram.peek(0x6800), 0x42);
+    }
+
+    #[test]
+    fn the_device_attached_last_wins() {
+        let mut bus = BUS::new();
+        let id = bus.attach_device(Box::new(Ram::new(0x0000..=0x00FF, 0x0100)));
+        bus.write(0x0010, 0x55);
+        assert_eq!(bus.read(0x0010), 0x55);
+        assert_eq!(bus.device::<Ram>(id).unwrap().memory()[0x10], 0x55);
+        /* The built-in RAM (the device underneath) never saw the write */
+        assert_eq!(bus.read(0x0810), 0x00);
+    }
+
+    #[test]
+    fn writes_go_to_the_device_and_the_builtin() {
+        let mut bus = BUS::new();
+        let id = bus.attach_device(Box::new(Ram::new(0x2000..=0x2007, 0x0008)));
+        bus.write(0x2003, 0x10);
+        bus.write(0x2004, 0xAB);
+        assert_eq!(bus.ppu.oam[0x10], 0xAB);
+        assert_eq!(bus.device::<Ram>(id).unwrap().peek(0x2004), 0xAB);
+        /* Reads only go to the device */
+        bus.write(0x2004, 0xCD);
+        assert_eq!(bus.read(0x2004), 0xCD);
+    }
+
+    #[test]
+    fn devices_can_be_looked_at_and_reset_through_the_bus() {
+        let mut bus = BUS::new();
+        let port = bus.attach_device(Box::new(Ram::new(0x6000..=0x7FFF, 0x2000)));
+        bus.write(0x6000, 0x80);
+        assert_eq!(bus.peek(0x6000), 0x80);
+
+        bus.device_mut::<Ram>(port).unwrap().memory_mut().fill(0);
+        assert_eq!(bus.peek(0x6000), 0x00);
+
+        assert!(bus.device::<Ticker>(port).is_none());
+        assert!(bus.device::<Ram>(port + 1).is_none());
+    }
+
+    #[test]
+    fn only_devices_that_need_it_get_ticked() {
+        let mut bus = BUS::new();
+        let ticker = bus.attach_device(Box::new(Ticker::default()));
+        for _ in 0..3 {
+            bus.tick_devices();
+        }
+        assert_eq!(bus.device::<Ticker>(ticker).unwrap().ticks, 3);
+    }
+}
@@ -0,0 +1,185 @@
+/*  disasm.rs
+*   Turns memory back into 6502 assembly, using the same LOOKUP table the CPU runs on.
+*
+*   The disassembler doesn't care where the bytes come from, it only gets a function that reads
+*   one byte. That way it works on the BUS (see BUS::disassemble), on a flat test bus or on a
+*   plain ROM image. Whatever you hand it must not have side effects though: reading $2002 or
+*   $2007 for real changes the PPU, so on the BUS only the read only path is used.
+*
+*   The operands are written the usual way:
+*
+*   LDA #$10    LDA $10    LDA $10,X    LDA $1234    LDA $1234,X    LDA ($10,X)    LDA ($10),Y
+*   JMP ($1234) ASL A      BNE $C0F2 (branches show where they go, not the offset)
+*/
+
+use crate::cpu::{AddrMode, Instruction, Mnemonic, LOOKUP};
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/* One disassembled instruction */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: u16,
+    /* The opcode followed by the operand bytes */
+    pub bytes: Vec<u8>,
+    pub mnemonic: Mnemonic,
+    pub addrmode: AddrMode,
+    /* Unofficial opcodes, nestest.log marks them with a '*' */
+    pub illegal: bool,
+    /* The formatted operand, empty for implied instructions */
+    pub operand: String,
+}
+
+impl DisasmLine {
+    /* The address of the next instruction */
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+
+    /* Just the instruction, "LDA ($10),Y" */
+    pub fn text(&self) -> String {
+        if self.operand.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, self.operand)
+        }
+    }
+}
+
+/* "C000  4C F5 C5  JMP $C5F5", the same columns nestest.log uses */
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(
+            f,
+            "{:04X}  {:<9}{}{}",
+            self.addr,
+            bytes.join(" "),
+            if self.illegal { '*' } else { ' ' },
+            self.text(),
+        )
+    }
+}
+
+/* How many bytes follow the opcode */
+pub fn operand_len(addrmode: AddrMode) -> u16 {
+    match addrmode {
+        AddrMode::IMP => 0,
+        AddrMode::ABS | AddrMode::ABX | AddrMode::ABY | AddrMode::IND => 2,
+        _ => 1,
+    }
+}
+
+/* Disassembles the single instruction at addr */
+pub fn disassemble_one(addr: u16, mut peek: impl FnMut(u16) -> u8) -> DisasmLine {
+    let instruction: &Instruction = &LOOKUP[peek(addr) as usize];
+    let bytes: Vec<u8> = (0..=operand_len(instruction.addrmode))
+        .map(|i| peek(addr.wrapping_add(i)))
+        .collect();
+    let lo = bytes.get(1).copied().unwrap_or(0);
+    let word = ((bytes.get(2).copied().unwrap_or(0) as u16) << 8) | lo as u16;
+
+    let operand = match instruction.addrmode {
+        AddrMode::IMP => match instruction.mnemonic {
+            /* The shifts and rotates use implied addressing for the accumulator */
+            Mnemonic::ASL | Mnemonic::LSR | Mnemonic::ROL | Mnemonic::ROR => "A".to_string(),
+            _ => String::new(),
+        },
+        AddrMode::IMM => format!("#${:02X}", lo),
+        AddrMode::ZP0 => format!("${:02X}", lo),
+        AddrMode::ZPX => format!("${:02X},X", lo),
+        AddrMode::ZPY => format!("${:02X},Y", lo),
+        /* The offset counts from the instruction after the branch */
+        AddrMode::REL => format!("${:04X}", addr.wrapping_add(2).wrapping_add(lo as i8 as u16)),
+        AddrMode::ABS => format!("${:04X}", word),
+        AddrMode::ABX => format!("${:04X},X", word),
+        AddrMode::ABY => format!("${:04X},Y", word),
+        AddrMode::IND => format!("(${:04X})", word),
+        AddrMode::IZX => format!("(${:02X},X)", lo),
+        AddrMode::IZY => format!("(${:02X}),Y", lo),
+    };
+
+    DisasmLine {
+        addr,
+        bytes,
+        mnemonic: instruction.mnemonic,
+        addrmode: instruction.addrmode,
+        illegal: instruction.illegal,
+        operand,
+    }
+}
+
+/* Disassembles every instruction that starts inside range. There is no way to tell code from
+*  data, so we just go from one instruction to the next, the last one can reach past the end. */
+pub fn disassemble(range: RangeInclusive<u16>, mut peek: impl FnMut(u16) -> u8) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    /* u32, otherwise a range that ends at $FFFF would never end */
+    let mut addr = *range.start() as u32;
+    while addr <= *range.end() as u32 {
+        let line = disassemble_one(addr as u16, &mut peek);
+        addr += line.bytes.len() as u32;
+        lines.push(line);
+    }
+    lines
+}
+
+/* Hex addresses on the command line, with or without $ or 0x in front */
+pub fn parse_addr(arg: &str) -> Option<u16> {
+    u16::from_str_radix(arg.trim_start_matches('$').trim_start_matches("0x"), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* Disassembles bytes that are placed at addr */
+    fn disassemble_bytes(addr: u16, bytes: &[u8]) -> DisasmLine {
+        disassemble_one(addr, |a| bytes.get(a.wrapping_sub(addr) as usize).copied().unwrap_or(0))
+    }
+
+    #[test]
+    fn every_addressing_mode_is_written_the_usual_way() {
+        let cases: [(&[u8], &str); 14] = [
+            (&[0xEA], "NOP"),
+            (&[0x0A], "ASL A"),
+            (&[0xA9, 0x10], "LDA #$10"),
+            (&[0xA5, 0x10], "LDA $10"),
+            (&[0xB5, 0x10], "LDA $10,X"),
+            (&[0xB6, 0x10], "LDX $10,Y"),
+            (&[0xD0, 0x04], "BNE $C006"),
+            (&[0xD0, 0xFC], "BNE $BFFE"),
+            (&[0xAD, 0x34, 0x12], "LDA $1234"),
+            (&[0xBD, 0x34, 0x12], "LDA $1234,X"),
+            (&[0xB9, 0x34, 0x12], "LDA $1234,Y"),
+            (&[0x6C, 0x34, 0x12], "JMP ($1234)"),
+            (&[0xA1, 0x10], "LDA ($10,X)"),
+            (&[0xB1, 0x10], "LDA ($10),Y"),
+        ];
+        for (bytes, text) in cases {
+            let line = disassemble_bytes(0xC000, bytes);
+            assert_eq!(line.text(), text);
+            assert_eq!(line.bytes, bytes);
+            assert_eq!(line.next_addr(), 0xC000 + bytes.len() as u16);
+        }
+    }
+
+    #[test]
+    fn lines_use_the_nestest_columns() {
+        assert_eq!(disassemble_bytes(0xC000, &[0x4C, 0xF5, 0xC5]).to_string(), "C000  4C F5 C5  JMP $C5F5");
+        assert_eq!(disassemble_bytes(0xC5FB, &[0xA7, 0x02]).to_string(), "C5FB  A7 02    *LAX $02");
+    }
+
+    #[test]
+    fn disassemble_goes_from_one_instruction_to_the_next() {
+        /* LDA #$01, STA $0200, RTS */
+        let program = [0xA9, 0x01, 0x8D, 0x00, 0x02, 0x60];
+        let lines = disassemble(0x8000..=0x8005, |addr| program[(addr - 0x8000) as usize]);
+        let addrs: Vec<u16> = lines.iter().map(|line| line.addr).collect();
+        assert_eq!(addrs, [0x8000, 0x8002, 0x8005]);
+        assert_eq!(lines[2].text(), "RTS");
+
+        /* A range that ends at $FFFF still ends */
+        assert_eq!(disassemble(0xFFFE..=0xFFFF, |_| 0xEA).len(), 2);
+    }
+}
@@ -0,0 +1,302 @@
+/*  dma.rs
+*   The two DMA units inside the 2A03.
+*
+*   OAM DMA copies a whole page ($xx00-$xxFF) into the PPU's OAM, it's started by writing the
+*   page number to $4014. Practically every game does this once per frame to upload its sprites.
+*   DMC DMA fetches one sample byte for the APU's DMC channel whenever its buffer runs empty.
+*   The APU doesn't have a DMC channel yet, it will call start_dmc() and take dmc_data.
+*
+*   Both of them take the bus away from the CPU, which simply stops for as long as they run:
+*   - The first cycle is always a halt cycle, the CPU gets stopped
+*   - The 2A03 alternates between get (read) and put (write) cycles. Reads can only happen on
+*     get cycles, so if the next cycle is a put one, there's an extra alignment cycle
+*   - OAM DMA then does 256 gets and puts (read from the page, write to $2004), which makes
+*     513 or 514 cycles in total
+*   - DMC DMA needs a dummy cycle after the halt and then does its one get. If it happens during
+*     an OAM DMA the two share the halt, the DMC get steals a get cycle from the OAM DMA and the
+*     OAM DMA needs another alignment cycle to get back on track (usually 2 cycles more in total)
+*
+*   This only decides what happens on every cycle, the BUS does the actual reads and writes
+*   (see BUS::dma_cycle()). The Nes gives a cycle to the DMA instead of the CPU whenever
+*   active() is true. On the real thing the halt only works on a CPU read cycle, here it
+*   always happens on the cycle after the $4014 write, which is only wrong if the write was the
+*   first of the two writes of a read-modify-write instruction.
+*
+*   https://www.nesdev.org/wiki/DMA
+*/
+
+/* What the DMA unit does on one of its cycles */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCycle {
+    /* Halt, dummy and alignment cycles, nothing happens on the bus (the real thing repeats
+    *  whatever read the CPU was about to do, we don't know which one that is) */
+    Idle,
+    /* Read the next OAM byte from addr */
+    OamRead(u16),
+    /* Write the byte that was just read to $2004 */
+    OamWrite(u8),
+    /* Read a sample byte for the DMC from addr */
+    DmcRead(u16),
+}
+
+#[derive(Clone, Default)]
+pub struct DMA {
+    /* Set on the halt cycle, cleared when there is nothing left to do */
+    running: bool,
+
+    /* The page the OAM DMA copies from */
+    oam_page: u8,
+    oam_active: bool,
+    /* Counts the gets and puts of the OAM DMA, gets are even and puts are odd, 512 in total */
+    oam_counter: u16,
+    /* What the last get read, the next put writes it to $2004 */
+    pub oam_data: u8,
+
+    /* The address of the sample byte the DMC wants */
+    dmc_addr: Option<u16>,
+    /* The DMC DMA still needs its halt or dummy cycle before it can read */
+    dmc_need_halt: bool,
+    dmc_need_dummy: bool,
+    /* The byte the DMC DMA fetched, until the APU takes it */
+    pub dmc_data: Option<u8>,
+}
+
+impl DMA {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /* Writing to $4014 */
+    pub fn start_oam(&mut self, page: u8) {
+        self.oam_page = page;
+        self.oam_active = true;
+        self.oam_counter = 0;
+    }
+
+    /* The DMC's sample buffer ran empty */
+    pub fn start_dmc(&mut self, addr: u16) {
+        self.dmc_addr = Some(addr);
+        self.dmc_need_halt = true;
+        self.dmc_need_dummy = true;
+    }
+
+    /* True if the next CPU cycle belongs to the DMA */
+    pub fn active(&self) -> bool {
+        self.oam_active || self.dmc_addr.is_some()
+    }
+
+    /* Decides what the DMA does on the next cycle, get_cycle tells whether it's a get or a put one */
+    pub fn cycle(&mut self, get_cycle: bool) -> DmaCycle {
+        if !self.running {
+            /* The halt cycle, the DMC DMA (if there is one) counts this as its halt as well */
+            self.running = true;
+            self.dmc_need_halt = false;
+            return DmaCycle::Idle;
+        }
+
+        /* If the DMC DMA started while we were already running, its halt and dummy cycle can
+        *  happen while the OAM DMA keeps going */
+        let dmc_ready = !self.dmc_need_halt && !self.dmc_need_dummy;
+        if self.dmc_need_halt {
+            self.dmc_need_halt = false;
+        } else {
+            self.dmc_need_dummy = false;
+        }
+
+        let cycle = if get_cycle {
+            match self.dmc_addr {
+                Some(addr) if dmc_ready => {
+                    self.dmc_addr = None;
+                    DmaCycle::DmcRead(addr)
+                },
+                _ if self.oam_active && self.oam_counter & 1 == 0 => {
+                    let addr = ((self.oam_page as u16) << 8) | (self.oam_counter >> 1);
+                    self.oam_counter += 1;
+                    DmaCycle::OamRead(addr)
+                },
+                _ => DmaCycle::Idle,
+            }
+        } else if self.oam_active && self.oam_counter & 1 == 1 {
+            self.oam_counter += 1;
+            if self.oam_counter == 512 {
+                self.oam_active = false;
+            }
+            DmaCycle::OamWrite(self.oam_data)
+        } else {
+            DmaCycle::Idle
+        };
+
+        if !self.active() {
+            self.running = false;
+        }
+        cycle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BUS;
+    use crate::{Cartridge, Nes};
+
+    /* Runs the DMA until it's done, starting on a get or a put cycle. start_dmc_at starts a DMC
+    *  DMA in front of that cycle. Returns every cycle it did */
+    fn run_dma(dma: &mut DMA, first_cycle_is_get: bool, start_dmc_at: Option<usize>) -> Vec<DmaCycle> {
+        let mut cycles = Vec::new();
+        let mut get_cycle = first_cycle_is_get;
+        loop {
+            if start_dmc_at == Some(cycles.len()) {
+                dma.start_dmc(0xC000);
+            }
+            if !dma.active() {
+                break;
+            }
+            cycles.push(dma.cycle(get_cycle));
+            get_cycle = !get_cycle;
+        }
+        cycles
+    }
+
+    fn run_oam_dma(first_cycle_is_get: bool, start_dmc_at: Option<usize>) -> Vec<DmaCycle> {
+        let mut dma = DMA::new();
+        dma.start_oam(0x02);
+        run_dma(&mut dma, first_cycle_is_get, start_dmc_at)
+    }
+
+    fn oam_reads(cycles: &[DmaCycle]) -> Vec<u16> {
+        cycles.iter().filter_map(|cycle| match cycle {
+            DmaCycle::OamRead(addr) => Some(*addr),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn oam_dma_takes_513_cycles_when_the_halt_is_a_put_cycle() {
+        let cycles = run_oam_dma(false, None);
+        assert_eq!(cycles.len(), 513);
+        assert_eq!(cycles[0], DmaCycle::Idle);
+        assert_eq!(cycles[1], DmaCycle::OamRead(0x0200));
+    }
+
+    #[test]
+    fn oam_dma_takes_514_cycles_when_the_halt_is_a_get_cycle() {
+        let cycles = run_oam_dma(true, None);
+        assert_eq!(cycles.len(), 514);
+        /* Halt, then one alignment cycle to get to the next get cycle */
+        assert_eq!(cycles[..3], [DmaCycle::Idle, DmaCycle::Idle, DmaCycle::OamRead(0x0200)]);
+    }
+
+    #[test]
+    fn oam_dma_reads_the_page_in_order_and_writes_after_every_read() {
+        let cycles = run_oam_dma(false, None);
+        assert_eq!(oam_reads(&cycles), (0x0200..=0x02FF).collect::<Vec<u16>>());
+        for pair in cycles[1..].chunks(2) {
+            assert!(matches!(pair, [DmaCycle::OamRead(_), DmaCycle::OamWrite(_)]));
+        }
+    }
+
+    #[test]
+    fn dmc_dma_takes_3_or_4_cycles() {
+        /* Halt, dummy, then the read on the next get cycle */
+        let mut dma = DMA::new();
+        dma.start_dmc(0xC000);
+        assert_eq!(run_dma(&mut dma, true, None), [DmaCycle::Idle, DmaCycle::Idle, DmaCycle::DmcRead(0xC000)]);
+
+        dma.start_dmc(0xC000);
+        assert_eq!(run_dma(&mut dma, false, None), [DmaCycle::Idle, DmaCycle::Idle, DmaCycle::Idle, DmaCycle::DmcRead(0xC000)]);
+    }
+
+    #[test]
+    fn dmc_dma_during_oam_dma_takes_a_get_cycle_and_costs_2_cycles() {
+        for first_cycle_is_get in [false, true] {
+            let plain = run_oam_dma(first_cycle_is_get, None).len();
+            let cycles = run_oam_dma(first_cycle_is_get, Some(101));
+            assert_eq!(cycles.len(), plain + 2);
+
+            /* Its halt and dummy cycle don't stop the OAM DMA, the read comes on the next get */
+            let dmc_read = cycles.iter().position(|&cycle| cycle == DmaCycle::DmcRead(0xC000)).unwrap();
+            assert!((103..=104).contains(&dmc_read));
+            /* The OAM DMA has to wait for the next get cycle to carry on */
+            assert_eq!(cycles[dmc_read + 1], DmaCycle::Idle);
+            assert!(matches!(cycles[dmc_read - 1], DmaCycle::OamWrite(_)));
+            assert!(matches!(cycles[dmc_read + 2], DmaCycle::OamRead(_)));
+            assert_eq!(oam_reads(&cycles), (0x0200..=0x02FF).collect::<Vec<u16>>());
+        }
+    }
+
+    #[test]
+    fn dmc_and_oam_dma_share_the_halt_cycle() {
+        let cycles = run_oam_dma(false, Some(0));
+        assert_eq!(cycles.len(), 515);
+        assert_eq!(cycles[..5], [
+            DmaCycle::Idle,
+            DmaCycle::OamRead(0x0200),
+            DmaCycle::OamWrite(0),
+            DmaCycle::DmcRead(0xC000),
+            DmaCycle::Idle,
+        ]);
+    }
+
+    /* Runs the BUS's DMA cycles until it's done, returns how many there were */
+    fn run_bus_dma(bus: &mut BUS) -> usize {
+        let mut get_cycle = true;
+        let mut cycles = 0;
+        while bus.dma.active() {
+            bus.dma_cycle(get_cycle);
+            get_cycle = !get_cycle;
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn bus_copies_the_page_into_oam() {
+        let mut bus = BUS::new();
+        for i in 0..256u16 {
+            bus.write(0x0300 + i, i as u8 ^ 0x5A);
+        }
+        bus.write(0x2003, 0x00);
+        bus.write(0x4014, 0x03);
+
+        assert_eq!(run_bus_dma(&mut bus), 514);
+        for i in 0..256 {
+            assert_eq!(bus.ppu.oam[i], i as u8 ^ 0x5A);
+        }
+    }
+
+    #[test]
+    fn bus_hands_the_sample_byte_to_the_dmc() {
+        let mut bus = BUS::new();
+        bus.write(0x0123, 0x77);
+        bus.dma.start_dmc(0x0123);
+        assert_eq!(run_bus_dma(&mut bus), 3);
+        assert_eq!(bus.dma.dmc_data, Some(0x77));
+    }
+
+    /* STA $4014 takes 4 cycles, the DMA 513 or 514 more depending on which cycle it starts on */
+    #[test]
+    fn sta_4014_stalls_the_cpu() {
+        /* LDA #$02, STA $4014, JMP to itself */
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..8].copy_from_slice(&[0xA9, 0x02, 0x8D, 0x14, 0x40, 0x4C, 0x05, 0x80]);
+        prg[0x3FFC] = 0x00;
+        prg[0x3FFD] = 0x80;
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+
+        let mut cycles = Vec::new();
+        for parity in 0..2 {
+            let mut nes = Nes::new();
+            nes.insert_cartridge(Cartridge::from_bytes(&rom).unwrap());
+            nes.reset();
+            while nes.cpu.pc != 0x8002 {
+                nes.step_instruction();
+            }
+            nes.bus.apu.cycle += parity;
+            cycles.push(nes.step_instruction().cpu_cycles);
+        }
+        cycles.sort();
+        assert_eq!(cycles, [517, 518]);
+    }
+}
@@ -0,0 +1,67 @@
+/*  flat_bus.rs
+*   A plain 64KB of RAM that the CPU can be connected to instead of the BUS.
+*
+*   There are no mirrors, no PPU and no cartridge, every address is just a byte of memory.
+*   Every read and write the CPU does is recorded (unless recording is turned off), so test
+*   runners can compare the bus activity of an instruction cycle by cycle.
+*/
+
+use crate::bus::CpuBus;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/* One cycle worth of bus activity */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub data: u8,
+    pub kind: AccessKind,
+}
+
+#[derive(Clone)]
+pub struct FlatBus {
+    pub ram: Vec<u8>,
+    pub accesses: Vec<BusAccess>,
+    /* Turn this off for long runs, accesses would grow forever otherwise */
+    pub recording: bool,
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus {
+            ram: vec![0u8; 0x10000],
+            accesses: Vec::new(),
+            recording: true,
+        }
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CpuBus for FlatBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.ram[addr as usize];
+        if self.recording {
+            self.accesses.push(BusAccess { addr, data, kind: AccessKind::Read });
+        }
+        data
+    }
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+        if self.recording {
+            self.accesses.push(BusAccess { addr, data, kind: AccessKind::Write });
+        }
+    }
+    /* Peeks aren't bus activity, so they aren't recorded */
+    fn peek(&self, addr: u16) -> u8 {
+        self.ram[addr as usize]
+    }
+}
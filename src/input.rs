@@ -0,0 +1,57 @@
+/*  input.rs
+*   The standard NES controller.
+*
+*   Writing 1 to bit 0 of $4016 makes both controllers keep loading the state of their buttons
+*   (strobe), writing 0 freezes it. After that every read of $4016 (controller 1) or $4017
+*   (controller 2) shifts out one button in bit 0, in the order of the BUTTON_ constants below.
+*   Once all 8 are out an official controller keeps returning 1.
+*/
+
+pub const BUTTON_A: u8 = 0b00000001;
+pub const BUTTON_B: u8 = 0b00000010;
+pub const BUTTON_SELECT: u8 = 0b00000100;
+pub const BUTTON_START: u8 = 0b00001000;
+pub const BUTTON_UP: u8 = 0b00010000;
+pub const BUTTON_DOWN: u8 = 0b00100000;
+pub const BUTTON_LEFT: u8 = 0b01000000;
+pub const BUTTON_RIGHT: u8 = 0b10000000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Controller {
+    /* The buttons that are held down right now, set by the frontend */
+    pub buttons: u8,
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Controller::default()
+    }
+
+    /* Bit 0 of a write to $4016 */
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        /* While the strobe is on the shift register keeps getting reloaded, so it's always A */
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        let data = self.shift & 0x01;
+        self.shift = (self.shift >> 1) | 0x80;
+        data
+    }
+
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons & 0x01
+        } else {
+            self.shift & 0x01
+        }
+    }
+}
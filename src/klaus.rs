@@ -0,0 +1,170 @@
+/*  klaus.rs
+*   Runs Klaus Dormann's 6502 test images (https://github.com/Klaus2m5/6502_65C02_functional_tests)
+*   against our CPU, connected to a FlatBus instead of the BUS.
+*
+*   The tests don't print anything, they just loop forever on the same instruction ("trap") when
+*   something goes wrong, and on a different one when everything went well. So we run the CPU
+*   until pc stops changing and look at where it got stuck:
+*
+*   - 6502_functional_test.bin tests every official instruction (decimal mode too, so the CPU runs
+*     as an NMOS 6502). It keeps the number of the current test in $0200, which is $F0 once all of
+*     them are done.
+*   - 6502_interrupt_test.bin tests IRQ, NMI and BRK. It fires the interrupts itself through a
+*     feedback port at $BFFC (bit 0 drives IRQ, bit 1 NMI). There is no test number, so you have
+*     to give it the address of its success loop (look for "success" in the .lst file).
+*   - 6502_decimal_test.bin checks ADC and SBC in decimal mode for all inputs. It doesn't loop at
+*     the end but stops with a 65C02 STP ($DB) or a BRK, depending on how it was built, neither of
+*     which stops an NMOS 6502. So we stop in front of them and the result is in $000B (0 means passed).
+*/
+
+use crate::bus::CpuBus;
+use crate::cpu::{CpuVariant, CPU, ICPU, IRQ_SOURCE_MAPPER};
+use crate::flat_bus::FlatBus;
+
+use std::fs;
+
+/* The functional test takes about 100 million cycles, if we get here something is broken */
+const MAX_CYCLES: u64 = 500_000_000;
+
+/* Where the functional test keeps the number of the running test, and what it is at the end */
+const TEST_CASE: u16 = 0x0200;
+const ALL_TESTS_DONE: u8 = 0xF0;
+
+/* The interrupt test's feedback port */
+const INTERRUPT_PORT: u16 = 0xBFFC;
+const INTERRUPT_PORT_IRQ: u8 = 0b00000001;
+const INTERRUPT_PORT_NMI: u8 = 0b00000010;
+
+/* The decimal test's result, and the opcodes it can end with (STP on the 65C02 and BRK) */
+const DECIMAL_ERROR: u16 = 0x000B;
+const DECIMAL_END_OPCODES: [u8; 2] = [0xDB, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KlausTest {
+    Functional,
+    Interrupt,
+    Decimal,
+}
+
+impl KlausTest {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "functional" => Some(KlausTest::Functional),
+            "interrupt" => Some(KlausTest::Interrupt),
+            "decimal" => Some(KlausTest::Decimal),
+            _ => None,
+        }
+    }
+
+    /* Where the image goes, the functional and interrupt tests are a full 64KB image */
+    fn load_addr(self) -> u16 {
+        match self {
+            KlausTest::Functional | KlausTest::Interrupt => 0x0000,
+            KlausTest::Decimal => 0x0200,
+        }
+    }
+
+    fn start_addr(self) -> u16 {
+        match self {
+            KlausTest::Functional | KlausTest::Interrupt => 0x0400,
+            KlausTest::Decimal => 0x0200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KlausOutcome {
+    Passed,
+    /* Stuck somewhere that isn't the success loop */
+    Trapped,
+    /* The CPU ran into a JAM opcode */
+    Jammed,
+    /* pc never stopped changing */
+    TimedOut,
+}
+
+pub struct KlausReport {
+    pub outcome: KlausOutcome,
+    /* Where the CPU got stuck */
+    pub pc: u16,
+    /* The test that was running, only the functional test has numbers */
+    pub test_case: Option<u8>,
+    pub cycles: u64,
+}
+
+/* Loads the image, runs it until it gets stuck and works out if that was the success loop.
+*  success is the address of the success loop, the functional test can do without it. */
+pub fn run(test: KlausTest, image_file: &str, success: Option<u16>) -> Result<KlausReport, String> {
+    if test == KlausTest::Interrupt && success.is_none() {
+        return Err("The interrupt test needs the address of its success loop".to_string());
+    }
+
+    let image = fs::read(image_file).map_err(|e| format!("Error opening {}: {}", image_file, e))?;
+    let load_addr = test.load_addr() as usize;
+    if load_addr + image.len() > 0x10000 {
+        return Err(format!("{} doesn't fit into 64KB at ${:04X}", image_file, load_addr));
+    }
+
+    let mut bus = FlatBus::new();
+    bus.recording = false;
+    bus.ram[load_addr..load_addr + image.len()].copy_from_slice(&image);
+    if test == KlausTest::Interrupt {
+        bus.ram[INTERRUPT_PORT as usize] = 0;
+    }
+
+    let mut cpu = CPU::new();
+    cpu.variant = CpuVariant::Nmos6502;
+    cpu.pc = test.start_addr();
+    cpu.sp = 0xFD;
+    cpu.status_flags = 0x24;
+
+    let mut last_pc = cpu.pc;
+    let mut cycles: u64 = 0;
+    let outcome = loop {
+        if cycles >= MAX_CYCLES {
+            break KlausOutcome::TimedOut;
+        }
+        cpu.clock(&mut bus);
+        cycles += 1;
+
+        if test == KlausTest::Interrupt {
+            let port = bus.ram[INTERRUPT_PORT as usize];
+            cpu.set_irq(IRQ_SOURCE_MAPPER, (port & INTERRUPT_PORT_IRQ) != 0);
+            cpu.set_nmi((port & INTERRUPT_PORT_NMI) != 0);
+        }
+
+        if cpu.jammed {
+            break KlausOutcome::Jammed;
+        }
+        if !cpu.at_instruction_boundary() {
+            continue;
+        }
+        if test == KlausTest::Decimal && DECIMAL_END_OPCODES.contains(&bus.peek(cpu.pc)) {
+            let passed = bus.ram[DECIMAL_ERROR as usize] == 0;
+            break if passed { KlausOutcome::Passed } else { KlausOutcome::Trapped };
+        }
+        /* Every trap is a branch or jump to itself, so the same pc twice in a row means we're stuck */
+        if cpu.pc != last_pc {
+            last_pc = cpu.pc;
+            continue;
+        }
+
+        let passed = match (test, success) {
+            (_, Some(success)) => cpu.pc == success,
+            (KlausTest::Functional, None) => bus.ram[TEST_CASE as usize] == ALL_TESTS_DONE,
+            (KlausTest::Interrupt, None) | (KlausTest::Decimal, None) => false,
+        };
+        break if passed { KlausOutcome::Passed } else { KlausOutcome::Trapped };
+    };
+
+    let test_case = match test {
+        KlausTest::Functional => Some(bus.ram[TEST_CASE as usize]),
+        _ => None,
+    };
+    Ok(KlausReport {
+        outcome,
+        pc: cpu.pc,
+        test_case,
+        cycles,
+    })
+}
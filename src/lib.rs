@@ -0,0 +1,44 @@
+/*  lib.rs
+*   The emulator as a library, so frontends and tools can use it as a dependency.
+*
+*   Nes (nes.rs) is the whole machine, Cartridge (cartridge.rs) loads .nes files, and input.rs,
+*   video.rs and audio.rs are how buttons go in and the picture and sound come out. palette.rs
+*   turns the picture into RGB.
+*   The binaries in src/main.rs and src/bin are thin wrappers around this.
+*/
+
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod apu;
+pub mod audio;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod device;
+pub mod disasm;
+pub mod dma;
+pub mod input;
+pub mod mapper;
+pub mod mapper000;
+pub mod nes;
+pub mod palette;
+pub mod ppu;
+pub mod region;
+pub mod video;
+
+/* Test harnesses and tools, used by the binaries */
+pub mod benchmark;
+pub mod flat_bus;
+pub mod klaus;
+pub mod nestest;
+pub mod single_step;
+
+pub use audio::AudioOutput;
+pub use cartridge::{Cartridge, ICartridge, RomInfo};
+pub use cpu::ICPU;
+pub use device::BusDevice;
+pub use nes::{Nes, RunEvents};
+pub use palette::Palette;
+pub use ppu::IPPU;
+pub use region::Region;
+pub use video::VideoOutput;
@@ -1,108 +1,124 @@
-mod bus;
-use bus::BUS;
-mod cpu;
-use cpu::CPU;
-mod ppu;
-use ppu::PPU;
-mod cartridge;
-use cartridge::Cartridge;
-mod mapper;
-mod mapper000;
+/*  main.rs
+*   The headless runner, it runs a ROM for a number of frames without a window or sound.
+*   Everything else lives in the library (lib.rs), the other tools are in src/bin.
+*/
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::rc::Weak;
-use crate::cpu::ICPU;
-use crate::cpu::Instruction;
+use nes_emulator::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use nes_emulator::{benchmark, Cartridge, Nes, Palette};
 
-use crate::ppu::IPPU;
-use crate::cartridge::ICartridge;
-use crate::mapper000::Mapper000;
-use crate::mapper::IMapper;
+use std::fs;
 
 fn main() {
-    // let pbus = BUS::new();
-    // let pcpu = CPU::new();
-    // let pppu = PPU::new();
-    // pcpu.borrow_mut().connect_bus(&Rc::new(pbus.clone()));
-    //
-    // pbus.borrow_mut().cpu = Some(pcpu); 
-    // pbus.borrow_mut().ppu = Some(pppu); 
-    //
-    // let cartridge: Rc<RefCell<Cartridge>> = Cartridge::new("nestest.nes");
-    //
-    // let cartridge_dyn: Rc<RefCell<dyn ICartridge>> = cartridge;
-    //
-    // // Wrap the trait object in another Rc:
-    // let cartridge_double: Rc<Rc<RefCell<dyn ICartridge>>> = Rc::new(cartridge_dyn);
-    //
-    // pbus.borrow_mut().insert_cartridge(&cartridge_double);   
-    //
-    // loop{
-    //     // pbus.borrow_mut().clock(); 
-    //
-    //     // Phase 1: Borrow the bus mutably for state updates.
-    //     let (cpu_clone, ppu_clone) = {
-    //         let mut bus = pbus.borrow_mut();
-    //         bus.n_sys_clockcounter += 1;
-    //         let cpu_clone = bus.cpu.clone();
-    //     let ppu_clone = bus.ppu.clone();
-    //     (cpu_clone, ppu_clone)
-    //   }; // The mutable borrow on `pbus` is dropped here.
-    //
-    //     // Phase 2: Call the clock functions without holding a borrow on `pbus`.
-    //     if let Some(cpu) = cpu_clone {
-    //         cpu.borrow_mut().clock();
-    //         println!("cpu clocked!");
-    //     }
-    //     if let Some(ppu) = ppu_clone {
-    //         ppu.borrow_mut().clock(); 
-    //         println!("ppu clocked!");
-    //     }
-    // }
+    let args: Vec<String> = std::env::args().collect();
 
-    // Initialize bus, CPU, and PPU.
-    let pbus = BUS::new();
-    let pcpu = CPU::new();
-    let pppu = PPU::new();
-    pcpu.borrow_mut().connect_bus(&Rc::new(pbus.clone()));
+    /* Speed measurement: NES-emulator --bench <rom> [million CPU cycles] */
+    if args.len() >= 3 && args[1] == "--bench" {
+        std::process::exit(run_bench(&args[2], args.get(3)));
+    }
 
-    pbus.borrow_mut().cpu = Some(pcpu); 
-    pbus.borrow_mut().ppu = Some(pppu); 
+    /* Headless run: NES-emulator <rom> [frames] [screenshot.ppm] [palette.pal] */
+    if args.len() >= 2 {
+        std::process::exit(run_headless(&args[1], args.get(2), args.get(3), args.get(4)));
+    }
 
-    let cartridge: Rc<RefCell<Cartridge>> = Cartridge::new("nestest.nes");
-    let cartridge_dyn: Rc<RefCell<dyn ICartridge>> = cartridge;
-    let cartridge_double: Rc<Rc<RefCell<dyn ICartridge>>> = Rc::new(cartridge_dyn);
-    pbus.borrow_mut().insert_cartridge(&cartridge_double);   
+    eprintln!("Usage: NES-emulator <rom> [frames] [screenshot.ppm] [palette.pal]");
+    eprintln!("       NES-emulator --bench <rom> [million CPU cycles]");
+    std::process::exit(1);
+}
 
-    // A separate counter to track PPU clocks.
-    let mut clock_counter = 0;
+/* Runs the ROM for the given number of frames (60 if there is none), stops early if the CPU jams or traps.
+*  The last frame can be saved as a screenshot */
+fn run_headless(rom_file: &str, frames: Option<&String>, screenshot: Option<&String>, palette_file: Option<&String>) -> i32 {
+    let frames: u64 = match frames.map(|arg| arg.parse()) {
+        None => 60,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("The number of frames has to be a whole number");
+            return 1;
+        },
+    };
+    let cartridge = match Cartridge::load(rom_file) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+    let palette = match palette_file.map(|file| Palette::load(file)) {
+        None => Palette::default(),
+        Some(Ok(palette)) => palette,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
 
-    loop {
-        {
-            // Begin a mutable borrow block on the bus.
-            let mut bus = pbus.borrow_mut();
-            // Optionally update bus internal clock counter.
-            bus.n_sys_clockcounter += 1;
-            
-            // Clock the PPU.
-            if let Some(ppu) = bus.ppu.clone() {
-                ppu.borrow_mut().clock();
-                println!("ppu clocked!");
-            }
-        } // Mutable borrow on `pbus` ends here.
+    let mut nes = Nes::new();
+    nes.insert_cartridge(cartridge);
+    /* The CPU can only read the reset vector once the cartridge is inserted */
+    nes.reset();
 
-        // Increment the counter after the PPU has clocked.
-        clock_counter += 1;
+    let mut cpu_cycles = 0;
+    for frame in 1..=frames {
+        let events = nes.run_frame();
+        cpu_cycles += events.cpu_cycles;
+        if events.jammed {
+            println!("{}: CPU jammed at ${:04X} in frame {}", rom_file, nes.cpu.pc, frame);
+            return 1;
+        }
+        if events.trapped {
+            println!("{}: CPU trapped on an unofficial opcode at ${:04X} in frame {}", rom_file, nes.cpu.pc, frame);
+            return 1;
+        }
+    }
+    println!("{}: ran {} frames ({} CPU cycles)", rom_file, frames, cpu_cycles);
 
-        // Every third PPU clock, clock the CPU.
-        if clock_counter % 3 == 0 {
-            // Obtain a new borrow for the CPU clock call.
-            if let Some(cpu) = pbus.borrow().cpu.clone() {
-                // Pass the bus reference if your CPU::clock() requires it.
-                cpu.borrow_mut().clock();
-                println!("cpu clocked!");
-            }
+    if let Some(screenshot) = screenshot {
+        if let Err(e) = save_ppm(screenshot, nes.frame(), &palette) {
+            eprintln!("Error writing {}: {}", screenshot, e);
+            return 1;
         }
     }
+    0
+}
+
+/* PPM is about the simplest image format there is, a tiny header and then RGB */
+fn save_ppm(file_name: &str, frame: &[u16], palette: &Palette) -> std::io::Result<()> {
+    let mut data = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    for pixel in frame {
+        data.extend_from_slice(&palette.rgb(*pixel));
+    }
+    fs::write(file_name, data)
+}
+
+fn run_bench(rom_file: &str, million_cycles: Option<&String>) -> i32 {
+    let million_cycles: u64 = match million_cycles.map(|arg| arg.parse()) {
+        None => 20,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("The number of cycles has to be a whole number (in millions)");
+            return 1;
+        },
+    };
+
+    let result = match benchmark::run(rom_file, million_cycles * 1_000_000) {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        },
+    };
+    if result.events.jammed {
+        println!("{}: CPU jammed, only {} CPU cycles ran", rom_file, result.cpu_cycles);
+    } else if result.events.trapped {
+        println!("{}: CPU trapped on an unofficial opcode, only {} CPU cycles ran", rom_file, result.cpu_cycles);
+    }
+    println!(
+        "{} CPU cycles in {:.2}s: {:.2} MHz emulated, {:.1}x the speed of an NTSC NES",
+        result.cpu_cycles,
+        result.elapsed.as_secs_f64(),
+        result.mhz(),
+        result.mhz() / benchmark::NTSC_CPU_MHZ,
+    );
+    0
 }
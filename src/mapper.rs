@@ -1,39 +1,47 @@
-use std::{cell::RefCell, rc::{Rc, Weak}};
-use crate::bus::BUS;
-
-
-pub struct Mapper{
-    pub prg_banks: u8,
-    pub chr_banks: u8,
-
-    pub bus: Weak<RefCell<BUS>>,
-}
-
-pub trait IMapper{
-    fn new(prg_banks: u8, chr_banks: u8) -> Rc<RefCell<Self>>
+use crate::cartridge::Mirror;
+
+/* Every mapper translates the addresses the CPU and PPU ask for into offsets inside the
+*  cartridge's PRG and CHR memory. If a mapper returns false the address isn't handled by it.
+*
+*  The map functions are called for real bus accesses, so mappers that react to reads can do that
+*  there. The peek versions only translate the address and must not change the mapper.
+*
+*  The cartridge owns its mapper as a Box<dyn IMapper>, box_clone and Send are there because
+*  the Nes has to stay Clone and Send (see nes.rs). */
+pub trait IMapper: Send{
+    fn new(prg_banks: u8, chr_banks: u8) -> Self
     where 
         Self: Sized;
+    fn box_clone(&self) -> Box<dyn IMapper>;
+
+    fn cpu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool;
+    fn cpu_map_write(&mut self, addr: u16, mapped_addr: &mut u32) -> bool;
+    fn ppu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool;
+    fn ppu_map_write(&mut self, addr: u16, mapped_addr: &mut u32) -> bool;
+    fn cpu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool;
+    fn ppu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool;
+
+    /* Mappers that can switch the mirroring return it here, None means the header is right */
+    fn mirror(&self) -> Option<Mirror> {
+        None
+    }
 
-    fn cpu_map_read(&mut self, addr: u16, mapped_addr: u32) -> bool;
-    fn cpu_map_write(&mut self, addr: u16, mapped_addr: u32) -> bool;
-    fn ppu_map_read(&mut self, addr: u16, mapped_addr: u32) -> bool;
-    fn ppu_map_write(&mut self, addr: u16, mapped_addr: u32) -> bool;
+    /* Mappers that put their own memory behind the nametables ($2000-$3EFF, like MMC5's ExRAM
+    *  or the Namco 163) answer here and return true. Everyone else leaves them to the PPU */
+    fn nametable_read(&mut self, _addr: u16, _data: &mut u8) -> bool {
+        false
+    }
+    fn nametable_write(&mut self, _addr: u16, _data: u8) -> bool {
+        false
+    }
+    fn nametable_peek(&self, _addr: u16, _data: &mut u8) -> bool {
+        false
+    }
 
 }
 
-impl IMapper for Mapper{
-    fn new(prg_banks: u8, chr_banks: u8) -> Rc<RefCell<Self>>{
-        Rc::new(RefCell::new(Mapper{
-            bus: Weak::new(),
-            prg_banks,
-            chr_banks,
-        }))
+impl Clone for Box<dyn IMapper> {
+    fn clone(&self) -> Self {
+        self.box_clone()
     }
-
-
-    fn cpu_map_read(&mut self, addr: u16, mut mapped_addr: u32) -> bool{todo!()}
-    fn cpu_map_write(&mut self, addr: u16, mut mapped_addr: u32) -> bool{todo!()}
-    fn ppu_map_read(&mut self, addr: u16, mut mapped_addr: u32) -> bool{todo!()}
-    fn ppu_map_write(&mut self, addr: u16, mut mapped_addr: u32) -> bool{todo!()}
-
 }
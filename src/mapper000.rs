@@ -1,9 +1,7 @@
-use crate::mapper::{IMapper, Mapper};
-
-use std::rc::Rc;
-use std::cell::RefCell;
+use crate::mapper::IMapper;
 
 
+#[derive(Clone)]
 pub struct Mapper000{
     prg_banks: u8,
     chr_banks: u8,
@@ -11,48 +9,67 @@ pub struct Mapper000{
 
 impl IMapper for Mapper000{
 
-    fn new(prg_banks: u8, chr_banks: u8) -> Rc<RefCell<Self>>{
-        Rc::new(RefCell::new(Mapper000{
+    fn new(prg_banks: u8, chr_banks: u8) -> Self{
+        Mapper000{
             prg_banks,
             chr_banks,
-        }))
+        }
+    }
+    fn box_clone(&self) -> Box<dyn IMapper>{
+        Box::new(self.clone())
     }
 
 
-    fn cpu_map_read(&mut self, addr: u16, mut mapped_addr: u32) -> bool{
+    fn cpu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool{
+        /* 16KB carts are mirrored into both halves of $8000-$FFFF */
         if addr >= 0x8000 {
-            mapped_addr = (addr & if self.prg_banks as u8 > 1 { 0x7FFF } else { 0x3FFF }) as u32;
+            *mapped_addr = (addr & if self.prg_banks > 1 { 0x7FFF } else { 0x3FFF }) as u32;
             return true;
         }
 
-        return false;
+        false
+    }
+    fn cpu_map_write(&mut self, _addr: u16, _mapped_addr: &mut u32) -> bool{
+        /* NROM has no registers and no PRG RAM, $8000-$FFFF is ROM. Writes (including the dummy
+        *  writes of read-modify-write instructions) must not change the game's code */
+        false
     }
-    fn cpu_map_write(&mut self, addr: u16, mut mapped_addr: u32) -> bool{
+    fn ppu_map_read(&mut self, addr: u16, mapped_addr: &mut u32) -> bool{
+            
+        if addr <= 0x1FFF{
+            *mapped_addr = addr as u32;
+            return true;
+        }
+
+        false
+    }
+    fn ppu_map_write(&mut self, addr: u16, mapped_addr: &mut u32) -> bool{
         
-        if addr >= 0x8000{
-            mapped_addr = (addr & if self.prg_banks as u8 > 1 { 0x7FFF } else { 0x3FFF }) as u32;
+        /* Only CHR RAM can be written to */
+        if addr <= 0x1FFF && self.chr_banks == 0{
+            *mapped_addr = addr as u32;
             return true;
         }
 
-        return false;
+        false
     }
-    fn ppu_map_read(&mut self, addr: u16, mut mapped_addr: u32) -> bool{
-            
-        if addr >= 0x8000 && addr <= 0x1FFF{
-            mapped_addr = addr as u32;
+
+    /* Mapper 000 has no state at all, peeking is just reading */
+    fn cpu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool{
+        if addr >= 0x8000 {
+            *mapped_addr = (addr & if self.prg_banks > 1 { 0x7FFF } else { 0x3FFF }) as u32;
             return true;
         }
 
-        return false;
+        false
     }
-    fn ppu_map_write(&mut self, addr: u16, mapped_addr: u32) -> bool{
-        
-        // if addr >= 0x8000 && addr <= 0x1FFF{
-        //
-        //     return true;
-        // }
+    fn ppu_map_peek(&self, addr: u16, mapped_addr: &mut u32) -> bool{
+        if addr <= 0x1FFF{
+            *mapped_addr = addr as u32;
+            return true;
+        }
 
-        return false;
+        false
     }
 
 }
@@ -0,0 +1,392 @@
+/*  nes.rs
+*   The whole machine in one struct.
+*
+*   The Nes owns the CPU and the BUS, and the BUS owns everything the CPU can reach (RAM, PPU,
+*   APU and the cartridge). Nothing points back at anything, whoever needs something else for
+*   a clock gets it handed in (the CPU gets the BUS, the PPU gets the cartridge). So there are
+*   no Rc's or RefCell's anywhere, and the whole machine can be cloned (save states, rewind)
+*   or sent to another thread like any other value.
+*
+*   Frontends, test runners and tools drive the machine through the step and run functions,
+*   every one of them stops early when something interesting happens (see RunEvents).
+*/
+
+use crate::apu::IAPU;
+use crate::audio::AudioOutput;
+use crate::bus::BUS;
+use crate::cartridge::Cartridge;
+use crate::cpu::{CPU, ICPU};
+use crate::ppu::IPPU;
+use crate::region::Region;
+use crate::video::VideoOutput;
+
+use std::collections::HashSet;
+
+#[derive(Clone)]
+pub struct Nes {
+    pub cpu: CPU,
+    pub bus: BUS,
+
+    /* Counts PPU clocks since the last reset */
+    pub clock_counter: u64,
+
+    pub region: Region,
+    /* Master clocks since the last CPU cycle, a PAL CPU cycle doesn't take a whole number of
+    *  PPU clocks (see region.rs) */
+    cpu_clock_phase: u8,
+    /* The CPU's NMI input gets looked at on the next dot, see clock() */
+    cpu_clocked_last_dot: bool,
+
+    /* The run functions stop when an instruction at one of these addresses is about to start */
+    pub breakpoints: HashSet<u16>,
+}
+
+/* What happened during a step or run. The run functions stop at the first of these */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RunEvents {
+    /* CPU cycles that were run */
+    pub cpu_cycles: u64,
+    /* The PPU finished a frame */
+    pub frame_completed: bool,
+    /* The CPU is about to run the instruction at a breakpoint */
+    pub breakpoint: Option<u16>,
+    /* The CPU ran into a JAM opcode, nothing but a reset gets it going again */
+    pub jammed: bool,
+    /* The CPU is stopped in front of an unofficial opcode (IllegalOpcodeMode::Trap), until
+    *  cpu.resume_trap() is called */
+    pub trapped: bool,
+}
+
+impl RunEvents {
+    fn stop(&self) -> bool {
+        self.breakpoint.is_some() || self.jammed || self.trapped
+    }
+
+    fn merge(&mut self, other: RunEvents) {
+        self.cpu_cycles += other.cpu_cycles;
+        self.frame_completed |= other.frame_completed;
+        self.breakpoint = self.breakpoint.or(other.breakpoint);
+        self.jammed |= other.jammed;
+        self.trapped |= other.trapped;
+    }
+}
+
+/* If something that isn't Clone or Send ever sneaks into the machine, this stops compiling */
+const _: fn() = || {
+    fn check<T: Clone + Send>() {}
+    check::<Nes>();
+};
+
+impl Nes {
+    pub fn new() -> Self {
+        Nes {
+            cpu: CPU::new(),
+            bus: BUS::new(),
+            clock_counter: 0,
+            region: Region::Ntsc,
+            cpu_clock_phase: 0,
+            cpu_clocked_last_dot: false,
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    /* Call reset() afterwards, the CPU only finds the reset vector once the cartridge is in.
+    *  Switches to the region the cartridge's header asks for, call set_region() afterwards to
+    *  override it */
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.set_region(cartridge.region);
+        self.bus.insert_cartridge(cartridge);
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.bus.ppu.region = region;
+        self.bus.apu.region = region;
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset(&mut self.bus);
+        self.clock_counter = 0;
+        self.cpu_clock_phase = 0;
+        self.cpu_clocked_last_dot = false;
+    }
+
+    /* One PPU clock. The CPU and the APU get clocked every 3 (3.2 on PAL) PPU clocks, returns
+    *  true if they were clocked as well */
+    pub fn clock(&mut self) -> bool {
+        self.bus.ppu.clock(&mut self.bus.cartridge);
+        self.clock_counter += 1;
+        /* The CPU samples /NMI at the end of its cycle, which is a dot after its read or write */
+        if self.cpu_clocked_last_dot {
+            self.cpu.set_nmi(self.bus.ppu.nmi_output());
+        }
+        self.cpu_clocked_last_dot = false;
+
+        self.cpu_clock_phase += self.region.ppu_divider();
+        if self.cpu_clock_phase >= self.region.cpu_divider() {
+            self.cpu_clock_phase -= self.region.cpu_divider();
+            /* While a DMA runs the CPU is halted and the DMA gets its cycles */
+            if self.bus.dma.active() {
+                let get_cycle = self.bus.apu.cycle.is_multiple_of(2);
+                self.bus.dma_cycle(get_cycle);
+            } else {
+                self.cpu.clock(&mut self.bus);
+            }
+            self.bus.apu.clock();
+            self.bus.tick_devices();
+            self.cpu_clocked_last_dot = true;
+            return true;
+        }
+        false
+    }
+
+    /* One CPU cycle, which is three PPU clocks (three or four on PAL) */
+    #[inline]
+    pub fn step_cycle(&mut self) -> RunEvents {
+        let mut events = RunEvents::default();
+        let was_jammed = self.cpu.jammed;
+        let dma_cycle = self.bus.dma.active();
+
+        while !self.clock() {}
+        events.cpu_cycles = 1;
+
+        if self.bus.ppu.frame_complete {
+            self.bus.ppu.frame_complete = false;
+            events.frame_completed = true;
+        }
+
+        /* Only jamming is news, a CPU that was already jammed doesn't stop anything */
+        events.jammed = self.cpu.jammed && !was_jammed;
+        /* A trap on the other hand stays news until somebody resumes it */
+        events.trapped = self.cpu.trapped;
+        /* A DMA cycle leaves the CPU where it was, so it can't reach a breakpoint */
+        if !dma_cycle && !self.breakpoints.is_empty() && self.cpu.at_instruction_boundary() && self.breakpoints.contains(&self.cpu.pc) {
+            events.breakpoint = Some(self.cpu.pc);
+        }
+        events
+    }
+
+    /* Runs the rest of the current instruction (or a whole one if the CPU is in between two).
+    *  An interrupt sequence counts as an instruction here, and so does a DMA the instruction
+    *  started */
+    pub fn step_instruction(&mut self) -> RunEvents {
+        self.run_while(|nes| (!nes.cpu.at_instruction_boundary() && !nes.cpu.jammed) || nes.bus.dma.active())
+    }
+
+    /* Runs until the PPU starts on the given scanline (-1 is the pre-render line). If it's
+    *  already past the start of that scanline that means the one in the next frame. Scanlines
+    *  the PPU doesn't have (anything past 260 on NTSC, 310 on PAL and Dendy) don't run anything */
+    pub fn run_until_scanline(&mut self, scanline: i16) -> RunEvents {
+        if !(-1..self.region.scanlines() - 1).contains(&scanline) {
+            return RunEvents::default();
+        }
+        /* We can only stop every 3 PPU clocks (3 or 4 on PAL), so the start is somewhere in the
+        *  first few dots */
+        let dots = if self.region == Region::Pal { 4 } else { 3 };
+        self.run_while(|nes| nes.bus.ppu.scanline != scanline || nes.bus.ppu.cycle >= dots)
+    }
+
+    /* Runs until the PPU finishes the current frame */
+    pub fn run_frame(&mut self) -> RunEvents {
+        let mut events = RunEvents::default();
+        while !events.frame_completed && !events.stop() {
+            events.merge(self.step_cycle());
+        }
+        events
+    }
+
+    pub fn run_cycles(&mut self, cpu_cycles: u64) -> RunEvents {
+        let mut events = RunEvents::default();
+        while events.cpu_cycles < cpu_cycles && !events.stop() {
+            events.merge(self.step_cycle());
+        }
+        events
+    }
+
+    /* run_frame for frontends, the finished frame and the sound of it go to video and audio */
+    pub fn run_frame_with(&mut self, video: &mut dyn VideoOutput, audio: &mut dyn AudioOutput) -> RunEvents {
+        let events = self.run_frame();
+        if events.frame_completed {
+            video.present_frame(self.frame());
+        }
+        audio.queue_samples(&self.bus.apu.samples);
+        self.bus.apu.samples.clear();
+        events
+    }
+
+    /* The last frame the PPU finished (see video.rs) */
+    pub fn frame(&self) -> &[u16] {
+        &self.bus.ppu.frame_buffer
+    }
+
+    /* port 0 is controller 1, buttons are the input::BUTTON_ constants or'ed together */
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        self.bus.controllers[port].buttons = buttons;
+    }
+
+    /* Steps at least one CPU cycle, then keeps going as long as keep_going says so */
+    fn run_while(&mut self, keep_going: impl Fn(&Nes) -> bool) -> RunEvents {
+        let mut events = self.step_cycle();
+        while keep_going(self) && !events.stop() {
+            events.merge(self.step_cycle());
+        }
+        events
+    }
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::IllegalOpcodeMode;
+    use crate::mapper::IMapper;
+    use crate::mapper000::Mapper000;
+
+    /* A machine with program at $8000 (followed by NOPs) on a 16KB NROM cartridge */
+    fn nes_with(program: &[u8]) -> Nes {
+        let mut cartridge = Cartridge::empty();
+        cartridge.prg_memory = vec![0xEA; 0x4000];
+        cartridge.prg_memory[..program.len()].copy_from_slice(program);
+        /* NMI handler at $9000 */
+        cartridge.prg_memory[0x3FFA] = 0x00;
+        cartridge.prg_memory[0x3FFB] = 0x90;
+        cartridge.prg_memory[0x3FFC] = 0x00;
+        cartridge.prg_memory[0x3FFD] = 0x80;
+        cartridge.chr_memory = vec![0; 0x2000];
+        cartridge.prg_banks = 1;
+        cartridge.chr_banks = 1;
+        cartridge.mapper = Some(Box::new(Mapper000::new(1, 1)));
+        cartridge.image_valid = true;
+
+        let mut nes = Nes::new();
+        nes.insert_cartridge(cartridge);
+        nes.reset();
+        nes.step_instruction();
+        nes
+    }
+
+    #[test]
+    fn a_trap_stops_every_run_until_it_is_resumed() {
+        /* NOP, then the unofficial one byte NOP */
+        let mut nes = nes_with(&[0xEA, 0x1A]);
+        nes.cpu.illegal_opcode_mode = IllegalOpcodeMode::Trap;
+
+        let events = nes.run_frame();
+        assert!(events.trapped);
+        assert!(!events.frame_completed);
+        assert_eq!(nes.cpu.pc, 0x8001);
+        assert!(nes.run_cycles(100).trapped);
+        assert_eq!(nes.cpu.pc, 0x8001);
+
+        nes.cpu.resume_trap();
+        let events = nes.step_instruction();
+        assert!(!events.trapped);
+        assert_eq!(nes.cpu.pc, 0x8002);
+    }
+
+    #[test]
+    fn breakpoints_stop_in_front_of_the_instruction() {
+        let mut nes = nes_with(&[]);
+        nes.breakpoints.insert(0x8010);
+        let events = nes.run_frame();
+        assert_eq!(events.breakpoint, Some(0x8010));
+        assert_eq!(nes.cpu.pc, 0x8010);
+        assert!(nes.cpu.at_instruction_boundary());
+    }
+
+    #[test]
+    fn run_until_scanline_only_runs_to_scanlines_that_exist() {
+        let mut nes = nes_with(&[]);
+        nes.run_until_scanline(100);
+        assert_eq!(nes.bus.ppu.scanline, 100);
+        assert!(nes.bus.ppu.cycle < 3);
+
+        nes.run_until_scanline(-1);
+        assert_eq!(nes.bus.ppu.scanline, -1);
+
+        assert_eq!(nes.run_until_scanline(261), RunEvents::default());
+        assert_eq!(nes.run_until_scanline(-2), RunEvents::default());
+    }
+
+    /* Lines the PPU up so the CPU cycle after the next one lands on vblank's dot (241, 1) plus
+    *  offset and does the register access there, then gives the CPU time to take the NMI */
+    fn access_around_vblank(offset: i16, access: impl FnOnce(&mut BUS)) -> bool {
+        /* JMP $8000 */
+        let mut nes = nes_with(&[0x4C, 0x00, 0x80]);
+        nes.bus.write(0x2000, 0x80);
+        nes.run_until_scanline(240);
+        while !nes.clock() {}
+        nes.bus.ppu.cycle = 340 + offset;
+        nes.bus.ppu.scanline = 240;
+        if nes.bus.ppu.cycle > 340 {
+            nes.bus.ppu.cycle -= 341;
+            nes.bus.ppu.scanline = 241;
+        }
+        /* That's three dots, the last one being (241, 1 + offset) */
+        while !nes.clock() {}
+        assert_eq!((nes.bus.ppu.scanline, nes.bus.ppu.cycle - 1), (241, 1 + offset));
+        access(&mut nes.bus);
+        nes.run_cycles(20);
+        nes.cpu.pc >= 0x9000
+    }
+
+    #[test]
+    fn reading_the_status_around_vblank_can_eat_the_nmi() {
+        let read_status = |offset| {
+            let mut status = 0;
+            let nmi = access_around_vblank(offset, |bus| status = bus.read(0x2002) & 0x80);
+            (status, nmi)
+        };
+        assert_eq!(read_status(-2), (0, true));
+        /* One dot early and vblank doesn't happen at all this frame */
+        assert_eq!(read_status(-1), (0, false));
+        /* Seen and cleared before the CPU sampled the NMI line */
+        assert_eq!(read_status(0), (0x80, false));
+        assert_eq!(read_status(1), (0x80, false));
+        assert_eq!(read_status(2), (0x80, true));
+    }
+
+    #[test]
+    fn turning_nmis_off_right_after_vblank_still_lets_one_through() {
+        let disable = |offset| access_around_vblank(offset, |bus| bus.write(0x2000, 0x00));
+        assert!(!disable(-1));
+        assert!(!disable(0));
+        assert!(!disable(1));
+        assert!(disable(2));
+    }
+
+    /* CPU cycles in each of the next 5 frames, rendering stays off so NTSC doesn't skip dots */
+    fn frame_lengths(region: Region) -> Vec<u64> {
+        /* JMP $8000 */
+        let mut nes = nes_with(&[0x4C, 0x00, 0x80]);
+        nes.set_region(region);
+        nes.run_frame();
+        (0..5).map(|_| nes.run_frame().cpu_cycles).collect()
+    }
+
+    #[test]
+    fn each_region_has_its_own_frame_length() {
+        /* 262 * 341 / 3 = 29780.67 */
+        assert_eq!(frame_lengths(Region::Ntsc), [29781, 29781, 29780, 29781, 29781]);
+        /* 312 * 341 / 3.2 = 33247.5 */
+        assert_eq!(frame_lengths(Region::Pal), [33247, 33248, 33247, 33248, 33247]);
+        /* 312 * 341 / 3 */
+        assert_eq!(frame_lengths(Region::Dendy), [35464; 5]);
+    }
+
+    #[test]
+    fn dendy_starts_vblank_50_lines_later() {
+        let mut nes = nes_with(&[0x4C, 0x00, 0x80]);
+        nes.set_region(Region::Dendy);
+        nes.run_until_scanline(290);
+        assert_eq!(nes.bus.peek(0x2002) & 0x80, 0);
+        nes.run_until_scanline(292);
+        assert_eq!(nes.bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(nes.run_until_scanline(312), RunEvents::default());
+    }
+}
@@ -0,0 +1,239 @@
+/*  nestest.rs
+*   Runs nestest.nes in "automation mode" and compares our CPU against a reference log.
+*
+*   nestest (https://www.qmtpro.com/~nes/misc/nestest.txt) tests pretty much every official and
+*   unofficial instruction. If you start it at $C000 instead of the reset vector it runs all
+*   tests without needing a PPU or controller input. Nintendulator's log of such a run
+*   (nestest.log) is the de facto reference for CPU emulation, every line looks like this:
+*
+*   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+*
+*   We emit the exact same line before every instruction and stop at the first line that differs.
+*/
+
+use crate::bus::CpuBus;
+use crate::cpu::{AddrMode, Mnemonic, CPU};
+use crate::disasm::{self, DisasmLine};
+use crate::nes::Nes;
+use crate::Cartridge;
+
+use std::fs;
+
+/* Where nestest starts when it runs without a screen */
+const AUTOMATION_START: u16 = 0xC000;
+/* After the last test nestest returns with an empty stack, which lands at $0001 */
+const AUTOMATION_END: u16 = 0x0001;
+
+/* nestest.nes starts its automation mode with JMP $C5F5, anything else is some other ROM */
+const AUTOMATION_ENTRY: [u8; 3] = [0x4C, 0xF5, 0xC5];
+
+/* What compare() found out */
+pub struct NestestReport {
+    /* Lines that matched the reference log, up to the first one that didn't */
+    pub matched: usize,
+    pub divergence: Option<Divergence>,
+}
+
+/* The first line where our trace and the reference log disagree */
+pub struct Divergence {
+    pub line: usize,
+    pub expected: String,
+    pub actual: String,
+}
+
+/* Formats the instruction at the CPU's pc together with the CPU and PPU state, exactly like nestest.log */
+pub fn trace_line(cpu: &CPU, bus: &dyn CpuBus, scanline: i16, dot: i16) -> String {
+    let line = disasm::disassemble_one(cpu.pc, |addr| bus.peek(addr));
+
+    format!(
+        "{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        format!("{}{}", line, memory_annotation(cpu, bus, &line)),
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.status_flags,
+        cpu.sp,
+        scanline,
+        dot,
+        cpu.total_cycles,
+    )
+}
+
+/* nestest.log also shows where memory operands end up and what is stored there, which depends
+*  on the registers, so that part isn't in the DisasmLine */
+fn memory_annotation(cpu: &CPU, bus: &dyn CpuBus, line: &DisasmLine) -> String {
+    let lo = line.bytes.get(1).copied().unwrap_or(0);
+    let word = ((line.bytes.get(2).copied().unwrap_or(0) as u16) << 8) | lo as u16;
+    /* Reads a little endian pointer from the zero page, which wraps around inside of it */
+    let read_zp_word = |ptr: u8| -> u16 {
+        ((bus.peek(ptr.wrapping_add(1) as u16) as u16) << 8) | bus.peek(ptr as u16) as u16
+    };
+
+    match line.addrmode {
+        AddrMode::IMP | AddrMode::IMM | AddrMode::REL => String::new(),
+        AddrMode::ZP0 => format!(" = {:02X}", bus.peek(lo as u16)),
+        AddrMode::ZPX => {
+            let target = lo.wrapping_add(cpu.x);
+            format!(" @ {:02X} = {:02X}", target, bus.peek(target as u16))
+        },
+        AddrMode::ZPY => {
+            let target = lo.wrapping_add(cpu.y);
+            format!(" @ {:02X} = {:02X}", target, bus.peek(target as u16))
+        },
+        AddrMode::ABS => match line.mnemonic {
+            /* Jumps don't access memory at their target, so there is no value to show */
+            Mnemonic::JMP | Mnemonic::JSR => String::new(),
+            _ => format!(" = {:02X}", bus.peek(word)),
+        },
+        AddrMode::ABX => {
+            let target = word.wrapping_add(cpu.x as u16);
+            format!(" @ {:04X} = {:02X}", target, bus.peek(target))
+        },
+        AddrMode::ABY => {
+            let target = word.wrapping_add(cpu.y as u16);
+            format!(" @ {:04X} = {:02X}", target, bus.peek(target))
+        },
+        AddrMode::IND => {
+            /* Same page boundary bug as in addrmode_ind */
+            let hi_addr = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = ((bus.peek(hi_addr) as u16) << 8) | bus.peek(word) as u16;
+            format!(" = {:04X}", target)
+        },
+        AddrMode::IZX => {
+            let ptr = lo.wrapping_add(cpu.x);
+            let target = read_zp_word(ptr);
+            format!(" @ {:02X} = {:04X} = {:02X}", ptr, target, bus.peek(target))
+        },
+        AddrMode::IZY => {
+            let base = read_zp_word(lo);
+            let target = base.wrapping_add(cpu.y as u16);
+            format!(" = {:04X} @ {:04X} = {:02X}", base, target, bus.peek(target))
+        },
+    }
+}
+
+/* Boots the ROM at $C000 and calls on_line with every trace line, until on_line returns false,
+*  the CPU jams or nestest is done. Fails if the ROM can't be loaded or isn't nestest.nes */
+pub fn run(rom_file: &str, mut on_line: impl FnMut(String) -> bool) -> Result<(), String> {
+    let mut nes = Nes::new();
+    nes.insert_cartridge(Cartridge::load(rom_file)?);
+    let entry = [0, 1, 2].map(|i| nes.bus.peek(AUTOMATION_START + i));
+    if entry != AUTOMATION_ENTRY {
+        return Err(format!("{} doesn't look like nestest, there is no JMP $C5F5 at $C000", rom_file));
+    }
+    nes.reset();
+    nes.cpu.pc = AUTOMATION_START;
+    /* Let the reset sequence finish first */
+    nes.step_instruction();
+
+    /* Every step ends right before a new instruction starts. pc is only meaningful in between
+    *  instructions, in the middle of one it can hold anything */
+    loop {
+        if nes.cpu.pc == AUTOMATION_END {
+            return Ok(());
+        }
+        let ppu = &nes.bus.ppu;
+        if !on_line(trace_line(&nes.cpu, &nes.bus, ppu.scanline, ppu.cycle)) {
+            return Ok(());
+        }
+        if nes.step_instruction().jammed {
+            return Ok(());
+        }
+    }
+}
+
+/* Runs the ROM and compares every trace line with the reference log. Reports the number of
+*  matching lines and the first line that didn't match. */
+pub fn compare(rom_file: &str, log_file: &str) -> Result<NestestReport, String> {
+    let reference = fs::read_to_string(log_file).map_err(|e| format!("Error opening {}: {}", log_file, e))?;
+    let mut expected_lines = reference.lines().map(|line| line.trim_end());
+
+    let mut matched = 0;
+    let mut divergence = None;
+    run(rom_file, |actual| {
+        match expected_lines.next() {
+            /* Everything in the log matched */
+            None => false,
+            Some(expected) if expected == actual => {
+                matched += 1;
+                true
+            },
+            Some(expected) => {
+                divergence = Some(Divergence {
+                    line: matched + 1,
+                    expected: expected.to_string(),
+                    actual,
+                });
+                false
+            },
+        }
+    })?;
+
+    /* Running out of program before the log ends is a divergence too */
+    if divergence.is_none() {
+        if let Some(expected) = expected_lines.next() {
+            divergence = Some(Divergence {
+                line: matched + 1,
+                expected: expected.to_string(),
+                actual: "<CPU stopped>".to_string(),
+            });
+        }
+    }
+
+    Ok(NestestReport { matched, divergence })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /* Writes an NROM image with program at $C000 (and the rest filled with JAMs) to a temporary file */
+    fn write_rom(name: &str, program: &[(u16, &[u8])]) -> PathBuf {
+        let mut prg = vec![0x02; 0x4000];
+        for (addr, bytes) in program {
+            let offset = (*addr - 0xC000) as usize;
+            prg[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(prg);
+        rom.extend(vec![0; 0x2000]);
+
+        let path = std::env::temp_dir().join(format!("{}-{}.nes", name, std::process::id()));
+        fs::write(&path, rom).unwrap();
+        path
+    }
+
+    #[test]
+    fn trace_lines_look_like_nestest_log() {
+        let rom = write_rom("nestest-trace", &[
+            (0xC000, &[0x4C, 0xF5, 0xC5]),
+            /* LDA #$01, STA $02, LDX $02, LAX $02 */
+            (0xC5F5, &[0xA9, 0x01, 0x85, 0x02, 0xA6, 0x02, 0xA7, 0x02]),
+        ]);
+        let mut lines = Vec::new();
+        let result = run(rom.to_str().unwrap(), |line| {
+            lines.push(line);
+            lines.len() < 5
+        });
+        fs::remove_file(&rom).unwrap();
+
+        assert!(result.is_ok());
+        assert_eq!(lines, [
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+            "C5F5  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+            "C5F7  85 02     STA $02 = 00                    A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 36 CYC:12",
+            "C5F9  A6 02     LDX $02 = 01                    A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 45 CYC:15",
+            "C5FB  A7 02    *LAX $02 = 01                    A:01 X:01 Y:00 P:24 SP:FD PPU:  0, 54 CYC:18",
+        ]);
+    }
+
+    #[test]
+    fn other_roms_are_refused() {
+        let rom = write_rom("nestest-refused", &[(0xC000, &[0xEA, 0xEA, 0xEA])]);
+        let result = run(rom.to_str().unwrap(), |_| true);
+        fs::remove_file(&rom).unwrap();
+
+        assert!(result.unwrap_err().contains("doesn't look like nestest"));
+    }
+}
@@ -0,0 +1,146 @@
+/*  palette.rs
+*   Turns the palette indices in the frame buffer into colours.
+*
+*   The NES doesn't have RGB colours, the PPU generates a video signal straight from the 6 bit
+*   colour number, so every emulator (and every TV) ends up with slightly different colours.
+*   There is a built-in palette, or you can load a .pal file. Those come in two sizes:
+*   - 64 colours (192 bytes), colour emphasis is then done by darkening the other channels
+*   - 512 colours (1536 bytes), one set of 64 for every combination of emphasis bits
+*/
+
+use std::fs;
+
+/* The colours from OneLoneCoder's NES emulator, which is where this project started */
+const DEFAULT_COLOURS: [[u8; 3]; 64] = [
+    [84, 84, 84], [0, 30, 116], [8, 16, 144], [48, 0, 136], [68, 0, 100], [92, 0, 48], [84, 4, 0], [60, 24, 0],
+    [32, 42, 0], [8, 58, 0], [0, 64, 0], [0, 60, 0], [0, 50, 60], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [152, 150, 152], [8, 76, 196], [48, 50, 236], [92, 30, 228], [136, 20, 176], [160, 20, 100], [152, 34, 32], [120, 60, 0],
+    [84, 90, 0], [40, 114, 0], [8, 124, 0], [0, 118, 40], [0, 102, 120], [0, 0, 0], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [76, 154, 236], [120, 124, 236], [176, 98, 236], [228, 84, 236], [236, 88, 180], [236, 106, 100], [212, 136, 32],
+    [160, 170, 0], [116, 196, 0], [76, 208, 32], [56, 204, 108], [56, 180, 204], [60, 60, 60], [0, 0, 0], [0, 0, 0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236], [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180], [160, 214, 228], [160, 162, 160], [0, 0, 0], [0, 0, 0],
+];
+
+/* How much an emphasis bit darkens the channels it doesn't emphasize, roughly what the PPU does */
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/* The emphasis bits of a pixel (bits 6-8), as they are on an NTSC PPU */
+const EMPHASIS_RED: u16 = 0b001000000;
+const EMPHASIS_GREEN: u16 = 0b010000000;
+const EMPHASIS_BLUE: u16 = 0b100000000;
+
+#[derive(Clone)]
+pub struct Palette {
+    /* 64 or 512 entries, see the top of the file */
+    colours: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            colours: DEFAULT_COLOURS.to_vec(),
+        }
+    }
+}
+
+impl Palette {
+    /* A .pal file is nothing but RGB triplets */
+    pub fn from_bytes(data: &[u8]) -> Result<Self, String> {
+        if data.len() != 64 * 3 && data.len() != 512 * 3 {
+            return Err(format!("A palette has 64 or 512 colours (192 or 1536 bytes), not {} bytes", data.len()));
+        }
+        Ok(Palette {
+            colours: data.chunks_exact(3).map(|rgb| [rgb[0], rgb[1], rgb[2]]).collect(),
+        })
+    }
+
+    pub fn load(file_name: &str) -> Result<Self, String> {
+        let data = fs::read(file_name).map_err(|e| format!("Error opening {}: {}", file_name, e))?;
+        Palette::from_bytes(&data).map_err(|e| format!("{}: {}", file_name, e))
+    }
+
+    /* The colour of one pixel from the frame buffer */
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        if self.colours.len() == 512 {
+            return self.colours[(pixel & 0x01FF) as usize];
+        }
+
+        let [r, g, b] = self.colours[(pixel & 0x003F) as usize];
+        if pixel & (EMPHASIS_RED | EMPHASIS_GREEN | EMPHASIS_BLUE) == 0 {
+            return [r, g, b];
+        }
+        let attenuate = |channel: u8, emphasized: u16| -> u8 {
+            /* Every emphasis bit that is set darkens the channels it doesn't belong to */
+            let others = (pixel & (EMPHASIS_RED | EMPHASIS_GREEN | EMPHASIS_BLUE) & !emphasized).count_ones();
+            (channel as f32 * EMPHASIS_ATTENUATION.powi(others as i32)) as u8
+        };
+        [attenuate(r, EMPHASIS_RED), attenuate(g, EMPHASIS_GREEN), attenuate(b, EMPHASIS_BLUE)]
+    }
+
+    /* 4 bytes per pixel, R G B A in that order, alpha is always 255 */
+    pub fn to_rgba8888(&self, frame: &[u16], out: &mut [u8]) {
+        for (pixel, rgba) in frame.iter().zip(out.chunks_exact_mut(4)) {
+            let [r, g, b] = self.rgb(*pixel);
+            rgba.copy_from_slice(&[r, g, b, 0xFF]);
+        }
+    }
+
+    /* 5 bits red, 6 bits green, 5 bits blue */
+    pub fn to_rgb565(&self, frame: &[u16], out: &mut [u16]) {
+        for (pixel, rgb565) in frame.iter().zip(out.iter_mut()) {
+            let [r, g, b] = self.rgb(*pixel);
+            *rgb565 = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_without_emphasis_are_the_palette_colour() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x00), [84, 84, 84]);
+        assert_eq!(palette.rgb(0x16), [152, 34, 32]);
+        assert_eq!(palette.rgb(0x30), [236, 238, 236]);
+    }
+
+    #[test]
+    fn emphasis_darkens_the_other_channels() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30 | EMPHASIS_RED), [236, 194, 192]);
+        assert_eq!(palette.rgb(0x30 | EMPHASIS_RED | EMPHASIS_GREEN), [192, 194, 157]);
+        /* All three darken everything */
+        assert_eq!(palette.rgb(0x30 | EMPHASIS_RED | EMPHASIS_GREEN | EMPHASIS_BLUE), [157, 158, 157]);
+    }
+
+    #[test]
+    fn palette_files_have_64_or_512_colours() {
+        let colours: Vec<u8> = (0..64 * 3).map(|n| n as u8).collect();
+        let palette = Palette::from_bytes(&colours).unwrap();
+        assert_eq!(palette.rgb(0x01), [3, 4, 5]);
+
+        /* With 512 colours the emphasis bits pick the set */
+        let colours: Vec<u8> = (0..512 * 3).map(|n| (n / 3) as u8).collect();
+        let palette = Palette::from_bytes(&colours).unwrap();
+        assert_eq!(palette.rgb(0x41), [0x41, 0x41, 0x41]);
+
+        assert!(Palette::from_bytes(&[0; 100]).is_err());
+    }
+
+    #[test]
+    fn frames_turn_into_rgba_and_rgb565() {
+        let palette = Palette::default();
+        let frame = [0x16, 0x30];
+
+        let mut rgba = [0u8; 8];
+        palette.to_rgba8888(&frame, &mut rgba);
+        assert_eq!(rgba, [152, 34, 32, 255, 236, 238, 236, 255]);
+
+        let mut rgb565 = [0u16; 2];
+        palette.to_rgb565(&frame, &mut rgb565);
+        assert_eq!(rgb565, [(19 << 11) | (8 << 5) | 4, (29 << 11) | (59 << 5) | 29]);
+    }
+}
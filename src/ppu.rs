@@ -1,136 +1,1358 @@
+/*  ppu.rs
+*   The 2C02, the chip that draws the picture. The CPU talks to it through the 8 registers at
+*   $2000-$2007 (mirrored up to $3FFF by the BUS), the PPU itself has its own 14 bit bus with the
+*   pattern tables on the cartridge, the nametables in VRAM and the palette at $3F00.
+*/
 
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::task::Wake;
-
+use crate::cartridge::Mirror;
+use crate::region::Region;
 use crate::ICartridge;
-use crate::bus::BUS;
+use crate::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
 
-pub struct PPU{
-    //cartridge: Weak<Rc<RefCell<dyn ICartridge>>>,
-    
-    /* New pointer system */
-    pub bus: Weak<RefCell<BUS>>,
-    pub cartridge: Weak<Rc<RefCell<dyn ICartridge>>>, 
+/* PPUCTRL ($2000) */
+const CTRL_NAMETABLE: u8 = 0b00000011;
+const CTRL_INCREMENT_32: u8 = 0b00000100;
+const CTRL_SPRITE_PATTERN: u8 = 0b00001000;
+const CTRL_BACKGROUND_PATTERN: u8 = 0b00010000;
+const CTRL_SPRITE_SIZE_16: u8 = 0b00100000;
+const CTRL_NMI: u8 = 0b10000000;
+
+/* PPUMASK ($2001) */
+const MASK_GREYSCALE: u8 = 0b00000001;
+const MASK_BACKGROUND_LEFT: u8 = 0b00000010;
+const MASK_SPRITES_LEFT: u8 = 0b00000100;
+const MASK_BACKGROUND: u8 = 0b00001000;
+const MASK_SPRITES: u8 = 0b00010000;
+const MASK_EMPHASIS: u8 = 0b11100000;
+
+/* PPUSTATUS ($2002), the lower 5 bits are whatever is left on the PPU's data bus */
+const STATUS_SPRITE_OVERFLOW: u8 = 0b00100000;
+const STATUS_SPRITE_ZERO_HIT: u8 = 0b01000000;
+const STATUS_VBLANK: u8 = 0b10000000;
+
+/* Byte 2 of every sprite in OAM */
+const SPRITE_PALETTE: u8 = 0b00000011;
+const SPRITE_BEHIND_BACKGROUND: u8 = 0b00100000;
+const SPRITE_FLIP_HORIZONTAL: u8 = 0b01000000;
+const SPRITE_FLIP_VERTICAL: u8 = 0b10000000;
 
-    frame_complete: bool,
+/* 341 dots per scanline, lines 0-239 are visible, then the post-render lines, vblank (from
+*  241 on NTSC, see region.rs) and the pre-render line (-1, the last line on most diagrams),
+*  which gets everything ready for line 0 */
+const DOTS_PER_SCANLINE: i16 = 341;
 
-    scanline: i16,
-    cycle: i16,
+/* A bit on the PPU's data bus fades back to 0 about 600ms after it was last driven to 1 */
+const OPEN_BUS_DECAY_FRAMES: u8 = 36;
+
+/* The PPU doesn't hold on to the cartridge, whoever owns both (the BUS) hands it in whenever
+*  the PPU needs to get onto its own bus */
+#[derive(Clone)]
+pub struct PPU{
+    /* Set when the last scanline is done, whoever is waiting for the frame clears it again */
+    pub frame_complete: bool,
+
+    pub scanline: i16,
+    pub cycle: i16,
 
     /* 2KB of VRAM */
     tbl_name: [[u8; 1024]; 2],
     palette: [u8; 32],
+    /* Sprite attribute memory, 64 sprites with 4 bytes each (Y, tile, attributes, X) */
+    pub oam: [u8; 256],
+    /* The (up to) 8 sprites sprite evaluation found for the next scanline */
+    secondary_oam: [u8; 32],
+    sprite_count: u8,
+    /* Sprite 0 is in secondary_oam, and in the shifters for the line that is being drawn */
+    sprite_zero_next: bool,
+    sprite_zero_current: bool,
+
+    /* The last finished picture, one palette index per pixel (see video.rs). The PPU draws into
+    *  draw_buffer and swaps the two when frame_complete gets set, so this one never has half
+    *  of the next frame in it */
+    pub frame_buffer: Vec<u16>,
+    draw_buffer: Vec<u16>,
     /* unnecessary */ 
     // tbl_pattern: [[u8; 4096]; 2],
+
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+
+    /* The "loopy" registers (named after the person who figured them out):
+    *  v is the current VRAM address, t the temporary one (the top left of the screen during
+    *  rendering), x the fine X scroll and w the write toggle shared by $2005 and $2006 */
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+
+    /* Decides the number of scanlines, where vblank starts and a few other things */
+    pub region: Region,
+    /* Every other frame is one dot shorter while rendering on NTSC, see clock() */
+    pub odd_frame: bool,
+    /* This frame's odd_frame dot skip, see clock() */
+    skip_last_dot: bool,
+    /* $2002 was read right before vblank would have started, so it doesn't this frame */
+    suppress_vblank: bool,
+
+    /* $2007 reads are delayed by one read, except for the palette */
+    data_buffer: u8,
+
+    /* The background pipeline: the next tile gets fetched into the bg_next_ latches over 8 dots
+    *  while the shifters feed the current one out pixel by pixel. The pattern shifters hold
+    *  two tiles, the attribute shifters hold the palette bits stretched out to 8 pixels */
+    bg_next_tile_id: u8,
+    bg_next_tile_attrib: u8,
+    bg_next_tile_lsb: u8,
+    bg_next_tile_msb: u8,
+    bg_shifter_pattern_lo: u16,
+    bg_shifter_pattern_hi: u16,
+    bg_shifter_attrib_lo: u16,
+    bg_shifter_attrib_hi: u16,
+
+    /* The sprites on the line that is being drawn. x counts down to 0, then the pattern
+    *  shifters start shifting the sprite out */
+    sprite_shifter_pattern_lo: [u8; 8],
+    sprite_shifter_pattern_hi: [u8; 8],
+    sprite_attrib: [u8; 8],
+    sprite_x: [u8; 8],
+
+    /* The PPU's data bus, reading a write-only register returns what's left on it. Every bit
+    *  has its own age in frames so it can decay on its own */
+    open_bus: u8,
+    open_bus_age: [u8; 8],
 }
 
 pub trait IPPU {
 
-    fn new() -> Rc<RefCell<Self>>
+    fn new() -> Self
     where 
         Self: Sized;
 
-    /* Functions for accessing the CPU Bus */
-    fn cpu_read(&mut self, addr: u16, read_only: bool) -> u8;
-    fn cpu_write(&mut self, addr: u16, data: u8);
+    /* Functions for accessing the CPU Bus. Reading some registers changes the PPU ($2002 clears
+    *  the vblank flag and the write toggle, $2007 moves the read buffer and the VRAM address),
+    *  cpu_peek returns the same value without any of that for debuggers and the like.
+    *  $2007 goes through to the PPU bus, that's what the cartridge is for */
+    fn cpu_read(&mut self, cartridge: &mut dyn ICartridge, addr: u16) -> u8;
+    fn cpu_write(&mut self, cartridge: &mut dyn ICartridge, addr: u16, data: u8);
+    fn cpu_peek(&self, cartridge: &dyn ICartridge, addr: u16) -> u8;
 
     /* Functions for accessing the PPU Bus */
-    fn ppu_read(&mut self, addr: u16, read_only: bool) -> u8;
-    fn ppu_write(&mut self, addr: u16, data: u8);
+    fn ppu_read(&mut self, cartridge: &mut dyn ICartridge, addr: u16) -> u8;
+    fn ppu_write(&mut self, cartridge: &mut dyn ICartridge, addr: u16, data: u8);
+    fn ppu_peek(&self, cartridge: &dyn ICartridge, addr: u16) -> u8;
 
-    fn connect_cartridge(&mut self, cartridge: &Rc<Rc<RefCell<dyn ICartridge>>>);
-    fn clock(&mut self);
+    /* One dot, the cartridge is for the tile fetches */
+    fn clock(&mut self, cartridge: &mut dyn ICartridge);
 
 }
 
-impl IPPU for PPU{
+impl PPU{
+    /* The PPU's /NMI output: vblank is on and PPUCTRL wants to hear about it. The CPU only reacts
+    *  when this goes from false to true, so turning PPUCTRL bit 7 on during vblank fires another NMI */
+    pub fn nmi_output(&self) -> bool{
+        self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI != 0
+    }
+
+    /* $2007 moves v along by 1 (across) or 32 (down) */
+    fn increment_v(&mut self){
+        /* While rendering, v belongs to the background fetches and gets both scroll increments */
+        if self.rendering_enabled() && self.scanline < 240 {
+            self.increment_scroll_x();
+            self.increment_scroll_y();
+            return;
+        }
+        let increment = if self.ctrl & CTRL_INCREMENT_32 != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(increment) & 0x7FFF;
+    }
+
+    fn rendering_enabled(&self) -> bool{
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    /*  v (and t) are split up like this:
+    *   yyy NN YYYYY XXXXX
+    *   ||| || ||||| +++++-- coarse X scroll (tile column)
+    *   ||| || +++++-------- coarse Y scroll (tile row)
+    *   ||| ++-------------- nametable select
+    *   +++----------------- fine Y scroll (row inside the tile)
+    */
+
+    /* One tile to the right, into the next nametable after the last column */
+    fn increment_scroll_x(&mut self){
+        if !self.rendering_enabled() {
+            return;
+        }
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    /* One pixel down, tile row 29 is the last one before the attributes, so that's where we
+    *  switch nametables. Rows 30 and 31 (only reachable by writing them) wrap without switching */
+    fn increment_scroll_y(&mut self){
+        if !self.rendering_enabled() {
+            return;
+        }
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
 
-    fn new() -> Rc<RefCell<Self>>{
-                Rc::new(RefCell::new(PPU {
-                    bus: Weak::new(),
-                    cartridge: Weak::new(), 
-                    frame_complete: false,
-                    scanline: 0,
-                    cycle: 0,
-                    tbl_name: [[0u8; 1024]; 2],
-                    palette: [0u8; 32],
-        }))
-    }
-
-    fn cpu_read(&mut self, addr: u16, read_only: bool) -> u8{
-        let mut data: u8 = 0x00;
-
-        match data {
-            0x0000 => /* Control */ {},
-            0x0001 => /* Mask */{},
-            0x0002 => /* Status */{},
-            0x0003 => /* OAM Address */{},
-            0x0004 => /* OAM Data */{},
-            0x0005 => /* Scroll */{},
-            0x0006 => /* PPU Address */{},
-            0x0007 => /* PPU Data */{},
+    /* Back to the left edge of the screen (dot 257) */
+    fn transfer_address_x(&mut self){
+        if self.rendering_enabled() {
+            self.v = (self.v & !0x041F) | (self.t & 0x041F);
+        }
+    }
+
+    /* Back to the top of the screen (pre-render line, dots 280-304) */
+    fn transfer_address_y(&mut self){
+        if self.rendering_enabled() {
+            self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+        }
+    }
+
+    /* The fetched tile goes into the low byte of the shifters, behind the one being drawn */
+    fn load_background_shifters(&mut self){
+        self.bg_shifter_pattern_lo = (self.bg_shifter_pattern_lo & 0xFF00) | self.bg_next_tile_lsb as u16;
+        self.bg_shifter_pattern_hi = (self.bg_shifter_pattern_hi & 0xFF00) | self.bg_next_tile_msb as u16;
+        self.bg_shifter_attrib_lo = (self.bg_shifter_attrib_lo & 0xFF00) | if self.bg_next_tile_attrib & 0b01 != 0 { 0x00FF } else { 0x0000 };
+        self.bg_shifter_attrib_hi = (self.bg_shifter_attrib_hi & 0xFF00) | if self.bg_next_tile_attrib & 0b10 != 0 { 0x00FF } else { 0x0000 };
+    }
+
+    fn update_shifters(&mut self){
+        if self.mask & MASK_BACKGROUND != 0 {
+            self.bg_shifter_pattern_lo <<= 1;
+            self.bg_shifter_pattern_hi <<= 1;
+            self.bg_shifter_attrib_lo <<= 1;
+            self.bg_shifter_attrib_hi <<= 1;
+        }
+    }
+
+    /* One step of the 8 dot fetch cycle: nametable, attribute, pattern low, pattern high */
+    fn fetch_background(&mut self, cartridge: &mut dyn ICartridge){
+        match (self.cycle - 1) % 8 {
+            0 => {
+                self.load_background_shifters();
+                self.bg_next_tile_id = self.ppu_read(cartridge, 0x2000 | (self.v & 0x0FFF));
+            },
+            2 => {
+                /* One attribute byte covers 4x4 tiles, 2 bits for each 2x2 quarter of it */
+                let v = self.v;
+                let mut attrib = self.ppu_read(cartridge, 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                if v & 0x0040 != 0 {
+                    attrib >>= 4;
+                }
+                if v & 0x0002 != 0 {
+                    attrib >>= 2;
+                }
+                self.bg_next_tile_attrib = attrib & 0x03;
+            },
+            4 => {
+                let addr = self.background_pattern_addr();
+                self.bg_next_tile_lsb = self.ppu_read(cartridge, addr);
+            },
+            6 => {
+                let addr = self.background_pattern_addr() + 8;
+                self.bg_next_tile_msb = self.ppu_read(cartridge, addr);
+            },
+            7 => self.increment_scroll_x(),
             _ => {},
+        }
+    }
+
+    fn background_pattern_addr(&self) -> u16{
+        let table = if self.ctrl & CTRL_BACKGROUND_PATTERN != 0 { 0x1000 } else { 0x0000 };
+        table + ((self.bg_next_tile_id as u16) << 4) + ((self.v >> 12) & 0x07)
+    }
+
+    /* The background pixel (0-3) and palette (0-3) at the current dot */
+    fn background_pixel(&self) -> (u8, u8){
+        if self.mask & MASK_BACKGROUND == 0 {
+            return (0, 0);
+        }
+        if self.cycle <= 8 && self.mask & MASK_BACKGROUND_LEFT == 0 {
+            return (0, 0);
+        }
+        let bit = 0x8000 >> self.x;
+        let pixel = (((self.bg_shifter_pattern_hi & bit) != 0) as u8) << 1 | ((self.bg_shifter_pattern_lo & bit) != 0) as u8;
+        let palette = (((self.bg_shifter_attrib_hi & bit) != 0) as u8) << 1 | ((self.bg_shifter_attrib_lo & bit) != 0) as u8;
+        (pixel, palette)
+    }
+
+    fn sprite_height(&self) -> i16{
+        if self.ctrl & CTRL_SPRITE_SIZE_16 != 0 { 16 } else { 8 }
+    }
+
+    /* Finds the sprites for the next scanline. The real PPU does this over dots 65-256, we do
+    *  it all at once at dot 257, nothing can look at secondary OAM in between anyway.
+    *  Sprite Y is one less than the first line the sprite is on, so the sprites on the next
+    *  line are the ones where this line - Y is inside the sprite */
+    fn evaluate_sprites(&mut self){
+        self.secondary_oam = [0xFF; 32];
+        self.sprite_count = 0;
+        self.sprite_zero_next = false;
+
+        let height = self.sprite_height();
+        let in_range = |y: u8, scanline: i16| (0..height).contains(&(scanline - y as i16));
+
+        let mut n = 0;
+        while n < 64 {
+            let y = self.oam[n * 4];
+            if in_range(y, self.scanline) {
+                let slot = self.sprite_count as usize * 4;
+                self.secondary_oam[slot..slot + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                if n == 0 {
+                    self.sprite_zero_next = true;
+                }
+                self.sprite_count += 1;
+            }
+            n += 1;
+            if self.sprite_count == 8 {
+                break;
+            }
+        }
+
+        /* With 8 sprites found the PPU keeps looking for a 9th to set the overflow flag, but it
+        *  increments the byte index m together with the sprite index n. So it looks at tile
+        *  numbers, attributes and X positions as if they were Y, and misses real overflows */
+        let mut m = 0;
+        while n < 64 {
+            let y = self.oam[n * 4 + m];
+            if in_range(y, self.scanline) {
+                self.status |= STATUS_SPRITE_OVERFLOW;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0x03;
+        }
+    }
+
+    /* Dots 257-320: 8 dots for each sprite slot, the pattern bytes are fetched on the same dots
+    *  as the background ones. Empty slots fetch tile $FF, mappers watching the bus see that */
+    fn fetch_sprite(&mut self, cartridge: &mut dyn ICartridge){
+        let slot = ((self.cycle - 257) / 8) as usize;
+        let step = (self.cycle - 257) % 8;
+        if step != 4 && step != 6 {
+            return;
+        }
+
+        let used = slot < self.sprite_count as usize;
+        let (y, tile, attrib, x) = if used {
+            let sprite = &self.secondary_oam[slot * 4..slot * 4 + 4];
+            (sprite[0], sprite[1], sprite[2], sprite[3])
+        } else {
+            (0xFF, 0xFF, 0x00, 0xFF)
+        };
 
+        let height = self.sprite_height();
+        let mut row = if used { self.scanline - y as i16 } else { 0 };
+        if attrib & SPRITE_FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
         }
+        let addr = if height == 16 {
+            /* 8x16 sprites pick their pattern table with bit 0 of the tile number */
+            let table = if tile & 0x01 != 0 { 0x1000 } else { 0x0000 };
+            let tile = (tile & 0xFE) as u16 + if row >= 8 { 1 } else { 0 };
+            table + (tile << 4) + (row & 0x07) as u16
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_PATTERN != 0 { 0x1000 } else { 0x0000 };
+            table + ((tile as u16) << 4) + row as u16
+        };
 
-        return data;
+        let mut pattern = self.ppu_read(cartridge, if step == 4 { addr } else { addr + 8 });
+        if !used {
+            pattern = 0;
+        } else if attrib & SPRITE_FLIP_HORIZONTAL != 0 {
+            pattern = pattern.reverse_bits();
+        }
+
+        if step == 4 {
+            self.sprite_shifter_pattern_lo[slot] = pattern;
+        } else {
+            self.sprite_shifter_pattern_hi[slot] = pattern;
+            self.sprite_attrib[slot] = attrib;
+            self.sprite_x[slot] = x;
+        }
+        if slot == 0 {
+            self.sprite_zero_current = self.sprite_zero_next;
+        }
     }
-    fn cpu_write(&mut self, addr: u16, data: u8){
-        match data {
-            0x0000 => /* Control */ {},
-            0x0001 => /* Mask */{},
-            0x0002 => /* Status */{},
-            0x0003 => /* OAM Address */{},
-            0x0004 => /* OAM Data */{},
-            0x0005 => /* Scroll */{},
-            0x0006 => /* PPU Address */{},
-            0x0007 => /* PPU Data */{},
+
+    /* Dots 2-257, every sprite either gets closer or shifts one pixel out */
+    fn update_sprite_shifters(&mut self){
+        if self.mask & MASK_SPRITES == 0 {
+            return;
+        }
+        for slot in 0..8 {
+            if self.sprite_x[slot] > 0 {
+                self.sprite_x[slot] -= 1;
+            } else {
+                self.sprite_shifter_pattern_lo[slot] <<= 1;
+                self.sprite_shifter_pattern_hi[slot] <<= 1;
+            }
+        }
+    }
+
+    /* The first sprite with a visible pixel at the current dot: pixel (0-3), palette (4-7),
+    *  whether it's behind the background, and whether it's the one in slot 0 */
+    fn sprite_pixel(&self) -> (u8, u8, bool, bool){
+        if self.mask & MASK_SPRITES == 0 {
+            return (0, 0, false, false);
+        }
+        if self.cycle <= 8 && self.mask & MASK_SPRITES_LEFT == 0 {
+            return (0, 0, false, false);
+        }
+        for slot in 0..8 {
+            if self.sprite_x[slot] != 0 {
+                continue;
+            }
+            let pixel = ((self.sprite_shifter_pattern_hi[slot] & 0x80) >> 6) | ((self.sprite_shifter_pattern_lo[slot] & 0x80) >> 7);
+            if pixel == 0 {
+                continue;
+            }
+            let attrib = self.sprite_attrib[slot];
+            return (pixel, (attrib & SPRITE_PALETTE) + 4, attrib & SPRITE_BEHIND_BACKGROUND != 0, slot == 0);
+        }
+        (0, 0, false, false)
+    }
+
+    fn draw_pixel(&mut self){
+        let (bg_pixel, bg_palette) = self.background_pixel();
+        let (fg_pixel, fg_palette, behind, slot_zero) = self.sprite_pixel();
+
+        /* Sprite 0 hit: an opaque pixel of sprite 0 on top of an opaque background pixel. It
+        *  doesn't happen at x = 255, and the left clipping hides it like everything else there */
+        if bg_pixel != 0 && fg_pixel != 0 && slot_zero && self.sprite_zero_current && self.cycle != 256 {
+            self.status |= STATUS_SPRITE_ZERO_HIT;
+        }
+
+        let (pixel, palette) = match (bg_pixel, fg_pixel) {
+            (0, 0) => (0, 0),
+            (0, _) => (fg_pixel, fg_palette),
+            (_, 0) => (bg_pixel, bg_palette),
+            _ if behind => (bg_pixel, bg_palette),
+            _ => (fg_pixel, fg_palette),
+        };
+
+        /* Pixel 0 is transparent, all of those show the backdrop colour at $3F00 */
+        let colour_addr = if pixel == 0 { 0x3F00 } else { 0x3F00 + ((palette as u16) << 2) + pixel as u16 };
+        let mut emphasis = self.mask & MASK_EMPHASIS;
+        if self.region.swaps_emphasis() {
+            /* Bit 5 is green and bit 6 is red here, the frame buffer always has red first */
+            emphasis = (emphasis & 0x80) | ((emphasis & 0x20) << 1) | ((emphasis & 0x40) >> 1);
+        }
+        let colour = self.palette_read(colour_addr) as u16 | ((emphasis as u16) << 1);
+        let index = self.scanline as usize * SCREEN_WIDTH + (self.cycle - 1) as usize;
+        self.draw_buffer[index] = colour;
+    }
+
+    /* Puts the bits in drive_mask of data on the PPU's data bus, which refreshes them */
+    fn drive_open_bus(&mut self, data: u8, drive_mask: u8){
+        self.open_bus = (self.open_bus & !drive_mask) | (data & drive_mask);
+        for bit in 0..8 {
+            if drive_mask & data & (1 << bit) != 0 {
+                self.open_bus_age[bit] = 0;
+            }
+        }
+    }
+
+    /* Once a frame, the bits that haven't been driven for long enough fade to 0 */
+    fn decay_open_bus(&mut self){
+        for bit in 0..8 {
+            if self.open_bus & (1 << bit) == 0 {
+                continue;
+            }
+            self.open_bus_age[bit] += 1;
+            if self.open_bus_age[bit] >= OPEN_BUS_DECAY_FRAMES {
+                self.open_bus &= !(1 << bit);
+            }
+        }
+    }
+
+    /* Where a nametable address ($2000-$3EFF) ends up in our 2KB of VRAM: which table and where in it.
+    *  $3000-$3EFF is a mirror of $2000-$2EFF */
+    fn nametable_addr(mirror: Mirror, addr: u16) -> (usize, usize){
+        let addr = addr & 0x0FFF;
+        let table = match mirror {
+            Mirror::Vertical => (addr >> 10) & 0x01,
+            Mirror::Horizontal => (addr >> 11) & 0x01,
+            Mirror::OnescreenLo => 0,
+            Mirror::OnescreenHi => 1,
+            /* The cartridge answers these itself, if it doesn't we fall back to vertical */
+            Mirror::FourScreen => (addr >> 10) & 0x01,
+        };
+        (table as usize, (addr & 0x03FF) as usize)
+    }
+
+    /* Palette RAM is only 6 bits wide, the top 2 bits of a read come from the open bus */
+    fn palette_addr(addr: u16) -> usize{
+        let mut addr = addr & 0x001F;
+        /* The background colour entries of the sprite palettes are the ones of the background */
+        if addr & 0x0013 == 0x0010 {
+            addr &= 0x000F;
+        }
+        addr as usize
+    }
+    fn palette_read(&self, addr: u16) -> u8{
+        let data = self.palette[PPU::palette_addr(addr)] & 0x3F;
+        if self.mask & MASK_GREYSCALE != 0 { data & 0x30 } else { data }
+    }
+}
+
+impl IPPU for PPU{
+
+    fn new() -> Self{
+        PPU {
+            frame_complete: false,
+            scanline: 0,
+            cycle: 0,
+            tbl_name: [[0u8; 1024]; 2],
+            palette: [0u8; 32],
+            oam: [0u8; 256],
+            secondary_oam: [0xFFu8; 32],
+            sprite_count: 0,
+            sprite_zero_next: false,
+            sprite_zero_current: false,
+            frame_buffer: vec![0u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+            draw_buffer: vec![0u16; SCREEN_WIDTH * SCREEN_HEIGHT],
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            region: Region::Ntsc,
+            odd_frame: false,
+            skip_last_dot: false,
+            suppress_vblank: false,
+            data_buffer: 0,
+            bg_next_tile_id: 0,
+            bg_next_tile_attrib: 0,
+            bg_next_tile_lsb: 0,
+            bg_next_tile_msb: 0,
+            bg_shifter_pattern_lo: 0,
+            bg_shifter_pattern_hi: 0,
+            bg_shifter_attrib_lo: 0,
+            bg_shifter_attrib_hi: 0,
+            sprite_shifter_pattern_lo: [0u8; 8],
+            sprite_shifter_pattern_hi: [0u8; 8],
+            sprite_attrib: [0u8; 8],
+            sprite_x: [0xFFu8; 8],
+            open_bus: 0,
+            open_bus_age: [0u8; 8],
+        }
+    }
+
+    fn cpu_read(&mut self, cartridge: &mut dyn ICartridge, addr: u16) -> u8{
+        match addr {
+            0x0002 => /* Status */ {
+                /* cycle is the next dot, so vblank would have been set right after this read. The
+                *  read sees it clear and it stays clear for the whole frame, no NMI either */
+                if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
+                    self.suppress_vblank = true;
+                }
+                let data = (self.status & 0xE0) | (self.open_bus & 0x1F);
+                self.drive_open_bus(data, 0xE0);
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                data
+            },
+            0x0004 => /* OAM Data */ {
+                let mut data = self.oam[self.oam_addr as usize];
+                /* Bits 2-4 of the sprite attributes don't exist */
+                if self.oam_addr & 0x03 == 0x02 {
+                    data &= 0xE3;
+                }
+                self.drive_open_bus(data, 0xFF);
+                data
+            },
+            0x0007 => /* PPU Data */ {
+                /* v has 15 bits, the top one is fine Y scroll and isn't part of the address */
+                let v = self.v & 0x3FFF;
+                let data = if v >= 0x3F00 {
+                    /* The palette answers right away, the buffer gets the nametable underneath */
+                    self.data_buffer = self.ppu_read(cartridge, v - 0x1000);
+                    let data = self.palette_read(v) | (self.open_bus & 0xC0);
+                    self.drive_open_bus(data, 0x3F);
+                    data
+                } else {
+                    let data = self.data_buffer;
+                    self.data_buffer = self.ppu_read(cartridge, v);
+                    self.drive_open_bus(data, 0xFF);
+                    data
+                };
+                self.increment_v();
+                data
+            },
+            /* Control, Mask, OAM Address, Scroll and PPU Address can only be written */
+            _ => self.open_bus,
+        }
+    }
+    fn cpu_write(&mut self, cartridge: &mut dyn ICartridge, addr: u16, data: u8){
+        /* Whatever gets written stays on the data bus, even for $2002 */
+        self.drive_open_bus(data, 0xFF);
+
+        match addr {
+            0x0000 => /* Control */ {
+                self.ctrl = data;
+                /* t: ...GH.. ........ <- d: ......GH */
+                self.t = (self.t & 0x73FF) | (((data & CTRL_NAMETABLE) as u16) << 10);
+            },
+            0x0001 => /* Mask */ {
+                self.mask = data;
+            },
+            0x0002 => /* Status */ {},
+            0x0003 => /* OAM Address */ {
+                self.oam_addr = data;
+            },
+            0x0004 => /* OAM Data */ {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            },
+            0x0005 => /* Scroll */ {
+                if !self.w {
+                    /* t: ....... ...ABCDE <- d: ABCDE..., x: FGH <- d: .....FGH */
+                    self.t = (self.t & 0x7FE0) | ((data >> 3) as u16);
+                    self.x = data & 0x07;
+                } else {
+                    /* t: FGH..AB CDE..... <- d: ABCDEFGH */
+                    self.t = (self.t & 0x0C1F) | (((data & 0x07) as u16) << 12) | (((data & 0xF8) as u16) << 2);
+                }
+                self.w = !self.w;
+            },
+            0x0006 => /* PPU Address */ {
+                if !self.w {
+                    /* t: .CDEFGH ........ <- d: ..CDEFGH, bit 14 of t is cleared */
+                    self.t = (self.t & 0x00FF) | (((data & 0x3F) as u16) << 8);
+                } else {
+                    /* t: ....... ABCDEFGH <- d: ABCDEFGH, then v = t */
+                    self.t = (self.t & 0x7F00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            0x0007 => /* PPU Data */ {
+                let v = self.v;
+                self.ppu_write(cartridge, v, data);
+                self.increment_v();
+            },
             _ => {},
 
         }
     }
 
-    fn ppu_read(&mut self, mut addr: u16, read_only: bool) -> u8{
+    /* Nothing in here may change the PPU, see cpu_read for the registers that do */
+    fn cpu_peek(&self, _cartridge: &dyn ICartridge, addr: u16) -> u8{
+        match addr {
+            0x0002 => /* Status, without clearing vblank and the write toggle */ {
+                (self.status & 0xE0) | (self.open_bus & 0x1F)
+            },
+            0x0004 => /* OAM Data */ {
+                let data = self.oam[self.oam_addr as usize];
+                if self.oam_addr & 0x03 == 0x02 { data & 0xE3 } else { data }
+            },
+            0x0007 => /* PPU Data, the buffer and the address stay where they are */ {
+                if self.v & 0x3FFF >= 0x3F00 {
+                    self.palette_read(self.v) | (self.open_bus & 0xC0)
+                } else {
+                    self.data_buffer
+                }
+            },
+            _ => self.open_bus,
+        }
+    }
+
+    fn ppu_read(&mut self, cartridge: &mut dyn ICartridge, mut addr: u16) -> u8{
         let mut data = 0x00;
         addr &= 0x3FFF;
 
-        let cart = self.cartridge.upgrade().unwrap();
-        
-        if (*cart).borrow_mut().ppu_read(addr, data){
-
+        if cartridge.ppu_read(addr, &mut data) {
+            return data;
+        }
+        if addr >= 0x3F00 {
+            data = self.palette_read(addr);
+        } else if addr >= 0x2000 {
+            let (table, offset) = PPU::nametable_addr(cartridge.mirror(), addr);
+            data = self.tbl_name[table][offset];
         }
 
         data
     }
-    fn ppu_write(&mut self, mut addr: u16, data: u8){
+    fn ppu_peek(&self, cartridge: &dyn ICartridge, mut addr: u16) -> u8{
+        let mut data = 0x00;
         addr &= 0x3FFF;
 
-        let cart = self.cartridge.upgrade().unwrap();
-        
-        //if (**cart).borrow_mut().ppu_write(addr, data){
-        //
-        //}
+        if cartridge.ppu_peek(addr, &mut data) {
+            return data;
+        }
+        if addr >= 0x3F00 {
+            data = self.palette_read(addr);
+        } else if addr >= 0x2000 {
+            let (table, offset) = PPU::nametable_addr(cartridge.mirror(), addr);
+            data = self.tbl_name[table][offset];
+        }
 
+        data
     }
+    fn ppu_write(&mut self, cartridge: &mut dyn ICartridge, mut addr: u16, data: u8){
+        addr &= 0x3FFF;
 
-    fn connect_cartridge(&mut self, cartridge: &Rc<Rc<RefCell<dyn ICartridge>>>){
-        self.cartridge = Rc::downgrade(cartridge);
+        if cartridge.ppu_write(addr, data) {
+            return;
+        }
+        if addr >= 0x3F00 {
+            self.palette[PPU::palette_addr(addr)] = data;
+        } else if addr >= 0x2000 {
+            let (table, offset) = PPU::nametable_addr(cartridge.mirror(), addr);
+            self.tbl_name[table][offset] = data;
+        }
     }
-    fn clock(&mut self){
+    fn clock(&mut self, cartridge: &mut dyn ICartridge){
 
-        /* DrawPixel(cycle -1, scanline); */
+        /* The visible scanlines and the pre-render line (-1) fetch the background */
+        if self.scanline < 240 {
+            if (2..258).contains(&self.cycle) || (321..338).contains(&self.cycle) {
+                self.update_shifters();
+                self.fetch_background(cartridge);
+            }
+            if self.cycle == 256 {
+                self.increment_scroll_y();
+            }
+            if self.cycle == 257 {
+                self.load_background_shifters();
+                self.transfer_address_x();
+            }
+            /* Two nametable fetches nobody uses at the end of the line */
+            if self.cycle == 338 || self.cycle == 340 {
+                self.bg_next_tile_id = self.ppu_read(cartridge, 0x2000 | (self.v & 0x0FFF));
+            }
+            if self.scanline == -1 && (280..305).contains(&self.cycle) {
+                self.transfer_address_y();
+            }
+
+            if self.scanline == -1 && self.cycle == 1 {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO_HIT | STATUS_SPRITE_OVERFLOW);
+            }
+            if (2..258).contains(&self.cycle) && self.scanline >= 0 {
+                self.update_sprite_shifters();
+            }
+            if self.cycle == 257 && self.rendering_enabled() {
+                /* There's no evaluation on the pre-render line, so there are never sprites on line 0 */
+                if self.scanline >= 0 {
+                    self.evaluate_sprites();
+                } else {
+                    self.sprite_count = 0;
+                    self.sprite_zero_next = false;
+                }
+            }
+            if (257..321).contains(&self.cycle) && self.rendering_enabled() {
+                /* OAMADDR is used by the sprite fetches and ends up at 0 */
+                self.oam_addr = 0;
+                self.fetch_sprite(cartridge);
+            }
+        }
+
+        if (0..240).contains(&self.scanline) && (1..=256).contains(&self.cycle) {
+            self.draw_pixel();
+        }
+
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 1 {
+            if !self.suppress_vblank {
+                self.status |= STATUS_VBLANK;
+            }
+            self.suppress_vblank = false;
+        }
 
         self.cycle += 1;
 
-        if self.cycle >= 341{
+        /* On odd NTSC frames the pre-render line skips its last dot when rendering is on. That's
+        *  decided a dot early, turning rendering on or off after dot 338 is too late to matter */
+        if self.scanline == -1 && self.cycle == DOTS_PER_SCANLINE - 2 {
+            self.skip_last_dot = self.odd_frame && self.rendering_enabled() && self.region.skips_odd_frame_dot();
+        }
+        if self.scanline == -1 && self.cycle == DOTS_PER_SCANLINE - 1 && self.skip_last_dot {
+            self.cycle = DOTS_PER_SCANLINE;
+        }
+
+        if self.cycle >= DOTS_PER_SCANLINE{
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline >= 261 {
+            /* The pre-render line is one of them */
+            if self.scanline >= self.region.scanlines() - 1 {
                 self.scanline = -1;
                 self.frame_complete = true;
+                self.odd_frame = !self.odd_frame;
+                std::mem::swap(&mut self.frame_buffer, &mut self.draw_buffer);
+                self.decay_open_bus();
             }
         }
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::mapper::IMapper;
+    use crate::mapper000::Mapper000;
+
+    /* A PPU with an NROM cartridge, every CHR byte holds the low byte of its address */
+    fn setup() -> (PPU, Cartridge) {
+        let mut cartridge = Cartridge::empty();
+        cartridge.prg_memory = vec![0; 0x4000];
+        cartridge.chr_memory = (0..0x2000).map(|addr| addr as u8).collect();
+        cartridge.prg_banks = 1;
+        cartridge.chr_banks = 1;
+        cartridge.mapper = Some(Box::new(Mapper000::new(1, 1)));
+        cartridge.image_valid = true;
+        (PPU::new(), cartridge)
+    }
+
+    fn set_addr(ppu: &mut PPU, cartridge: &mut Cartridge, addr: u16) {
+        ppu.cpu_write(cartridge, 0x0006, (addr >> 8) as u8);
+        ppu.cpu_write(cartridge, 0x0006, addr as u8);
+    }
+
+    #[test]
+    fn scroll_and_address_writes_fill_in_t_v_and_x() {
+        let (mut ppu, mut cart) = setup();
+
+        ppu.cpu_write(&mut cart, 0x0000, 0x02);
+        assert_eq!(ppu.t, 0x0800);
+
+        ppu.cpu_write(&mut cart, 0x0005, 0x7D);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0x080F, 5, true));
+        ppu.cpu_write(&mut cart, 0x0005, 0x5E);
+        assert_eq!((ppu.t, ppu.w), (0x696F, false));
+
+        ppu.cpu_write(&mut cart, 0x0006, 0x3D);
+        assert_eq!((ppu.t, ppu.w), (0x3D6F, true));
+        assert_eq!(ppu.v, 0);
+        ppu.cpu_write(&mut cart, 0x0006, 0xF0);
+        assert_eq!((ppu.t, ppu.v, ppu.w), (0x3DF0, 0x3DF0, false));
+    }
+
+    #[test]
+    fn reading_the_status_clears_vblank_and_the_write_toggle() {
+        let (mut ppu, mut cart) = setup();
+        ppu.status = STATUS_VBLANK;
+        ppu.cpu_write(&mut cart, 0x0006, 0x21);
+
+        assert_eq!(ppu.cpu_peek(&cart, 0x0002) & 0x80, 0x80);
+        assert!(ppu.w);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0002) & 0x80, 0x80);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0002) & 0x80, 0);
+        assert!(!ppu.w);
+
+        /* The toggle starts over, so this is the first write again */
+        ppu.cpu_write(&mut cart, 0x0006, 0x3F);
+        assert_eq!(ppu.t & 0x3F00, 0x3F00);
+        assert!(ppu.w);
+    }
+
+    #[test]
+    fn data_reads_come_one_read_late() {
+        let (mut ppu, mut cart) = setup();
+        set_addr(&mut ppu, &mut cart, 0x0010);
+
+        assert_eq!(ppu.cpu_peek(&cart, 0x0007), 0);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007), 0);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007), 0x10);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007), 0x11);
+        assert_eq!(ppu.v, 0x0013);
+
+        ppu.cpu_write(&mut cart, 0x0000, CTRL_INCREMENT_32);
+        ppu.cpu_read(&mut cart, 0x0007);
+        assert_eq!(ppu.v, 0x0033);
+    }
+
+    #[test]
+    fn palette_reads_come_right_away() {
+        let (mut ppu, mut cart) = setup();
+        set_addr(&mut ppu, &mut cart, 0x3F01);
+        ppu.cpu_write(&mut cart, 0x0007, 0x2C);
+        ppu.cpu_write(&mut cart, 0x0007, 0xFF);
+
+        set_addr(&mut ppu, &mut cart, 0x3F01);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007) & 0x3F, 0x2C);
+        /* Palette RAM is 6 bits wide */
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007) & 0x3F, 0x3F);
+
+        ppu.cpu_write(&mut cart, 0x0001, MASK_GREYSCALE);
+        set_addr(&mut ppu, &mut cart, 0x3F01);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007) & 0x3F, 0x20);
+
+        /* Bit 14 of v isn't part of the address, $7F01 is the palette too */
+        ppu.v = 0x7F01;
+        assert_eq!(ppu.cpu_peek(&cart, 0x0007) & 0x3F, 0x20);
+        /* but $4010 is the pattern table at $0010 */
+        ppu.v = 0x4010;
+        ppu.cpu_read(&mut cart, 0x0007);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0007), 0x10);
+    }
+
+    #[test]
+    fn the_sprite_palettes_share_their_background_colour() {
+        let (mut ppu, mut cart) = setup();
+        for (addr, mirror) in [(0x3F10, 0x3F00), (0x3F14, 0x3F04), (0x3F18, 0x3F08), (0x3F1C, 0x3F0C)] {
+            set_addr(&mut ppu, &mut cart, addr);
+            ppu.cpu_write(&mut cart, 0x0007, (addr & 0x1F) as u8);
+            assert_eq!(ppu.ppu_read(&mut cart, mirror), (addr & 0x1F) as u8);
+        }
+        /* The other sprite colours are their own */
+        ppu.ppu_write(&mut cart, 0x3F11, 0x21);
+        assert_eq!(ppu.ppu_read(&mut cart, 0x3F01), 0x00);
+        assert_eq!(ppu.ppu_read(&mut cart, 0x3F31), 0x21);
+    }
+
+    #[test]
+    fn oam_data_writes_move_the_address_along() {
+        let (mut ppu, mut cart) = setup();
+        ppu.cpu_write(&mut cart, 0x0003, 0x01);
+        ppu.cpu_write(&mut cart, 0x0004, 0x12);
+        ppu.cpu_write(&mut cart, 0x0004, 0xFF);
+        assert_eq!(ppu.oam_addr, 0x03);
+
+        ppu.cpu_write(&mut cart, 0x0003, 0x02);
+        /* Bits 2-4 of the attribute byte don't exist */
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0004), 0xE3);
+        ppu.cpu_write(&mut cart, 0x0003, 0x01);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0004), 0x12);
+    }
+
+    #[test]
+    fn write_only_registers_read_back_the_data_bus() {
+        let (mut ppu, mut cart) = setup();
+        ppu.cpu_write(&mut cart, 0x0000, 0xA5);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0005), 0xA5);
+        /* $2002 only drives the top 3 bits */
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0002), 0x05);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0000), 0x05);
+
+        /* Bits that aren't driven again fade after a while */
+        for _ in 0..OPEN_BUS_DECAY_FRAMES as u32 * 341 * 262 {
+            ppu.clock(&mut cart);
+        }
+        assert_eq!(ppu.cpu_peek(&cart, 0x0003), 0x00);
+    }
+
+    /* Clocks the PPU until it is about to run the given dot */
+    fn run_to(ppu: &mut PPU, cartridge: &mut Cartridge, scanline: i16, cycle: i16) {
+        while ppu.scanline != scanline || ppu.cycle != cycle {
+            ppu.clock(cartridge);
+        }
+    }
+
+    #[test]
+    fn coarse_x_moves_into_the_next_nametable() {
+        let (mut ppu, _) = setup();
+        ppu.mask = MASK_BACKGROUND;
+        ppu.v = 0x001E;
+        ppu.increment_scroll_x();
+        assert_eq!(ppu.v, 0x001F);
+        ppu.increment_scroll_x();
+        assert_eq!(ppu.v, 0x0400);
+        ppu.v = 0x041F;
+        ppu.increment_scroll_x();
+        assert_eq!(ppu.v, 0x0000);
+    }
+
+    #[test]
+    fn fine_y_carries_into_coarse_y_and_the_next_nametable() {
+        let (mut ppu, _) = setup();
+        ppu.mask = MASK_BACKGROUND;
+        ppu.v = 0x6000 | (5 << 5);
+        ppu.increment_scroll_y();
+        assert_eq!(ppu.v, 0x7000 | (5 << 5));
+        ppu.increment_scroll_y();
+        assert_eq!(ppu.v, 6 << 5);
+
+        /* Row 29 is the last one, the next row is the top of the nametable below */
+        ppu.v = 0x7000 | (29 << 5) | 0x0003;
+        ppu.increment_scroll_y();
+        assert_eq!(ppu.v, 0x0800 | 0x0003);
+
+        /* Rows 30 and 31 are the attributes, they wrap to 0 in the same nametable */
+        ppu.v = 0x7800 | (31 << 5);
+        ppu.increment_scroll_y();
+        assert_eq!(ppu.v, 0x0800);
+    }
+
+    #[test]
+    fn scroll_updates_need_rendering_to_be_on() {
+        let (mut ppu, _) = setup();
+        ppu.v = 0x001F;
+        ppu.t = 0x7BFF;
+        ppu.increment_scroll_x();
+        ppu.increment_scroll_y();
+        ppu.transfer_address_x();
+        ppu.transfer_address_y();
+        assert_eq!(ppu.v, 0x001F);
+    }
+
+    #[test]
+    fn t_is_copied_into_v_piece_by_piece() {
+        let (mut ppu, _) = setup();
+        ppu.mask = MASK_SPRITES;
+        ppu.t = 0x7FFF;
+        ppu.transfer_address_x();
+        assert_eq!(ppu.v, 0x041F);
+        ppu.transfer_address_y();
+        assert_eq!(ppu.v, 0x7FFF);
+    }
+
+    #[test]
+    fn rendering_reloads_v_from_t() {
+        let (mut ppu, mut cart) = setup();
+        run_to(&mut ppu, &mut cart, 100, 0);
+        ppu.mask = MASK_BACKGROUND;
+        /* Coarse X 3, coarse Y 7, fine Y 2, second nametable */
+        ppu.t = 0x2000 | 0x0400 | (7 << 5) | 3;
+        ppu.v = 0;
+
+        run_to(&mut ppu, &mut cart, 100, 258);
+        assert_eq!(ppu.v & 0x041F, 0x0403);
+
+        run_to(&mut ppu, &mut cart, -1, 305);
+        assert_eq!(ppu.v & 0x7BE0, 0x2000 | (7 << 5));
+
+        /* The first two tiles of the next line are fetched at the end of this one */
+        run_to(&mut ppu, &mut cart, 0, 0);
+        assert_eq!(ppu.v, ppu.t + 2);
+    }
+
+    #[test]
+    fn data_accesses_while_rendering_bump_both_scroll_counters() {
+        let (mut ppu, mut cart) = setup();
+        run_to(&mut ppu, &mut cart, 100, 0);
+        ppu.mask = MASK_BACKGROUND;
+        ppu.v = 0x1000 | (3 << 5) | 4;
+        ppu.cpu_read(&mut cart, 0x0007);
+        assert_eq!(ppu.v, 0x2000 | (3 << 5) | 5);
+
+        /* Outside of rendering it's the usual 1 or 32 */
+        ppu.mask = 0;
+        ppu.cpu_read(&mut cart, 0x0007);
+        assert_eq!(ppu.v, 0x2000 | (3 << 5) | 6);
+    }
+
+    #[test]
+    fn background_pixels_use_the_pattern_and_the_palette() {
+        let (mut ppu, mut cart) = setup();
+        /* The nametables are empty so every tile is tile 0, make it colour 1 all over */
+        cart.chr_memory[0..8].fill(0xFF);
+        cart.chr_memory[8..16].fill(0x00);
+        ppu.ppu_write(&mut cart, 0x3F00, 0x0F);
+        ppu.ppu_write(&mut cart, 0x3F01, 0x16);
+        ppu.mask = MASK_BACKGROUND | 0x20;
+
+        run_to(&mut ppu, &mut cart, 2, 0);
+        let line = &ppu.draw_buffer[SCREEN_WIDTH..SCREEN_WIDTH * 2];
+        /* The left 8 pixels are hidden, the emphasis bits sit above the colour */
+        assert!(line[..8].iter().all(|&colour| colour == 0x0F | 0x40));
+        assert!(line[8..].iter().all(|&colour| colour == 0x16 | 0x40));
+    }
+
+    /* Puts sprite n into OAM */
+    fn set_sprite(ppu: &mut PPU, n: usize, y: u8, tile: u8, attrib: u8, x: u8) {
+        ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[y, tile, attrib, x]);
+    }
+
+    /* OAM full of sprites that are way below the screen */
+    fn hide_sprites(ppu: &mut PPU) {
+        ppu.oam = [0xF0; 256];
+    }
+
+    #[test]
+    fn evaluation_finds_the_first_8_sprites_on_the_next_line() {
+        let (mut ppu, _) = setup();
+        hide_sprites(&mut ppu);
+        set_sprite(&mut ppu, 0, 20, 1, 2, 3);
+        set_sprite(&mut ppu, 5, 13, 4, 5, 6);
+        /* Ends on line 20, so it's not on line 21 */
+        set_sprite(&mut ppu, 6, 12, 7, 8, 9);
+        ppu.scanline = 20;
+        ppu.evaluate_sprites();
+
+        assert_eq!(ppu.sprite_count, 2);
+        assert!(ppu.sprite_zero_next);
+        assert_eq!(ppu.secondary_oam[0..8], [20, 1, 2, 3, 13, 4, 5, 6]);
+        assert!(ppu.secondary_oam[8..].iter().all(|&byte| byte == 0xFF));
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+
+        /* 8x16 sprites reach further down */
+        ppu.ctrl = CTRL_SPRITE_SIZE_16;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprite_count, 3);
+    }
+
+    #[test]
+    fn a_ninth_sprite_sets_the_overflow_flag() {
+        let (mut ppu, _) = setup();
+        hide_sprites(&mut ppu);
+        for n in 1..10 {
+            set_sprite(&mut ppu, n, 50, 0, 0, 0);
+        }
+        ppu.scanline = 50;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprite_count, 8);
+        assert!(!ppu.sprite_zero_next);
+        assert_ne!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn the_overflow_search_has_the_hardware_bug() {
+        let (mut ppu, _) = setup();
+        hide_sprites(&mut ppu);
+        for n in 0..8 {
+            set_sprite(&mut ppu, n, 50, 0, 0, 0);
+        }
+        /* The search looks at Y of sprite 8, then the tile number of sprite 9... */
+        set_sprite(&mut ppu, 9, 0xF0, 50, 0, 0);
+        ppu.scanline = 50;
+        ppu.evaluate_sprites();
+        assert_ne!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+
+        /* ...so a real 9th sprite after that is missed */
+        ppu.status = 0;
+        set_sprite(&mut ppu, 9, 0xF0, 0xF0, 0, 0);
+        set_sprite(&mut ppu, 10, 50, 0, 0, 0);
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.status & STATUS_SPRITE_OVERFLOW, 0);
+    }
+
+    /* Background tile 0 is colour 1 everywhere, sprite tile 1 colour 3 */
+    fn sprite_setup() -> (PPU, Cartridge) {
+        let (mut ppu, mut cart) = setup();
+        cart.chr_memory[0..8].fill(0xFF);
+        cart.chr_memory[8..16].fill(0x00);
+        cart.chr_memory[16..32].fill(0xFF);
+        ppu.ppu_write(&mut cart, 0x3F01, 0x01);
+        ppu.ppu_write(&mut cart, 0x3F13, 0x13);
+        ppu.ppu_write(&mut cart, 0x3F17, 0x17);
+        hide_sprites(&mut ppu);
+        (ppu, cart)
+    }
+
+    #[test]
+    fn sprites_are_drawn_one_line_below_their_y() {
+        let (mut ppu, mut cart) = sprite_setup();
+        set_sprite(&mut ppu, 3, 10, 1, 0x00, 20);
+        set_sprite(&mut ppu, 4, 10, 1, 0x01, 24);
+        set_sprite(&mut ppu, 5, 30, 1, SPRITE_BEHIND_BACKGROUND, 20);
+        ppu.mask = MASK_BACKGROUND | MASK_SPRITES;
+        run_to(&mut ppu, &mut cart, -1, 0);
+        run_to(&mut ppu, &mut cart, 40, 0);
+
+        let pixel = |x: usize, y: usize| ppu.draw_buffer[y * SCREEN_WIDTH + x];
+        assert_eq!(pixel(20, 10), 0x01);
+        assert_eq!(pixel(20, 11), 0x13);
+        assert_eq!(pixel(27, 18), 0x13);
+        assert_eq!(pixel(28, 18), 0x17);
+        assert_eq!(pixel(32, 18), 0x01);
+        assert_eq!(pixel(20, 19), 0x01);
+        /* Behind the background, which is opaque everywhere */
+        assert_eq!(pixel(20, 31), 0x01);
+    }
+
+    #[test]
+    fn sprite_zero_hits_opaque_background() {
+        let (mut ppu, mut cart) = sprite_setup();
+        set_sprite(&mut ppu, 0, 10, 1, 0x00, 20);
+        ppu.mask = MASK_BACKGROUND | MASK_SPRITES;
+        run_to(&mut ppu, &mut cart, -1, 0);
+        run_to(&mut ppu, &mut cart, 11, 20);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+        run_to(&mut ppu, &mut cart, 11, 22);
+        assert_ne!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+
+        /* The pre-render line clears it */
+        run_to(&mut ppu, &mut cart, -1, 2);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0);
+    }
+
+    #[test]
+    fn sprite_zero_misses_at_the_right_edge_and_in_the_clipped_left_edge() {
+        for (x, mask) in [(255, MASK_BACKGROUND | MASK_SPRITES | MASK_BACKGROUND_LEFT | MASK_SPRITES_LEFT), (0, MASK_BACKGROUND | MASK_SPRITES | MASK_SPRITES_LEFT), (0, MASK_BACKGROUND | MASK_SPRITES | MASK_BACKGROUND_LEFT), (20, MASK_SPRITES)] {
+            let (mut ppu, mut cart) = sprite_setup();
+            set_sprite(&mut ppu, 0, 10, 1, 0x00, x);
+            /* Narrow the sprite down to its first pixel */
+            cart.chr_memory[16..32].fill(0x80);
+            ppu.mask = mask;
+            run_to(&mut ppu, &mut cart, -1, 0);
+            run_to(&mut ppu, &mut cart, 30, 0);
+            assert_eq!(ppu.status & STATUS_SPRITE_ZERO_HIT, 0, "sprite at x = {}, mask {:08b}", x, mask);
+        }
+    }
+
+    #[test]
+    fn the_frame_buffer_only_changes_when_a_frame_is_done() {
+        let (mut ppu, mut cart) = setup();
+        cart.chr_memory[0..16].fill(0);
+        ppu.ppu_write(&mut cart, 0x3F00, 0x21);
+        ppu.mask = MASK_BACKGROUND | MASK_BACKGROUND_LEFT;
+        run_to(&mut ppu, &mut cart, -1, 0);
+        ppu.frame_complete = false;
+
+        ppu.ppu_write(&mut cart, 0x3F00, 0x22);
+        run_to(&mut ppu, &mut cart, 120, 0);
+        assert_eq!(ppu.draw_buffer[0], 0x22);
+        assert_eq!(ppu.frame_buffer[0], 0x21);
+
+        run_to(&mut ppu, &mut cart, -1, 0);
+        assert!(ppu.frame_complete);
+        assert!(ppu.frame_buffer.iter().all(|&colour| colour == 0x22));
+    }
+
+    /* Writes a different byte to each of the 4 nametables and reads them back */
+    fn nametables_with(mirror: Mirror) -> [u8; 4] {
+        let (mut ppu, mut cart) = setup();
+        cart.mirror = mirror;
+        if mirror == Mirror::FourScreen {
+            cart.vram = vec![0u8; 4096];
+        }
+        for table in 0..4 {
+            ppu.ppu_write(&mut cart, 0x2000 + table * 0x0400 + 0x0123, table as u8 + 1);
+        }
+        /* $3000-$3EFF is the same as $2000-$2EFF */
+        assert_eq!(ppu.ppu_read(&mut cart, 0x3123), ppu.ppu_read(&mut cart, 0x2123));
+        assert_eq!(ppu.ppu_peek(&cart, 0x3923), ppu.ppu_peek(&cart, 0x2923));
+        [0, 1, 2, 3].map(|table| ppu.ppu_read(&mut cart, 0x2000 + table * 0x0400 + 0x0123))
+    }
+
+    #[test]
+    fn nametables_follow_the_cartridge_mirroring() {
+        /* The second write to the same table wins */
+        assert_eq!(nametables_with(Mirror::Vertical), [3, 4, 3, 4]);
+        assert_eq!(nametables_with(Mirror::Horizontal), [2, 2, 4, 4]);
+        assert_eq!(nametables_with(Mirror::OnescreenLo), [4, 4, 4, 4]);
+        assert_eq!(nametables_with(Mirror::OnescreenHi), [4, 4, 4, 4]);
+        assert_eq!(nametables_with(Mirror::FourScreen), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn one_screen_mirroring_picks_a_table() {
+        let (mut ppu, mut cart) = setup();
+        cart.mirror = Mirror::OnescreenLo;
+        ppu.ppu_write(&mut cart, 0x2C00, 0x11);
+        cart.mirror = Mirror::OnescreenHi;
+        ppu.ppu_write(&mut cart, 0x2000, 0x22);
+        assert_eq!(ppu.ppu_read(&mut cart, 0x2400), 0x22);
+        cart.mirror = Mirror::OnescreenLo;
+        assert_eq!(ppu.ppu_read(&mut cart, 0x2400), 0x11);
+    }
+
+    #[test]
+    fn vblank_lasts_from_241_to_the_pre_render_line() {
+        let (mut ppu, mut cart) = setup();
+        ppu.cpu_write(&mut cart, 0x0000, CTRL_NMI);
+        run_to(&mut ppu, &mut cart, Region::Ntsc.vblank_scanline(), 1);
+        assert!(!ppu.nmi_output());
+        ppu.clock(&mut cart);
+        assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(ppu.nmi_output());
+
+        /* Turning NMIs off and on again during vblank makes another edge for the CPU */
+        ppu.cpu_write(&mut cart, 0x0000, 0);
+        assert!(!ppu.nmi_output());
+        ppu.cpu_write(&mut cart, 0x0000, CTRL_NMI);
+        assert!(ppu.nmi_output());
+
+        run_to(&mut ppu, &mut cart, -1, 1);
+        assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+        ppu.clock(&mut cart);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!ppu.nmi_output());
+    }
+
+    #[test]
+    fn reading_the_status_right_before_vblank_cancels_it() {
+        let (mut ppu, mut cart) = setup();
+        ppu.cpu_write(&mut cart, 0x0000, CTRL_NMI);
+        run_to(&mut ppu, &mut cart, Region::Ntsc.vblank_scanline(), 1);
+        assert_eq!(ppu.cpu_read(&mut cart, 0x0002) & STATUS_VBLANK, 0);
+        ppu.clock(&mut cart);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!ppu.nmi_output());
+
+        /* Only for this frame */
+        run_to(&mut ppu, &mut cart, 0, 0);
+        run_to(&mut ppu, &mut cart, Region::Ntsc.vblank_scanline(), 2);
+        assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+    }
+
+    /* Runs the pre-render line of an odd frame up to dot 338, sets PPUMASK to mask_at_338 and
+    *  then to mask_at_339 a dot later. Returns true if dot 340 was skipped */
+    fn skips_the_last_dot(mask_at_338: u8, mask_at_339: u8) -> bool {
+        let (mut ppu, mut cart) = setup();
+        run_to(&mut ppu, &mut cart, -1, 338);
+        ppu.odd_frame = true;
+        ppu.cpu_write(&mut cart, 0x0001, mask_at_338);
+        ppu.clock(&mut cart);
+        ppu.cpu_write(&mut cart, 0x0001, mask_at_339);
+        ppu.clock(&mut cart);
+        (ppu.scanline, ppu.cycle) == (0, 0)
+    }
+
+    #[test]
+    fn odd_frames_skip_a_dot_when_rendering_was_on_at_dot_338() {
+        assert!(skips_the_last_dot(MASK_BACKGROUND, MASK_BACKGROUND));
+        assert!(skips_the_last_dot(MASK_SPRITES, MASK_SPRITES));
+        assert!(!skips_the_last_dot(0, 0));
+
+        /* Dot 339 is too late to change anything */
+        assert!(skips_the_last_dot(MASK_BACKGROUND, 0));
+        assert!(!skips_the_last_dot(0, MASK_BACKGROUND));
+    }
+
+    #[test]
+    fn even_frames_never_skip_a_dot() {
+        let (mut ppu, mut cart) = setup();
+        ppu.cpu_write(&mut cart, 0x0001, MASK_BACKGROUND);
+        run_to(&mut ppu, &mut cart, -1, 338);
+        ppu.odd_frame = false;
+        ppu.clock(&mut cart);
+        ppu.clock(&mut cart);
+        assert_eq!((ppu.scanline, ppu.cycle), (-1, 340));
+    }
+
+    #[test]
+    fn pal_has_no_dot_skip_and_swaps_red_and_green_emphasis() {
+        let (mut ppu, mut cart) = setup();
+        ppu.region = Region::Pal;
+        /* PPUMASK bit 6 is red on PAL, the frame buffer has red in the lowest emphasis bit */
+        ppu.mask = MASK_BACKGROUND | MASK_BACKGROUND_LEFT | 0x40;
+        run_to(&mut ppu, &mut cart, -1, 338);
+        ppu.odd_frame = true;
+        ppu.clock(&mut cart);
+        ppu.clock(&mut cart);
+        assert_eq!((ppu.scanline, ppu.cycle), (-1, 340));
+
+        run_to(&mut ppu, &mut cart, 1, 0);
+        assert!(ppu.draw_buffer[..SCREEN_WIDTH].iter().all(|&colour| colour & 0x1C0 == 0x40));
+        ppu.mask = MASK_BACKGROUND | MASK_BACKGROUND_LEFT | 0x20;
+        run_to(&mut ppu, &mut cart, 2, 0);
+        assert!(ppu.draw_buffer[SCREEN_WIDTH..SCREEN_WIDTH * 2].iter().all(|&colour| colour & 0x1C0 == 0x80));
+    }
+}
@@ -0,0 +1,141 @@
+/*  region.rs
+*   NTSC, PAL and Dendy consoles run the same chips at different speeds.
+*
+*   Everything is driven by one master clock, the CPU and the PPU divide it down:
+*
+*   |       | master clock  | CPU | PPU | PPU dots per CPU cycle | scanlines | vblank starts on |
+*   |-------|---------------|-----|-----|------------------------|-----------|------------------|
+*   | NTSC  | 21.477272 MHz | /12 | /4  | 3                      | 262       | 241              |
+*   | PAL   | 26.601712 MHz | /16 | /5  | 3.2                    | 312       | 241              |
+*   | Dendy | 26.601712 MHz | /15 | /5  | 3                      | 312       | 291              |
+*
+*   Dendy is a Famicom clone built for 50Hz TVs. It keeps the NTSC length of vblank (20 lines)
+*   and puts the extra 50 lines before it, so NTSC games still have the same time for their NMI.
+*/
+
+/* CPU cycles at which the APU frame counter steps (the 5th is only used in 5-step mode). PAL
+*  divides a slower CPU clock down to the same 240Hz, so it needs more cycles */
+const FRAME_COUNTER_STEPS_NTSC: [u16; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_COUNTER_STEPS_PAL: [u16; 5] = [8313, 16627, 24939, 33253, 41565];
+
+/* CPU cycles between DMC output bits, selected by the low 4 bits of $4010 */
+const DMC_RATES_NTSC: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const DMC_RATES_PAL: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    /* How many master clocks one CPU cycle and one PPU dot take */
+    pub fn cpu_divider(self) -> u8 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+    pub fn ppu_divider(self) -> u8 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn master_clock_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 21_477_272.0,
+            Region::Pal | Region::Dendy => 26_601_712.0,
+        }
+    }
+    pub fn cpu_clock_hz(self) -> f64 {
+        self.master_clock_hz() / self.cpu_divider() as f64
+    }
+
+    /* Including the pre-render line */
+    pub fn scanlines(self) -> i16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+    pub fn vblank_scanline(self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /* Only the NTSC PPU skips a dot on odd frames */
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    /* The PAL PPU (and the Dendy's, which is a copy of it) has the red and green emphasis bits
+    *  of PPUMASK the other way around */
+    pub fn swaps_emphasis(self) -> bool {
+        self != Region::Ntsc
+    }
+
+    /* The Dendy's APU is an NTSC one, it runs the NTSC tables off its slightly faster CPU clock */
+    pub fn frame_counter_steps(self) -> &'static [u16; 5] {
+        match self {
+            Region::Pal => &FRAME_COUNTER_STEPS_PAL,
+            Region::Ntsc | Region::Dendy => &FRAME_COUNTER_STEPS_NTSC,
+        }
+    }
+
+    /* rate is the low 4 bits of $4010 */
+    pub fn dmc_rate(self, rate: u8) -> u16 {
+        match self {
+            Region::Pal => DMC_RATES_PAL[(rate & 0x0F) as usize],
+            Region::Ntsc | Region::Dendy => DMC_RATES_NTSC[(rate & 0x0F) as usize],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_and_ppu_clocks() {
+        assert_eq!(Region::Ntsc.cpu_clock_hz().round(), 1_789_773.0);
+        assert_eq!(Region::Pal.cpu_clock_hz().round(), 1_662_607.0);
+        assert_eq!(Region::Dendy.cpu_clock_hz().round(), 1_773_447.0);
+
+        /* PPU dots per CPU cycle */
+        let ratio = |region: Region| region.cpu_divider() as f64 / region.ppu_divider() as f64;
+        assert_eq!(ratio(Region::Ntsc), 3.0);
+        assert_eq!(ratio(Region::Pal), 3.2);
+        assert_eq!(ratio(Region::Dendy), 3.0);
+    }
+
+    #[test]
+    fn frame_counter_steps_come_at_the_same_240hz() {
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy] {
+            let steps = region.frame_counter_steps();
+            assert!(steps.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        /* A quarter frame is a little over 7457.5 CPU cycles on NTSC, 8313 on PAL */
+        let quarter_hz = |region: Region| region.cpu_clock_hz() / region.frame_counter_steps()[0] as f64;
+        assert_eq!(quarter_hz(Region::Ntsc).round(), 240.0);
+        assert_eq!(quarter_hz(Region::Pal).round(), 200.0);
+        assert_eq!(Region::Dendy.frame_counter_steps(), Region::Ntsc.frame_counter_steps());
+    }
+
+    #[test]
+    fn dmc_rates() {
+        assert_eq!(Region::Ntsc.dmc_rate(0x00), 428);
+        assert_eq!(Region::Ntsc.dmc_rate(0x0F), 54);
+        assert_eq!(Region::Pal.dmc_rate(0x00), 398);
+        assert_eq!(Region::Pal.dmc_rate(0x0F), 50);
+        assert_eq!(Region::Dendy.dmc_rate(0x0E), 72);
+        /* Only the low 4 bits of $4010 pick the rate */
+        assert_eq!(Region::Ntsc.dmc_rate(0xC5), Region::Ntsc.dmc_rate(0x05));
+    }
+}
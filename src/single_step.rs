@@ -0,0 +1,195 @@
+/*  single_step.rs
+*   Runs the per-opcode JSON test vectors (https://github.com/SingleStepTests/65x02, the nes6502
+*   set) against our CPU.
+*
+*   There is one file per opcode (a9.json, 6c.json, ...) with thousands of tests in it. Every test
+*   has the registers and RAM before and after one instruction, plus the list of bus accesses the
+*   instruction does on every single cycle:
+*
+*   { "name": "a9 29 f3", "initial": { "pc": 1234, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+*     "ram": [[1234, 169], [1235, 41]] }, "final": { ... }, "cycles": [[1234, 169, "read"], ...] }
+*
+*   The CPU is connected to a FlatBus, which records everything it does. The end state and the
+*   bus activity are checked separately, because getting the registers right is one thing, but
+*   doing the right dummy reads and writes on the right cycles is another.
+*/
+
+use crate::cpu::{CPU, ICPU};
+use crate::flat_bus::{AccessKind, BusAccess, FlatBus};
+
+use serde::Deserialize;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/* No instruction takes anywhere near this long, if it does the CPU is stuck */
+const MAX_CYCLES: usize = 100;
+
+#[derive(Deserialize)]
+struct CpuState {
+    pc: u16,
+    s: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Deserialize)]
+struct TestCase {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    final_state: CpuState,
+    cycles: Vec<(u16, u8, String)>,
+}
+
+/* The results for one opcode file */
+pub struct OpcodeReport {
+    pub opcode: String,
+    pub total: usize,
+    /* Registers and RAM match after the instruction */
+    pub state_passed: usize,
+    /* Every cycle did the expected read or write */
+    pub bus_passed: usize,
+    pub first_state_failure: Option<String>,
+    pub first_bus_failure: Option<String>,
+}
+
+impl OpcodeReport {
+    pub fn passed(&self) -> bool {
+        self.state_passed == self.total && self.bus_passed == self.total
+    }
+}
+
+/* Sets up the CPU and RAM from the test, runs one instruction and compares the results */
+fn run_test(cpu: &mut CPU, bus: &mut FlatBus, test: &TestCase) -> (Result<(), String>, Result<(), String>) {
+    for &(addr, data) in &test.initial.ram {
+        bus.ram[addr as usize] = data;
+    }
+    bus.accesses.clear();
+
+    cpu.pc = test.initial.pc;
+    cpu.sp = test.initial.s;
+    cpu.a = test.initial.a;
+    cpu.x = test.initial.x;
+    cpu.y = test.initial.y;
+    cpu.status_flags = test.initial.p;
+    cpu.cycles = 0;
+    cpu.step = 0;
+    cpu.jammed = false;
+    cpu.trapped = false;
+
+    /* The first clock starts the instruction, it's done once the CPU wants the next opcode */
+    let mut cycles = 0;
+    loop {
+        cpu.clock(bus);
+        cycles += 1;
+        if cpu.at_instruction_boundary() || cpu.jammed || cycles >= MAX_CYCLES {
+            break;
+        }
+    }
+
+    let mut state_diffs = Vec::new();
+    let expected = &test.final_state;
+    let registers = [
+        ("PC", cpu.pc, expected.pc),
+        ("S", cpu.sp as u16, expected.s as u16),
+        ("A", cpu.a as u16, expected.a as u16),
+        ("X", cpu.x as u16, expected.x as u16),
+        ("Y", cpu.y as u16, expected.y as u16),
+        ("P", cpu.status_flags as u16, expected.p as u16),
+    ];
+    for (name, actual, expected) in registers {
+        if actual != expected {
+            state_diffs.push(format!("{} {:02X} != {:02X}", name, actual, expected));
+        }
+    }
+    for &(addr, data) in &expected.ram {
+        let actual = bus.ram[addr as usize];
+        if actual != data {
+            state_diffs.push(format!("[{:04X}] {:02X} != {:02X}", addr, actual, data));
+        }
+    }
+
+    let expected_accesses: Vec<BusAccess> = test.cycles.iter()
+        .map(|(addr, data, kind)| BusAccess {
+            addr: *addr,
+            data: *data,
+            kind: if kind == "write" { AccessKind::Write } else { AccessKind::Read },
+        })
+        .collect();
+    let bus_result = if cycles != expected_accesses.len() {
+        Err(format!("took {} cycles, expected {}", cycles, expected_accesses.len()))
+    } else if let Some(i) = (0..expected_accesses.len()).find(|&i| bus.accesses.get(i) != Some(&expected_accesses[i])) {
+        Err(format!("cycle {}: {:?}, expected {:?}", i + 1, bus.accesses.get(i), expected_accesses[i]))
+    } else if bus.accesses.len() != expected_accesses.len() {
+        Err(format!("{} bus accesses, expected {}", bus.accesses.len(), expected_accesses.len()))
+    } else {
+        Ok(())
+    };
+
+    /* Leave the RAM clean for the next test */
+    for &(addr, _) in test.initial.ram.iter().chain(expected.ram.iter()) {
+        bus.ram[addr as usize] = 0;
+    }
+    for access in bus.accesses.drain(..) {
+        bus.ram[access.addr as usize] = 0;
+    }
+
+    let state_result = if state_diffs.is_empty() { Ok(()) } else { Err(state_diffs.join(", ")) };
+    (state_result, bus_result)
+}
+
+/* Runs every test in one opcode file */
+pub fn run_file(path: &Path) -> Result<OpcodeReport, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+    let tests: Vec<TestCase> = serde_json::from_str(&json).map_err(|e| format!("Error parsing {}: {}", path.display(), e))?;
+
+    let mut bus = FlatBus::new();
+    let mut cpu = CPU::new();
+
+    let mut report = OpcodeReport {
+        opcode: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        total: tests.len(),
+        state_passed: 0,
+        bus_passed: 0,
+        first_state_failure: None,
+        first_bus_failure: None,
+    };
+
+    for test in &tests {
+        let (state, bus) = run_test(&mut cpu, &mut bus, test);
+        match state {
+            Ok(()) => report.state_passed += 1,
+            Err(reason) => {
+                report.first_state_failure.get_or_insert(format!("{}: {}", test.name, reason));
+            },
+        }
+        match bus {
+            Ok(()) => report.bus_passed += 1,
+            Err(reason) => {
+                report.first_bus_failure.get_or_insert(format!("{}: {}", test.name, reason));
+            },
+        }
+    }
+
+    Ok(report)
+}
+
+/* Runs every *.json file in dir, sorted by opcode */
+pub fn run_dir(dir: &str) -> Result<Vec<OpcodeReport>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Error opening {}: {}", dir, e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+
+    if files.is_empty() {
+        return Err(format!("No *.json test files in {}", dir));
+    }
+
+    files.iter().map(|path| run_file(path)).collect()
+}
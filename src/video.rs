@@ -0,0 +1,15 @@
+/*  video.rs
+*   How the picture gets out of the emulator.
+*
+*   The PPU draws into a frame buffer of SCREEN_WIDTH x SCREEN_HEIGHT pixels (see Nes::frame).
+*   Every pixel is an index into the NES palette (0x00-0x3F) in bits 0-5, with the colour
+*   emphasis bits from PPUMASK in bits 6-8. Palette (palette.rs) turns that into RGB.
+*/
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+/* Implemented by frontends (windows, screenshot writers, ...), gets every finished frame */
+pub trait VideoOutput {
+    fn present_frame(&mut self, frame: &[u16]);
+}